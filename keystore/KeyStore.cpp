@@ -20,19 +20,35 @@
 
 #include <dirent.h>
 #include <fcntl.h>
+#include <inttypes.h>
+#include <stdio.h>
+#include <sys/stat.h>
+
+#include <algorithm>
+#include <chrono>
+#include <iomanip>
+#include <sstream>
 
 #include <openssl/bio.h>
+#include <openssl/rand.h>
 
 #include <utils/String16.h>
 #include <utils/String8.h>
 
+#include <android-base/file.h>
+#include <android-base/parseint.h>
+#include <android-base/properties.h>
 #include <android-base/scopeguard.h>
+#include <android-base/strings.h>
 #include <android/hardware/keymaster/3.0/IKeymasterDevice.h>
 #include <android/security/keystore/IKeystoreService.h>
 #include <log/log_event_list.h>
 
+#include <private/android_filesystem_config.h>
 #include <private/android_logger.h>
 
+#include "key_datetime.h"
+#include "key_descriptor.h"
 #include "keystore_utils.h"
 #include "permissions.h"
 #include <keystore/keystore_hidl_support.h>
@@ -43,6 +59,11 @@ namespace keystore {
 
 const char* KeyStore::kOldMasterKey = ".masterkey";
 const char* KeyStore::kMetaDataFile = ".metadata";
+const char* KeyStore::kPendingDeletionDir = "pending_deletion";
+const char* KeyStore::kReadOnlyUidsFile = ".readonly_uids";
+const char* KeyStore::kTestKeysFile = ".test_keys";
+const char* KeyStore::kErrorCountersFile = ".error_counters";
+const char* KeyStore::kGrantsFile = ".grants";
 
 const android::String16 KeyStore::kRsaKeyType("RSA");
 const android::String16 KeyStore::kEcKeyType("EC");
@@ -52,7 +73,10 @@ using android::String8;
 KeyStore::KeyStore(const KeymasterDevices& kmDevices,
                    SecurityLevel minimalAllowedSecurityLevelForNewKeys)
     : mAllowNewFallback(minimalAllowedSecurityLevelForNewKeys == SecurityLevel::SOFTWARE),
-      mConfirmationManager(new ConfirmationManager(this)) {
+      mSoftDeleteWindow(
+          android::base::GetUintProperty<uint32_t>("ro.keystore.soft_delete_window_secs", 0)),
+      mConfirmationManager(new ConfirmationManager(this)), mKeyLifecycleNotifier(this),
+      mErrorCounters(kErrorCountersFile), mGrants(kGrantsFile) {
     memset(&mMetaData, '\0', sizeof(mMetaData));
 
     static_assert(std::tuple_size<std::decay_t<decltype(kmDevices)>>::value ==
@@ -67,17 +91,140 @@ KeyStore::KeyStore(const KeymasterDevices& kmDevices,
 }
 
 KeyStore::~KeyStore() {
+    {
+        std::lock_guard<std::mutex> lock(mMaintenanceMutex);
+        mStopMaintenance = true;
+    }
+    mMaintenanceCondVar.notify_all();
+    if (mMaintenanceThread.joinable()) mMaintenanceThread.join();
+}
+
+void KeyStore::runStartupStep(const char* step, const std::function<void()>& run) {
+    // A step that never finishes leaves its start as the last startup line in the log, which
+    // tells a slow migration apart from a hang in a bug report.
+    ALOGI("startup step %s started", step);
+    auto start = std::chrono::steady_clock::now();
+    run();
+    auto duration = std::chrono::duration_cast<std::chrono::milliseconds>(
+        std::chrono::steady_clock::now() - start);
+    ALOGI("startup step %s took %" PRId64 " ms", step, int64_t(duration.count()));
+    mStartupSteps.emplace_back(step, duration);
 }
 
 ResponseCode KeyStore::initialize() {
-    readMetaData();
-    if (upgradeKeystore()) {
-        writeMetaData();
+    runStartupStep("upgrade", [&] {
+        readMetaData();
+        if (upgradeKeystore()) {
+            writeMetaData();
+        }
+    });
+    runStartupStep("load", [&] {
+        readReadOnlyUids();
+        readTestKeys();
+        mErrorCounters.load();
+        mGrants.load();
+    });
+    runStartupStep("drain_deletions", [&] { drainPendingDeletions(); });
+    // Tombstones left behind with a longer or no longer configured undo window expire right away.
+    runStartupStep("purge_tombstones", [&] { purgeExpiredTombstones(); });
+    if (android::base::GetBoolProperty("ro.keystore.repair_on_boot", false)) {
+        runStartupStep("repair", [&] { checkConsistency(true /* repair */); });
     }
 
+    // Clients that never finish or abort their operations would otherwise hold operation slots
+    // until they die. 0 disables the reaper.
+    std::chrono::seconds idleTimeout(android::base::GetUintProperty<uint32_t>(
+        "ro.keystore.operation_idle_timeout_secs", kDefaultOperationIdleTimeoutSecs));
+    if (idleTimeout.count() > 0) {
+        // Checking four times per timeout lets idle operations outlive it by a quarter at most.
+        addMaintenanceTask(std::max(idleTimeout / 4, std::chrono::seconds(1)),
+                           [this, idleTimeout] { reapIdleOperations(idleTimeout); });
+    }
+    addMaintenanceTask(kExpiredKeySweepInterval, [this] { purgeExpiredKeys(); });
+    if (mSoftDeleteWindow > 0) {
+        // Tombstones outlive the undo window by one sweep interval at most.
+        addMaintenanceTask(std::min(std::chrono::seconds(mSoftDeleteWindow),
+                                    kTombstoneSweepInterval),
+                           [this] { purgeExpiredTombstones(); });
+    }
+    mMaintenanceThread = std::thread([this] { runMaintenance(); });
+
     return ResponseCode::NO_ERROR;
 }
 
+void KeyStore::addMaintenanceTask(std::chrono::seconds interval, std::function<void()> run) {
+    mMaintenanceTasks.push_back({interval, std::chrono::steady_clock::now() + interval,
+                                 std::move(run)});
+}
+
+void KeyStore::runMaintenance() {
+    std::unique_lock<std::mutex> lock(mMaintenanceMutex);
+    while (true) {
+        auto due = std::min_element(mMaintenanceTasks.begin(), mMaintenanceTasks.end(),
+                                    [](const MaintenanceTask& a, const MaintenanceTask& b) {
+                                        return a.due < b.due;
+                                    });
+        if (due == mMaintenanceTasks.end()) return;
+        if (mMaintenanceCondVar.wait_until(lock, due->due, [this] { return mStopMaintenance; })) {
+            return;
+        }
+        // The tasks take locks of their own, e.g. key blob entry locks.
+        lock.unlock();
+        due->run();
+        lock.lock();
+        due->due = std::chrono::steady_clock::now() + due->interval;
+    }
+}
+
+void KeyStore::reapIdleOperations(std::chrono::seconds idleTimeout) {
+    for (unsigned i = 0; i < mKmDevices.size(); ++i) {
+        if (mKmDevices[SecurityLevel(i)]) {
+            mKmDevices[SecurityLevel(i)]->reapIdleOperations(idleTimeout);
+        }
+    }
+}
+
+void KeyStore::replaceKeymaster(const sp<Keymaster>& dead, const sp<Keymaster>& replacement) {
+    for (unsigned i = 0; i < mKmDevices.size(); ++i) {
+        if (mKmDevices[SecurityLevel(i)]) {
+            mKmDevices[SecurityLevel(i)]->replaceDevice(dead, replacement);
+        }
+    }
+}
+
+void KeyStore::expireOperationDevice(const sp<IBinder>& token, ResponseCode reason) {
+    std::lock_guard<std::mutex> lock(operationDeviceMapMutex_);
+    operationDeviceMap_.erase(token);
+    expiredOperations_.emplace_back(token, reason);
+    if (expiredOperations_.size() > kMaxExpiredOperations) expiredOperations_.pop_front();
+}
+
+std::optional<ResponseCode> KeyStore::getOperationDropReason(const sp<IBinder>& token) {
+    std::lock_guard<std::mutex> lock(operationDeviceMapMutex_);
+    auto expired = std::find_if(expiredOperations_.begin(), expiredOperations_.end(),
+                                [&](const auto& entry) {
+                                    return entry.first.unsafe_get() == token.get();
+                                });
+    if (expired == expiredOperations_.end()) return {};
+    return expired->second;
+}
+
+void KeyStore::dumpStartup(int fd) const {
+    dprintf(fd, "Startup steps (step, ms):\n");
+    for (const auto& [step, duration] : mStartupSteps) {
+        dprintf(fd, "  %s %" PRId64 "\n", step.c_str(), int64_t(duration.count()));
+    }
+}
+
+void KeyStore::dumpBeginRetryLimitHits(int fd) const {
+    dprintf(fd, "Begin retry limit hits (security level, count):\n");
+    for (unsigned i = 0; i < mKmDevices.size(); ++i) {
+        if (!mKmDevices[SecurityLevel(i)]) continue;
+        dprintf(fd, "  %s %zu\n", toString(SecurityLevel(i)).c_str(),
+                mKmDevices[SecurityLevel(i)]->getBeginRetryLimitHits());
+    }
+}
+
 ResponseCode KeyStore::initializeUser(const android::String8& pw, uid_t userId) {
     auto userState = mUserStateDB.getUserState(userId);
     return userState->initialize(pw);
@@ -95,8 +242,10 @@ ResponseCode KeyStore::writeMasterKey(const android::String8& pw, uid_t userId)
 }
 
 ResponseCode KeyStore::readMasterKey(const android::String8& pw, uid_t userId) {
-    auto userState = mUserStateDB.getUserState(userId);
-    return userState->readMasterKey(pw);
+    auto rc = mUserStateDB.getUserState(userId)->readMasterKey(pw);
+    // The user's encrypted pending deletions have become readable.
+    if (rc == ResponseCode::NO_ERROR) drainPendingDeletions();
+    return rc;
 }
 
 LockedKeyBlobEntry KeyStore::getLockedBlobEntryIfNotExists(const std::string& alias, uid_t uid) {
@@ -106,7 +255,17 @@ LockedKeyBlobEntry KeyStore::getLockedBlobEntryIfNotExists(const std::string& al
     return result;
 }
 
-std::optional<KeyBlobEntry> KeyStore::getBlobEntryIfExists(const std::string& alias, uid_t uid) {
+std::optional<KeyBlobEntry> KeyStore::getBlobEntryIfExists(const std::string& alias, uid_t uid,
+                                                           bool allowCertOnlyGrants) {
+    // Keys never cross the boundary between users, no matter how the entry was resolved. This
+    // guards against misconfigured uid mappings and grants to apps of another user.
+    auto sameUser = [uid](const KeyBlobEntry& kbe) {
+        if (get_user_id(kbe.uid()) == get_user_id(uid)) return true;
+        ALOGE("uid %d of user %d may not use key of uid %d of user %d", uid, get_user_id(uid),
+              kbe.uid(), get_user_id(kbe.uid()));
+        return false;
+    };
+
     KeyBlobEntry kbe(alias, mUserStateDB.getUserStateByUid(uid)->getUserDirName(), uid);
     if (kbe.hasKeyBlob()) return kbe;
 
@@ -114,19 +273,20 @@ std::optional<KeyBlobEntry> KeyStore::getBlobEntryIfExists(const std::string& al
     uid_t euid = get_keystore_euid(uid);
     if (euid != uid) {
         kbe = KeyBlobEntry(alias, mUserStateDB.getUserStateByUid(euid)->getUserDirName(), euid);
-        if (kbe.hasKeyBlob()) return kbe;
+        if (kbe.hasKeyBlob()) return sameUser(kbe) ? std::optional(kbe) : std::nullopt;
     }
 
     // They might be using a granted key.
     auto grant = mGrants.get(uid, alias);
-    if (grant) {
+    if (grant && (allowCertOnlyGrants || !grant->cert_only_)) {
         kbe = grant->entry_;
-        if (kbe.hasKeyBlob()) return kbe;
+        if (kbe.hasKeyBlob()) return sameUser(kbe) ? std::optional(kbe) : std::nullopt;
     }
     return {};
 }
-LockedKeyBlobEntry KeyStore::getLockedBlobEntryIfExists(const std::string& alias, uid_t uid) {
-    auto blobentry = getBlobEntryIfExists(alias, uid);
+LockedKeyBlobEntry KeyStore::getLockedBlobEntryIfExists(const std::string& alias, uid_t uid,
+                                                        bool allowCertOnlyGrants) {
+    auto blobentry = getBlobEntryIfExists(alias, uid, allowCertOnlyGrants);
     if (!blobentry) return {};
     LockedKeyBlobEntry lockedentry = LockedKeyBlobEntry::get(std::move(*blobentry));
     if (!lockedentry || !lockedentry->hasKeyBlob()) return {};
@@ -190,10 +350,18 @@ void KeyStore::resetUser(uid_t userId, bool keepUnenryptedEntries) {
             }
         }
         if (shouldDelete) {
-            del(lockedEntry);
+            // Encrypted entries only go away with the lock screen that protects them.
+            del(lockedEntry, keepUnenryptedEntries ? IKeystoreKeyLifecycleListener::KEY_INVALIDATED
+                                                   : IKeystoreKeyLifecycleListener::KEY_DELETED);
         }
     }
 
+    // A reset ends the undo window of soft-deleted entries as well. Their Keymaster blobs are
+    // deleted while the master key can still decrypt them. The listed entries must be unlocked
+    // first.
+    matches.clear();
+    purgeTombstones(userId, [](uid_t, time_t) { return true; });
+
     userState = mUserStateDB.getUserState(userId);
     if (!userState->deleteMasterKey()) {
         ALOGE("Failed to delete user %d's master key", userId);
@@ -233,6 +401,55 @@ void KeyStore::lock(uid_t userId) {
     userState->setState(STATE_LOCKED);
 }
 
+KeyStore::ConsistencyReport KeyStore::checkConsistency(bool repair) {
+    ConsistencyReport report;
+
+    DIR* dir = opendir(".");
+    if (!dir) {
+        ALOGE("couldn't open keystore directory: %s", strerror(errno));
+        return report;
+    }
+    std::vector<std::string> userDirNames;
+    time_t now = time(nullptr);
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        if (file->d_type == DT_DIR && android::base::StartsWith(file->d_name, "user_")) {
+            userDirNames.push_back(file->d_name);
+            continue;
+        }
+        // See Blob::writeBlob.
+        if (file->d_type != DT_REG || !android::base::StartsWith(file->d_name, ".tmp")) continue;
+        struct stat sbuf;
+        if (fstatat(dirfd(dir), file->d_name, &sbuf, 0) != 0 ||
+            now - sbuf.st_mtime < kStaleTempFileAge) {
+            continue;
+        }
+        ++report.staleTempFiles;
+        if (repair && unlinkat(dirfd(dir), file->d_name, 0) != 0) {
+            ALOGW("couldn't remove stale temporary file %s: %s", file->d_name, strerror(errno));
+        }
+    }
+    closedir(dir);
+
+    for (const auto& userDirName : userDirNames) {
+        ResponseCode rc;
+        std::list<LockedKeyBlobEntry> orphans;
+        std::tie(rc, orphans) = LockedKeyBlobEntry::listOrphanedCharacteristics(userDirName);
+        if (rc != ResponseCode::NO_ERROR) continue;
+        report.orphanedCharacteristics += orphans.size();
+        if (!repair) continue;
+        for (const auto& orphan : orphans) orphan.deleteBlobs();
+    }
+
+    report.danglingGrants = mGrants.removeDanglingGrants(!repair /* dryRun */);
+
+    ALOGI("consistency check%s: %zu orphaned characteristics, %zu stale temporary files, "
+          "%zu dangling grants",
+          repair ? " and repair" : "", report.orphanedCharacteristics, report.staleTempFiles,
+          report.danglingGrants);
+    return report;
+}
+
 static void maybeLogKeyIntegrityViolation(const LockedKeyBlobEntry& lockedEntry,
                                           const BlobType type) {
     if (!__android_log_security() || (type != TYPE_KEY_PAIR && type != TYPE_KEYMASTER_10)) return;
@@ -282,12 +499,28 @@ std::tuple<ResponseCode, Blob, Blob> KeyStore::get(const LockedKeyBlobEntry& blo
 
 ResponseCode KeyStore::put(const LockedKeyBlobEntry& blobfile, Blob keyBlob,
                            Blob characteristicsBlob) {
+    // Only the first write of a key blob creates the key. Upgrades and cache updates don't.
+    bool created = keyBlob && !blobfile->hasKeyBlob();
     auto userState = mUserStateDB.getUserStateByUid(blobfile->uid());
-    return blobfile.writeBlobs(std::move(keyBlob), std::move(characteristicsBlob),
-                               userState->getEncryptionKey(), userState->getState());
+    auto rc = blobfile.writeBlobs(std::move(keyBlob), std::move(characteristicsBlob),
+                                  userState->getEncryptionKey(), userState->getState());
+    userState = {};
+    if (created && rc == ResponseCode::NO_ERROR) {
+        mKeyLifecycleNotifier.notify(IKeystoreKeyLifecycleListener::KEY_CREATED, blobfile->uid(),
+                                     blobfile->alias());
+    }
+    return rc;
 }
 
-ResponseCode KeyStore::del(const LockedKeyBlobEntry& blobfile) {
+ResponseCode KeyStore::del(const LockedKeyBlobEntry& blobfile, int32_t event) {
+    bool blobsDeleted = false;
+    auto rc = deleteEntry(blobfile, event, &blobsDeleted);
+    if (blobsDeleted) setTestKey(blobfile, false);
+    return rc;
+}
+
+ResponseCode KeyStore::deleteEntry(const LockedKeyBlobEntry& blobfile, int32_t event,
+                                   bool* blobsDeleted) {
     Blob keyBlob;
     Blob charactaristicsBlob;
     ResponseCode rc;
@@ -296,39 +529,391 @@ ResponseCode KeyStore::del(const LockedKeyBlobEntry& blobfile) {
 
     std::tie(rc, keyBlob, charactaristicsBlob) = get(blobfile);
 
+    // if we got the blob successfully, we try and delete it from the keymaster device.
+    // This happens before the files are scrubbed, so that the pending deletion is persisted
+    // before the last copy of the key blob goes away.
+    if (rc == ResponseCode::NO_ERROR) deleteKeymasterBlob(keyBlob, alias, uid);
+
     // after getting the blob from the file system we scrub the filesystem.
     mGrants.removeAllGrantsToKey(uid, alias);
     auto result = blobfile.deleteBlobs();
+    if (result == ResponseCode::NO_ERROR) {
+        *blobsDeleted = true;
+        mKeyLifecycleNotifier.notify(event, uid, alias);
+    }
 
     if (rc != ResponseCode::NO_ERROR) {
         LOG(ERROR) << "get keyblob failed " << int(rc);
         return rc;
     }
 
-    // if we got the blob successfully, we try and delete it from the keymaster device
+    return result;
+}
+
+void KeyStore::deleteKeymasterBlob(const Blob& keyBlob, const std::string& alias, uid_t uid) {
+    if (keyBlob.getType() != ::TYPE_KEYMASTER_10) return;
+
     auto dev = getDevice(keyBlob);
+    auto pending = queuePendingDeletion(keyBlob, uid);
+    dev->deleteKey(blob2hidlVec(keyBlob), [dev, alias, uid, pending](Return<ErrorCode> rc) {
+        auto ret = KS_HANDLE_HIDL_ERROR(dev, rc);
+        // A device doesn't have to implement delete_key.
+        bool success = ret == ErrorCode::OK || ret == ErrorCode::UNIMPLEMENTED;
+        if (__android_log_security()) {
+            android_log_event_list(SEC_TAG_KEY_DESTROYED)
+                << int32_t(success) << alias << int32_t(uid) << LOG_ID_SECURITY;
+        }
+        if (!success) {
+            LOG(ERROR) << "Keymaster delete for key " << alias << " of uid " << uid << " failed";
+        }
+        // A blob the device no longer recognizes has nothing left to delete.
+        if (success || ret == ErrorCode::INVALID_KEY_BLOB) completePendingDeletion(pending);
+    });
+}
+
+std::optional<KeyBlobEntry> KeyStore::queuePendingDeletion(const Blob& keyBlob, uid_t uid) {
+    if (mkdir(kPendingDeletionDir, S_IRUSR | S_IWUSR | S_IXUSR) && errno != EEXIST) {
+        ALOGW("could not create pending deletion directory: %s", strerror(errno));
+        return {};
+    }
 
-    if (keyBlob.getType() == ::TYPE_KEYMASTER_10) {
-        dev->deleteKey(blob2hidlVec(keyBlob), [dev, alias, uid](Return<ErrorCode> rc) {
+    uint8_t id[8];
+    if (!RAND_bytes(id, sizeof(id))) {
+        ALOGW("could not generate pending deletion id");
+        return {};
+    }
+    std::stringstream alias;
+    alias << std::hex << std::setfill('0');
+    for (uint8_t b : id) alias << std::setw(2) << int(b);
+
+    // The blob keeps its keystore level encryption under its owner's master key. Encrypted
+    // pending deletions can therefore only be drained while their user is unlocked.
+    KeyBlobEntry entry(alias.str(), kPendingDeletionDir, uid);
+    auto userState = mUserStateDB.getUserStateByUid(uid);
+    auto rc = LockedKeyBlobEntry::get(entry).writeBlobs(keyBlob, {}, userState->getEncryptionKey(),
+                                                        userState->getState());
+    userState = {};
+    if (rc != ResponseCode::NO_ERROR) {
+        ALOGW("could not persist pending deletion %s: %d", alias.str().c_str(), int32_t(rc));
+        return {};
+    }
+    return entry;
+}
+
+void KeyStore::completePendingDeletion(const std::optional<KeyBlobEntry>& pending) {
+    if (!pending) return;
+    LockedKeyBlobEntry::get(*pending).deleteBlobs();
+}
+
+std::list<std::tuple<KeyBlobEntry, Blob>> KeyStore::readPendingDeletions() {
+    ResponseCode rc;
+    std::list<LockedKeyBlobEntry> entries;
+    std::tie(rc, entries) =
+        LockedKeyBlobEntry::list(kPendingDeletionDir, [](uid_t, const std::string&) { return true; });
+    if (rc != ResponseCode::NO_ERROR) return {};
+
+    std::list<std::tuple<KeyBlobEntry, Blob>> pending;
+    for (LockedKeyBlobEntry& lockedEntry : entries) {
+        auto userState = mUserStateDB.getUserStateByUid(lockedEntry->uid());
+        auto [rc, keyBlob, charBlob] =
+            lockedEntry.readBlobs(userState->getEncryptionKey(), userState->getState());
+        userState = {};
+        // Left for when the owner unlocks.
+        if (rc == ResponseCode::LOCKED) continue;
+        if (rc != ResponseCode::NO_ERROR || keyBlob.getType() != ::TYPE_KEYMASTER_10) {
+            LOG(ERROR) << "dropping unreadable pending deletion " << lockedEntry->alias();
+            lockedEntry.deleteBlobs();
+            continue;
+        }
+        pending.emplace_back(*lockedEntry, std::move(keyBlob));
+    }
+    // The entries are unlocked on return, so that the worker callbacks can lock them again.
+    return pending;
+}
+
+void KeyStore::submitPendingDeletions(std::list<std::tuple<KeyBlobEntry, Blob>> pending,
+                                      std::function<void()> done) {
+    // done runs once the last submitted deletion has been answered.
+    if (pending.empty()) {
+        if (done) done();
+        return;
+    }
+    auto remaining = std::make_shared<std::atomic<size_t>>(pending.size());
+    auto answered = [remaining, done] {
+        if (--*remaining == 0 && done) done();
+    };
+    for (auto& [entry, keyBlob] : pending) {
+        auto dev = getDevice(keyBlob);
+        if (!dev) {
+            answered();
+            continue;
+        }
+        std::optional<KeyBlobEntry> pendingEntry = entry;
+        dev->deleteKey(blob2hidlVec(keyBlob), [dev, pendingEntry, answered](Return<ErrorCode> rc) {
             auto ret = KS_HANDLE_HIDL_ERROR(dev, rc);
-            // A device doesn't have to implement delete_key.
-            bool success = ret == ErrorCode::OK || ret == ErrorCode::UNIMPLEMENTED;
-            if (__android_log_security()) {
-                android_log_event_list(SEC_TAG_KEY_DESTROYED)
-                    << int32_t(success) << alias << int32_t(uid) << LOG_ID_SECURITY;
-            }
-            if (!success) {
-                LOG(ERROR) << "Keymaster delete for key " << alias << " of uid " << uid
-                           << " failed";
+            if (ret == ErrorCode::OK || ret == ErrorCode::UNIMPLEMENTED ||
+                ret == ErrorCode::INVALID_KEY_BLOB) {
+                completePendingDeletion(pendingEntry);
+            } else {
+                LOG(ERROR) << "Keymaster delete for pending deletion " << pendingEntry->alias()
+                           << " failed; will retry on next start";
             }
+            answered();
         });
     }
+}
 
+void KeyStore::drainPendingDeletions() {
+    {
+        // Deletions that could not be read before may be readable now.
+        std::lock_guard<std::mutex> lock(mPendingDeletionDrainMutex);
+        mUndrainablePendingDeletions.fill(0);
+    }
+    auto pending = readPendingDeletions();
+    if (!pending.empty()) {
+        LOG(INFO) << "retrying " << pending.size() << " pending Keymaster deletion(s)";
+    }
+    submitPendingDeletions(std::move(pending));
+}
+
+size_t KeyStore::countPendingDeletions() {
+    DIR* dir = opendir(kPendingDeletionDir);
+    if (!dir) return 0;
+    size_t count = 0;
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        // Characteristics files start with a '.', but pending deletions have none.
+        if (file->d_type == DT_REG && file->d_name[0] != '.') ++count;
+    }
+    closedir(dir);
+    return count;
+}
+
+void KeyStore::relievePendingDeletionPressure(SecurityLevel securityLevel) {
+    if (!getDevice(securityLevel)) return;
+    uint32_t limit = android::base::GetUintProperty<uint32_t>("keystore.pending_deletion_limit",
+                                                              kDefaultPendingDeletionLimit);
+    // Counting files needs neither the list fence nor decryption. It covers all security levels,
+    // so it can only overestimate the pending deletions of this one.
+    size_t count = countPendingDeletions();
+    if (count <= limit) return;
+
+    {
+        std::lock_guard<std::mutex> lock(mPendingDeletionDrainMutex);
+        if (mPendingDeletionDrains[securityLevel]) return;
+        // Reading the pending deletions takes the list fence and decrypts every one of them, so
+        // it is not repeated while nothing has been added since it last came up short.
+        if (count <= mUndrainablePendingDeletions[securityLevel]) return;
+        mPendingDeletionDrains[securityLevel] = true;
+    }
+    auto drained = [this, securityLevel] {
+        std::lock_guard<std::mutex> lock(mPendingDeletionDrainMutex);
+        mPendingDeletionDrains[securityLevel] = false;
+    };
+
+    auto pending = readPendingDeletions();
+    pending.remove_if([&](const std::tuple<KeyBlobEntry, Blob>& deletion) {
+        return std::get<Blob>(deletion).getSecurityLevel() != securityLevel;
+    });
+    if (pending.size() <= limit) {
+        std::lock_guard<std::mutex> lock(mPendingDeletionDrainMutex);
+        mUndrainablePendingDeletions[securityLevel] = count;
+        mPendingDeletionDrains[securityLevel] = false;
+        return;
+    }
+
+    // Drain down to half the limit, so that the next few key creations don't pay for this again,
+    // but never occupy the worker with more than kMaxPendingDeletionDrain deletions at once.
+    size_t drain = std::min(pending.size() - limit / 2, kMaxPendingDeletionDrain);
+    LOG(WARNING) << pending.size() << " Keymaster deletions pending on security level "
+                 << toString(securityLevel) << "; draining " << drain;
+    pending.resize(drain);
+    submitPendingDeletions(std::move(pending), drained);
+}
+
+ResponseCode KeyStore::softDel(const LockedKeyBlobEntry& blobfile) {
+    if (mSoftDeleteWindow == 0) return del(blobfile);
+
+    // Only the most recent deletion of an alias can be undone.
+    if (blobfile->getTombstoneTime()) purgeTombstone(blobfile);
+
+    // Grants do not survive a deletion, not even one that is undone later.
+    mGrants.removeAllGrantsToKey(blobfile->uid(), blobfile->alias());
+    auto rc = blobfile.tombstoneBlobs();
+    if (rc == ResponseCode::NO_ERROR) {
+        setTestKey(blobfile, false);
+        mKeyLifecycleNotifier.notify(IKeystoreKeyLifecycleListener::KEY_DELETED, blobfile->uid(),
+                                     blobfile->alias());
+    }
+    return rc;
+}
+
+ResponseCode KeyStore::undelete(const LockedKeyBlobEntry& blobfile) {
+    if (blobfile->hasKeyBlob()) return ResponseCode::KEY_ALREADY_EXISTS;
+
+    auto deletedAt = blobfile->getTombstoneTime();
+    if (!deletedAt) return ResponseCode::KEY_NOT_FOUND;
+    if (time(nullptr) - *deletedAt >= time_t(mSoftDeleteWindow)) {
+        purgeTombstone(blobfile);
+        return ResponseCode::KEY_NOT_FOUND;
+    }
+
+    auto rc = blobfile.restoreTombstonedBlobs();
+    if (rc == ResponseCode::NO_ERROR) {
+        mKeyLifecycleNotifier.notify(IKeystoreKeyLifecycleListener::KEY_CREATED, blobfile->uid(),
+                                     blobfile->alias());
+    }
+    return rc;
+}
+
+LockedKeyBlobEntry KeyStore::getLockedBlobEntryIfTombstoned(const std::string& alias, uid_t uid) {
+    KeyBlobEntry kbe(alias, mUserStateDB.getUserStateByUid(uid)->getUserDirName(), uid);
+    auto result = LockedKeyBlobEntry::get(std::move(kbe));
+    if (!result->getTombstoneTime()) return {};
     return result;
 }
 
-std::string KeyStore::addGrant(const LockedKeyBlobEntry& blobfile, uid_t granteeUid) {
-    return mGrants.put(granteeUid, blobfile);
+void KeyStore::purgeTombstones(uid_t userId, std::function<bool(uid_t, time_t)> filter) {
+    std::string userDirName;
+    {
+        // userState holds a lock which must be relinquished before listTombstones is called.
+        auto userState = mUserStateDB.getUserState(userId);
+        if (!userState) return;
+        userDirName = userState->getUserDirName();
+    }
+
+    ResponseCode rc;
+    std::list<LockedKeyBlobEntry> matches;
+
+    std::tie(rc, matches) = LockedKeyBlobEntry::listTombstones(
+        userDirName, [&](uid_t uid, const std::string&, time_t deletedAt) {
+            return filter(uid, deletedAt);
+        });
+    if (rc != ResponseCode::NO_ERROR) return;
+
+    for (LockedKeyBlobEntry& lockedEntry : matches) {
+        purgeTombstone(lockedEntry);
+    }
+}
+
+namespace {
+
+bool hasUsageExpired(const Blob& charBlob) {
+    if (!charBlob) return false;
+    auto [success, hwEnforced, swEnforced] = charBlob.getKeyCharacteristics();
+    if (!success) return false;
+    auto expiry = hwEnforced.GetTagValue(TAG_USAGE_EXPIRE_DATETIME);
+    if (!expiry.isOk()) expiry = swEnforced.GetTagValue(TAG_USAGE_EXPIRE_DATETIME);
+    if (!expiry.isOk()) return false;
+    auto now = currentDateTime();
+    return now && *now >= dateTimeFromParameter(expiry.value());
+}
+
+bool isExpiredAutoDeleteKey(const Blob& keyBlob, const Blob& charBlob) {
+    return keyBlob.isAutoDelete() && hasUsageExpired(charBlob);
+}
+
+}  // namespace
+
+std::vector<std::string> KeyStore::listUserDirNames() {
+    std::vector<std::string> userDirNames;
+    DIR* dir = opendir(".");
+    if (!dir) {
+        ALOGE("couldn't open keystore directory: %s", strerror(errno));
+        return userDirNames;
+    }
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        if (file->d_type == DT_DIR && android::base::StartsWith(file->d_name, "user_")) {
+            userDirNames.push_back(file->d_name);
+        }
+    }
+    closedir(dir);
+    return userDirNames;
+}
+
+void KeyStore::purgeExpiredKeys() {
+    for (const auto& userDirName : listUserDirNames()) {
+        // The entries are only collected here and locked one at a time below, so that the sweep
+        // never blocks more than one key.
+        std::vector<KeyBlobEntry> entries;
+        ResponseCode rc;
+        std::tie(rc, std::ignore) =
+            LockedKeyBlobEntry::list(userDirName, [&](uid_t uid, const std::string& alias) {
+                entries.emplace_back(alias, userDirName, uid);
+                return false;
+            });
+        if (rc != ResponseCode::NO_ERROR) continue;
+
+        for (auto& entry : entries) {
+            auto lockedEntry = LockedKeyBlobEntry::get(std::move(entry));
+            if (!lockedEntry->hasKeyBlob()) continue;
+            // The characteristics cache is not encrypted, so keys that have not expired are
+            // never decrypted.
+            auto userState = mUserStateDB.getUserStateByUid(lockedEntry->uid());
+            auto [charRc, charBlob] = lockedEntry.readCharacteristicsBlob(
+                userState->getEncryptionKey(), userState->getState());
+            userState = {};
+            if (charRc != ResponseCode::NO_ERROR || !hasUsageExpired(charBlob)) continue;
+
+            auto [rc, keyBlob, keyCharBlob] = get(lockedEntry);
+            if (rc != ResponseCode::NO_ERROR || !isExpiredAutoDeleteKey(keyBlob, keyCharBlob)) {
+                continue;
+            }
+            LOG(INFO) << "deleting expired key " << lockedEntry->alias() << " of uid "
+                      << lockedEntry->uid();
+            del(lockedEntry, IKeystoreKeyLifecycleListener::KEY_INVALIDATED);
+        }
+    }
+}
+
+void KeyStore::purgeExpiredTombstones() {
+    time_t now = time(nullptr);
+    auto expired = [&](time_t deletedAt) { return now - deletedAt >= time_t(mSoftDeleteWindow); };
+    for (const auto& userDirName : listUserDirNames()) {
+        // Like in purgeExpiredKeys, the tombstones are only collected here and locked one at a
+        // time below.
+        std::vector<KeyBlobEntry> entries;
+        ResponseCode rc;
+        std::tie(rc, std::ignore) = LockedKeyBlobEntry::listTombstones(
+            userDirName, [&](uid_t uid, const std::string& alias, time_t deletedAt) {
+                if (expired(deletedAt)) entries.emplace_back(alias, userDirName, uid);
+                return false;
+            });
+        if (rc != ResponseCode::NO_ERROR) continue;
+
+        for (auto& entry : entries) {
+            auto lockedEntry = LockedKeyBlobEntry::get(std::move(entry));
+            // The entry may have been undeleted or deleted again in the meantime.
+            auto deletedAt = lockedEntry->getTombstoneTime();
+            if (deletedAt && expired(*deletedAt)) purgeTombstone(lockedEntry);
+        }
+    }
+}
+
+ResponseCode KeyStore::purgeTombstone(const LockedKeyBlobEntry& blobfile) {
+    auto userState = mUserStateDB.getUserStateByUid(blobfile->uid());
+    auto [rc, keyBlob] =
+        blobfile.readTombstonedKeyBlob(userState->getEncryptionKey(), userState->getState());
+    userState = {};
+
+    if (rc == ResponseCode::NO_ERROR) {
+        deleteKeymasterBlob(keyBlob, blobfile->alias(), blobfile->uid());
+    }
+
+    auto result = blobfile.deleteTombstonedBlobs();
+
+    if (rc != ResponseCode::NO_ERROR) {
+        LOG(ERROR) << "reading tombstoned keyblob failed " << int(rc);
+        return rc;
+    }
+
+    return result;
+}
+
+std::string KeyStore::addGrant(const LockedKeyBlobEntry& blobfile, uid_t granteeUid,
+                               bool certOnly) {
+    return mGrants.put(granteeUid, blobfile, certOnly);
 }
 
 bool KeyStore::removeGrant(const LockedKeyBlobEntry& blobfile, const uid_t granteeUid) {
@@ -357,7 +942,8 @@ KeyStore::getKeyForName(const android::String8& keyName, const uid_t uid, const
     std::tuple<ResponseCode, Blob, Blob, LockedKeyBlobEntry> result;
     auto& [rc, keyBlob, charBlob, lockedEntry] = result;
 
-    lockedEntry = getLockedBlobEntryIfExists(keyName.string(), uid);
+    // Certificate only grants are read through here, but only as certificates.
+    lockedEntry = getLockedBlobEntryIfExists(keyName.string(), uid, type == TYPE_GENERIC);
 
     if (!lockedEntry) return rc = ResponseCode::KEY_NOT_FOUND, std::move(result);
 
@@ -365,6 +951,11 @@ KeyStore::getKeyForName(const android::String8& keyName, const uid_t uid, const
 
     if (rc == ResponseCode::NO_ERROR) {
         if (keyBlob.getType() != type) return rc = ResponseCode::KEY_NOT_FOUND, std::move(result);
+        if (isExpiredAutoDeleteKey(keyBlob, charBlob)) {
+            LOG(INFO) << "deleting expired key " << keyName.string() << " of uid " << uid;
+            del(lockedEntry, IKeystoreKeyLifecycleListener::KEY_INVALIDATED);
+            return rc = ResponseCode::KEY_NOT_FOUND, std::move(result);
+        }
     }
     return result;
 }
@@ -431,6 +1022,187 @@ void KeyStore::writeMetaData() {
     rename(tmpFileName, kMetaDataFile);
 }
 
+void KeyStore::forgetUser(uid_t userId) {
+    mGrants.removeAllGrantsOfUser(userId);
+    mEnforcementPolicy.forget_device_locked(userId);
+
+    std::lock_guard<std::mutex> lock(mReadOnlyUidsMutex);
+    auto uids = mReadOnlyUids;
+    for (auto i = uids.begin(); i != uids.end();) {
+        i = get_user_id(*i) == userId ? uids.erase(i) : std::next(i);
+    }
+    if (uids == mReadOnlyUids) return;
+    if (!writeReadOnlyUids(uids)) {
+        ALOGE("Failed to forget the read-only uids of user %d", userId);
+        return;
+    }
+    mReadOnlyUids = std::move(uids);
+}
+
+bool KeyStore::isUidReadOnly(uid_t uid) const {
+    std::lock_guard<std::mutex> lock(mReadOnlyUidsMutex);
+    return mReadOnlyUids.count(uid) != 0;
+}
+
+ResponseCode KeyStore::setUidReadOnly(uid_t uid, bool readOnly) {
+    std::lock_guard<std::mutex> lock(mReadOnlyUidsMutex);
+    auto uids = mReadOnlyUids;
+    if (readOnly) {
+        uids.insert(uid);
+    } else {
+        uids.erase(uid);
+    }
+    if (uids == mReadOnlyUids) return ResponseCode::NO_ERROR;
+    if (!writeReadOnlyUids(uids)) return ResponseCode::SYSTEM_ERROR;
+    mReadOnlyUids = std::move(uids);
+    return ResponseCode::NO_ERROR;
+}
+
+void KeyStore::readReadOnlyUids() {
+    std::string content;
+    if (!android::base::ReadFileToString(kReadOnlyUidsFile, &content)) {
+        if (errno != ENOENT) ALOGE("couldn't read read-only uids: %s", strerror(errno));
+        return;
+    }
+
+    std::set<uid_t> uids;
+    for (const auto& line : android::base::Split(content, "\n")) {
+        if (line.empty()) continue;
+        uid_t uid;
+        if (!android::base::ParseUint(line, &uid)) {
+            ALOGW("ignoring malformed read-only uid \"%s\"", line.c_str());
+            continue;
+        }
+        uids.insert(uid);
+    }
+
+    std::lock_guard<std::mutex> lock(mReadOnlyUidsMutex);
+    mReadOnlyUids = std::move(uids);
+}
+
+bool KeyStore::writeReadOnlyUids(const std::set<uid_t>& uids) {
+    std::stringstream content;
+    for (uid_t uid : uids) content << uid << "\n";
+
+    const char* tmpFileName = ".readonly_uids.tmp";
+    if (!android::base::WriteStringToFile(content.str(), tmpFileName, S_IRUSR | S_IWUSR,
+                                          getuid(), getgid())) {
+        ALOGE("couldn't write read-only uids: %s", strerror(errno));
+        return false;
+    }
+    if (rename(tmpFileName, kReadOnlyUidsFile) == -1) {
+        ALOGE("couldn't replace read-only uids: %s", strerror(errno));
+        unlink(tmpFileName);
+        return false;
+    }
+    return true;
+}
+
+void KeyStore::setTestKey(const LockedKeyBlobEntry& blobfile, bool testKey) {
+    std::lock_guard<std::mutex> lock(mTestKeysMutex);
+    auto key = std::make_pair(blobfile->uid(), blobfile->alias());
+    // Most keys are not test keys, so most calls end here.
+    if ((mTestKeys.count(key) != 0) == testKey) return;
+    if (testKey) {
+        mTestKeys.insert(key);
+    } else {
+        mTestKeys.erase(key);
+    }
+    if (!writeTestKeys(mTestKeys)) {
+        ALOGE("Failed to update the test key record of %s",
+              toString({blobfile->uid(), blobfile->alias()}).c_str());
+        if (testKey) {
+            mTestKeys.erase(key);
+        } else {
+            mTestKeys.insert(key);
+        }
+    }
+}
+
+size_t KeyStore::deleteTestKeys(uid_t userId) {
+    std::vector<std::pair<uid_t, std::string>> candidates;
+    {
+        std::lock_guard<std::mutex> lock(mTestKeysMutex);
+        for (const auto& key : mTestKeys) {
+            if (get_user_id(key.first) == userId) candidates.push_back(key);
+        }
+    }
+
+    // The records of deleted keys are dropped at the end with a single write.
+    std::vector<std::pair<uid_t, std::string>> gone;
+    size_t deleted = 0;
+    for (const auto& key : candidates) {
+        const auto& [uid, alias] = key;
+        if (isUidReadOnly(uid)) continue;
+        auto lockedEntry = getLockedBlobEntryIfExists(alias, uid);
+        if (!lockedEntry) {
+            gone.push_back(key);
+            continue;
+        }
+        // Keys that cannot be decrypted right now, because the user is locked, are left alone.
+        ResponseCode rc;
+        std::tie(rc, std::ignore, std::ignore) = get(lockedEntry);
+        if (rc != ResponseCode::NO_ERROR) continue;
+        bool blobsDeleted = false;
+        if (deleteEntry(lockedEntry, IKeystoreKeyLifecycleListener::KEY_DELETED, &blobsDeleted) ==
+            ResponseCode::NO_ERROR) {
+            ++deleted;
+        }
+        if (blobsDeleted) gone.push_back(key);
+    }
+
+    std::lock_guard<std::mutex> lock(mTestKeysMutex);
+    size_t erased = 0;
+    for (const auto& key : gone) erased += mTestKeys.erase(key);
+    if (erased != 0 && !writeTestKeys(mTestKeys)) {
+        ALOGE("Failed to update the test key record of user %d", userId);
+    }
+    return deleted;
+}
+
+void KeyStore::readTestKeys() {
+    std::string content;
+    if (!android::base::ReadFileToString(kTestKeysFile, &content)) {
+        if (errno != ENOENT) ALOGE("couldn't read test keys: %s", strerror(errno));
+        return;
+    }
+
+    std::set<std::pair<uid_t, std::string>> keys;
+    for (const auto& line : android::base::Split(content, "\n")) {
+        if (line.empty()) continue;
+        // Entries are named like key files, "<uid>_<encoded alias>".
+        auto separator = line.find('_');
+        uid_t uid;
+        if (separator == std::string::npos ||
+            !android::base::ParseUint(line.substr(0, separator), &uid)) {
+            ALOGW("ignoring malformed test key \"%s\"", line.c_str());
+            continue;
+        }
+        keys.emplace(uid, decodeKeyName(line.substr(separator + 1)));
+    }
+
+    std::lock_guard<std::mutex> lock(mTestKeysMutex);
+    mTestKeys = std::move(keys);
+}
+
+bool KeyStore::writeTestKeys(const std::set<std::pair<uid_t, std::string>>& keys) {
+    std::stringstream content;
+    for (const auto& [uid, alias] : keys) content << uid << "_" << encodeKeyName(alias) << "\n";
+
+    const char* tmpFileName = ".test_keys.tmp";
+    if (!android::base::WriteStringToFile(content.str(), tmpFileName, S_IRUSR | S_IWUSR,
+                                          getuid(), getgid())) {
+        ALOGE("couldn't write test keys: %s", strerror(errno));
+        return false;
+    }
+    if (rename(tmpFileName, kTestKeysFile) == -1) {
+        ALOGE("couldn't replace test keys: %s", strerror(errno));
+        unlink(tmpFileName);
+        return false;
+    }
+    return true;
+}
+
 bool KeyStore::upgradeKeystore() {
     bool upgraded = false;
 
@@ -507,6 +1279,7 @@ void KeyStore::binderDied(const ::android::wp<IBinder>& who) {
         if (mKmDevices[SecurityLevel(i)]) mKmDevices[SecurityLevel(i)]->binderDied(who);
     }
     getConfirmationManager().binderDied(who);
+    mKeyLifecycleNotifier.binderDied(who);
 }
 
 }  // namespace keystore
@@ -26,15 +26,30 @@
 #include "auth_token_table.h"
 #include "blob.h"
 #include "confirmation_manager.h"
+#include "enforcement_trace.h"
+#include "error_counters.h"
 #include "grant_store.h"
+#include "key_lifecycle_notifier.h"
 #include "keymaster_worker.h"
 #include "keystore_keymaster_enforcement.h"
 #include "operation.h"
+#include "slow_call_tracker.h"
 #include "user_state.h"
 
 #include <array>
+#include <atomic>
+#include <chrono>
+#include <condition_variable>
+#include <functional>
+#include <list>
+#include <mutex>
 #include <optional>
+#include <set>
+#include <string>
+#include <thread>
 #include <tuple>
+#include <utility>
+#include <vector>
 
 namespace keystore {
 
@@ -97,7 +112,30 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
         return mKmDevices[blob.getSecurityLevel()];
     }
 
+    /**
+     * Swaps replacement in for the Keymaster device dead in every worker that uses it, e.g.,
+     * after the HAL behind dead restarted. The operations of dead are dropped.
+     */
+    void replaceKeymaster(const sp<Keymaster>& dead, const sp<Keymaster>& replacement);
+
+    /**
+     * Upgrades the database and loads the persisted state. The start and duration of each step
+     * are logged, and the durations are also reported by dumpStartup.
+     */
     ResponseCode initialize();
+    void dumpStartup(int fd) const;
+
+    /*
+     * Reports how often begin() gave up on TOO_MANY_OPERATIONS, per security level.
+     */
+    void dumpBeginRetryLimitHits(int fd) const;
+
+    /**
+     * Once early boot has ended, no new keys restricted to early boot can be created. The state
+     * is not persisted; it is only set once per boot.
+     */
+    bool setEarlyBootEnded() { return !mEarlyBootEnded.exchange(true); }
+    bool isEarlyBootEnded() const { return mEarlyBootEnded; }
 
     State getState(uid_t userId) { return mUserStateDB.getUserState(userId)->getState(); }
 
@@ -108,23 +146,93 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
     ResponseCode readMasterKey(const android::String8& pw, uid_t userId);
 
     LockedKeyBlobEntry getLockedBlobEntryIfNotExists(const std::string& alias, uid_t uid);
-    std::optional<KeyBlobEntry> getBlobEntryIfExists(const std::string& alias, uid_t uid);
-    LockedKeyBlobEntry getLockedBlobEntryIfExists(const std::string& alias, uid_t uid);
+    /*
+     * Certificate only grants are followed only with allowCertOnlyGrants, which is reserved for
+     * reading TYPE_GENERIC entries.
+     */
+    std::optional<KeyBlobEntry> getBlobEntryIfExists(const std::string& alias, uid_t uid,
+                                                     bool allowCertOnlyGrants = false);
+    LockedKeyBlobEntry getLockedBlobEntryIfExists(const std::string& alias, uid_t uid,
+                                                  bool allowCertOnlyGrants = false);
     /*
      * Delete entries owned by userId. If keepUnencryptedEntries is true
      * then only encrypted entries will be removed, otherwise all entries will
      * be removed.
      */
     void resetUser(uid_t userId, bool keepUnenryptedEntries);
+    /**
+     * Drops the state kept about a removed user outside of its key directory: grants from and to
+     * the user's uids, read-only uids of the user and the user's device lock state. Otherwise a
+     * new user that gets the same id would inherit them.
+     */
+    void forgetUser(uid_t userId);
     bool isEmpty(uid_t userId) const;
 
     void lock(uid_t userId);
 
+    /**
+     * State that keystore can leave behind when it dies in the middle of a mutation:
+     * characteristics blobs whose key blob is gone, temporary files of interrupted writes and
+     * grants to keys that no longer exist.
+     */
+    struct ConsistencyReport {
+        size_t orphanedCharacteristics = 0;
+        size_t staleTempFiles = 0;
+        size_t danglingGrants = 0;
+    };
+    /**
+     * Counts the leftovers of all users and, if repair is true, removes them. Temporary files are
+     * only considered stale once they are older than kStaleTempFileAge, so that writes in
+     * progress are left alone.
+     */
+    ConsistencyReport checkConsistency(bool repair);
+
     std::tuple<ResponseCode, Blob, Blob> get(const LockedKeyBlobEntry& blobfile);
     ResponseCode put(const LockedKeyBlobEntry& blobfile, Blob keyBlob, Blob characteristicsBlob);
-    ResponseCode del(const LockedKeyBlobEntry& blobfile);
+    ResponseCode del(const LockedKeyBlobEntry& blobfile,
+                     int32_t event = IKeystoreKeyLifecycleListener::KEY_DELETED);
+
+    /*
+     * Soft-deleted entries can be restored with undelete() until the undo window configured by
+     * ro.keystore.soft_delete_window_secs expires. A window of 0 makes softDel() a plain del().
+     */
+    ResponseCode softDel(const LockedKeyBlobEntry& blobfile);
+    ResponseCode undelete(const LockedKeyBlobEntry& blobfile);
+    LockedKeyBlobEntry getLockedBlobEntryIfTombstoned(const std::string& alias, uid_t uid);
+    /*
+     * Permanently deletes the soft-deleted entries of userId matched by filter. Must not be
+     * called while holding a LockedKeyBlobEntry or a user state lock (see resetUser).
+     */
+    void purgeTombstones(uid_t userId, std::function<bool(uid_t, time_t)> filter);
+    /*
+     * Permanently deletes the soft-deleted entries of all users whose undo window has expired.
+     * Runs once during initialize() and then on the maintenance thread.
+     */
+    void purgeExpiredTombstones();
+
+
+    /*
+     * Keys of a read-only uid, e.g., factory provisioned keys, can be used but not created,
+     * replaced or deleted. The set of read-only uids is persisted in kReadOnlyUidsFile.
+     */
+    bool isUidReadOnly(uid_t uid) const;
+    ResponseCode setUidReadOnly(uid_t uid, bool readOnly);
+
+    /*
+     * Test keys are recorded in kTestKeysFile rather than in their blob headers, so that
+     * deleteTestKeys can find them without reading every blob. Deleting a key drops its record,
+     * also when the deletion is undone later.
+     */
+    void setTestKey(const LockedKeyBlobEntry& blobfile, bool testKey);
+    /*
+     * Deletes the test keys of userId, except those of read-only uids and those that cannot be
+     * decrypted right now. Returns the number of deleted keys. Must not be called while holding a
+     * LockedKeyBlobEntry.
+     */
+    size_t deleteTestKeys(uid_t userId);
 
-    std::string addGrant(const LockedKeyBlobEntry& blobfile, uid_t granteeUid);
+    std::string addGrant(const LockedKeyBlobEntry& blobfile, uid_t granteeUid,
+                         bool certOnly = false);
     bool removeGrant(const LockedKeyBlobEntry& blobfile, const uid_t granteeUid);
     void removeAllGrantsToUid(const uid_t granteeUid);
 
@@ -142,6 +250,30 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
     AuthTokenTable& getAuthTokenTable() { return mAuthTokenTable; }
     KeystoreKeymasterEnforcement& getEnforcementPolicy() { return mEnforcementPolicy; }
     ConfirmationManager& getConfirmationManager() { return *mConfirmationManager; }
+    KeyLifecycleNotifier& getKeyLifecycleNotifier() { return mKeyLifecycleNotifier; }
+    ErrorCounters& getErrorCounters() { return mErrorCounters; }
+    EnforcementTrace& getEnforcementTrace() { return mEnforcementTrace; }
+    SlowCallTracker& getSlowCallTracker() { return mSlowCallTracker; }
+
+    /**
+     * Keymaster deletions are asynchronous. Each key blob is persisted in kPendingDeletionDir
+     * until the device has confirmed its deletion, so that a crash or restart in between cannot
+     * leak the key inside the secure hardware. Pending deletions are retried on startup, and
+     * those of encrypted blobs again when their owner, uid, unlocks. A blob must only be queued
+     * once no key entry refers to it anymore.
+     */
+    std::optional<KeyBlobEntry> queuePendingDeletion(const Blob& keyBlob, uid_t uid);
+    static void completePendingDeletion(const std::optional<KeyBlobEntry>& pending);
+
+    /**
+     * StrongBox implementations in particular have little storage, and keys that are still
+     * waiting for their deletion take up space in it. If more deletions than the system property
+     * keystore.pending_deletion_limit (default kDefaultPendingDeletionLimit) are pending for
+     * securityLevel, up to kMaxPendingDeletionDrain of them are submitted to the worker of
+     * securityLevel, ahead of the key creation that follows. Does not wait for them. Must not be
+     * called with any blob entry locked.
+     */
+    void relievePendingDeletionPressure(SecurityLevel securityLevel);
 
     void addOperationDevice(sp<IBinder> token, std::shared_ptr<KeymasterWorker> dev) {
         std::lock_guard<std::mutex> lock(operationDeviceMapMutex_);
@@ -159,10 +291,26 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
         std::lock_guard<std::mutex> lock(operationDeviceMapMutex_);
         operationDeviceMap_.erase(token);
     }
+    // Like removeOperationDevice, but remembers why keystore dropped the operation, e.g.,
+    // OPERATION_EXPIRED or BACKEND_RESTARTED, so that its client can be told why it is gone.
+    void expireOperationDevice(const sp<IBinder>& token, ResponseCode reason);
+    std::optional<ResponseCode> getOperationDropReason(const sp<IBinder>& token);
 
   private:
     static const char* kOldMasterKey;
     static const char* kMetaDataFile;
+    static const char* kPendingDeletionDir;
+    static const char* kReadOnlyUidsFile;
+    static const char* kTestKeysFile;
+    static const char* kErrorCountersFile;
+    static const char* kGrantsFile;
+    static constexpr time_t kStaleTempFileAge = 600;
+    static constexpr uint32_t kDefaultPendingDeletionLimit = 16;
+    static constexpr size_t kMaxPendingDeletionDrain = 8;
+    static constexpr uint32_t kDefaultOperationIdleTimeoutSecs = 1800;
+    static constexpr size_t kMaxExpiredOperations = 64;
+    static constexpr std::chrono::seconds kExpiredKeySweepInterval = std::chrono::hours(1);
+    static constexpr std::chrono::seconds kTombstoneSweepInterval = std::chrono::hours(1);
     static const android::String16 kRsaKeyType;
     static const android::String16 kEcKeyType;
 
@@ -170,17 +318,34 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
 
     bool mAllowNewFallback;
 
+    uint32_t mSoftDeleteWindow;
+
     UserStateDB mUserStateDB;
     AuthTokenTable mAuthTokenTable;
     KeystoreKeymasterEnforcement mEnforcementPolicy;
     sp<ConfirmationManager> mConfirmationManager;
+    KeyLifecycleNotifier mKeyLifecycleNotifier;
+    ErrorCounters mErrorCounters;
+    EnforcementTrace mEnforcementTrace;
+    SlowCallTracker mSlowCallTracker;
 
     ::keystore::GrantStore mGrants;
 
+    std::atomic_bool mEarlyBootEnded = false;
+
+    // Only written by initialize(), before the service is published.
+    std::vector<std::pair<std::string, std::chrono::milliseconds>> mStartupSteps;
+
     typedef struct { uint32_t version; } keystore_metadata_t;
 
     keystore_metadata_t mMetaData;
 
+    mutable std::mutex mReadOnlyUidsMutex;
+    std::set<uid_t> mReadOnlyUids;
+
+    mutable std::mutex mTestKeysMutex;
+    std::set<std::pair<uid_t, std::string>> mTestKeys;
+
     /**
      * Upgrade the key from the current version to whatever is newest.
      */
@@ -189,10 +354,73 @@ class KeyStore : public ::android::IBinder::DeathRecipient {
     void readMetaData();
     void writeMetaData();
 
+    void readReadOnlyUids();
+    bool writeReadOnlyUids(const std::set<uid_t>& uids);
+
+    void readTestKeys();
+    bool writeTestKeys(const std::set<std::pair<uid_t, std::string>>& keys);
+
+    // Like del, but leaves the test key record alone. Sets blobsDeleted if the files are gone.
+    ResponseCode deleteEntry(const LockedKeyBlobEntry& blobfile, int32_t event,
+                             bool* blobsDeleted);
+
     bool upgradeKeystore();
 
+    void deleteKeymasterBlob(const Blob& keyBlob, const std::string& alias, uid_t uid);
+
+    std::list<std::tuple<KeyBlobEntry, Blob>> readPendingDeletions();
+    // Submits the deletions to the workers of their security levels, and calls done once all of
+    // them have been answered.
+    void submitPendingDeletions(std::list<std::tuple<KeyBlobEntry, Blob>> pending,
+                                std::function<void()> done = {});
+    void drainPendingDeletions();
+    static size_t countPendingDeletions();
+    void runStartupStep(const char* step, const std::function<void()>& run);
+    ResponseCode purgeTombstone(const LockedKeyBlobEntry& blobfile);
+
+    // Whether a drain started by relievePendingDeletionPressure is still running, per level.
+    std::mutex mPendingDeletionDrainMutex;
+    Devices<bool, 3> mPendingDeletionDrains{};
+    // The pending deletion count at which relievePendingDeletionPressure last found too few
+    // deletions of a level that it could submit, e.g., because they belong to locked users. It
+    // does not look again until the count has grown beyond that or a user has been unlocked.
+    Devices<size_t, 3> mUndrainablePendingDeletions{};
+
     std::mutex operationDeviceMapMutex_;
     std::map<sp<IBinder>, std::shared_ptr<KeymasterWorker>> operationDeviceMap_;
+    // The most recently dropped operations and why they were dropped, oldest first. Bounded by
+    // kMaxExpiredOperations.
+    std::list<std::pair<android::wp<IBinder>, ResponseCode>> expiredOperations_;
+
+    void reapIdleOperations(std::chrono::seconds idleTimeout);
+
+    static std::vector<std::string> listUserDirNames();
+    /*
+     * Deletes the keys that were created with KEYSTORE_FLAG_AUTO_DELETE and whose
+     * USAGE_EXPIRE_DATETIME has passed. Keys that cannot be read right now, e.g. because their
+     * user is locked, are left for a later sweep. getKeyForName also deletes such keys lazily
+     * when they are looked up. Runs on the maintenance thread every kExpiredKeySweepInterval.
+     */
+    void purgeExpiredKeys();
+
+    /*
+     * Background work, such as reaping idle operations, runs as periodic tasks on
+     * mMaintenanceThread until mStopMaintenance is set. Each task first runs one interval after
+     * initialize(). Tasks must be added before the thread starts.
+     */
+    struct MaintenanceTask {
+        std::chrono::seconds interval;
+        std::chrono::steady_clock::time_point due;
+        std::function<void()> run;
+    };
+    void addMaintenanceTask(std::chrono::seconds interval, std::function<void()> run);
+    void runMaintenance();
+
+    std::vector<MaintenanceTask> mMaintenanceTasks;
+    std::thread mMaintenanceThread;
+    std::mutex mMaintenanceMutex;
+    std::condition_variable mMaintenanceCondVar;
+    bool mStopMaintenance = false;
 };
 
 }  // namespace keystore
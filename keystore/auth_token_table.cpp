@@ -243,6 +243,24 @@ void AuthTokenTable::onDeviceOffBody() {
     last_off_body_ = clock_function_();
 }
 
+std::optional<std::tuple<HardwareAuthToken, time_t>>
+AuthTokenTable::FindNewestTokenForKey(const AuthorizationSet& key_info) {
+    std::vector<uint64_t> key_sids;
+    ExtractSids(key_info, &key_sids);
+
+    std::lock_guard<std::mutex> lock(entries_mutex_);
+    const Entry* newest = nullptr;
+    for (const auto& entry : entries_) {
+        const auto& token = entry.token();
+        bool matches = std::any_of(key_sids.begin(), key_sids.end(), [&](uint64_t sid) {
+            return sid == token.userId || sid == token.authenticatorId;
+        });
+        if (matches && entry.is_newer_than(newest)) newest = &entry;
+    }
+    if (!newest) return {};
+    return std::make_tuple(newest->token(), clock_function_() - newest->time_received());
+}
+
 void AuthTokenTable::Clear() {
     std::lock_guard<std::mutex> lock(entries_mutex_);
 
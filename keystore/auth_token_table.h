@@ -16,6 +16,8 @@
 
 #include <memory>
 #include <mutex>
+#include <optional>
+#include <tuple>
 #include <vector>
 
 #include <keystore/keymaster_types.h>
@@ -92,6 +94,14 @@ class AuthTokenTable {
 
     void Clear();
 
+    /**
+     * Find the newest token issued for any of the KM_TAG_USER_SECURE_ID entries in \p key_info,
+     * whether or not it authorizes anything, and how many seconds ago it was received. For
+     * diagnostics only.
+     */
+    std::optional<std::tuple<HardwareAuthToken, time_t>>
+    FindNewestTokenForKey(const AuthorizationSet& key_info);
+
     /**
      * This function shall only be used for testing.
      *
@@ -20,6 +20,7 @@
 #include <errno.h>
 #include <fcntl.h>
 #include <string.h>
+#include <sys/stat.h>
 
 #include <log/log.h>
 
@@ -42,6 +43,22 @@ namespace {
 
 constexpr size_t kGcmIvSizeBytes = 96 / 8;
 
+// Infix of tombstone file names, which have the form ".<uid>_del_<encoded alias>".
+constexpr char kTombstoneInfix[] = "_del_";
+constexpr size_t kTombstoneInfixLength = sizeof(kTombstoneInfix) - 1;
+
+// Infix of the characteristics of a tombstone, ".<uid>_delchr_<encoded alias>".
+constexpr char kTombstoneCharacteristicsInfix[] = "_delchr_";
+constexpr size_t kTombstoneCharacteristicsInfixLength = sizeof(kTombstoneCharacteristicsInfix) - 1;
+
+// Infix of characteristics file names, which have the form ".<uid>_chr_<encoded alias>".
+constexpr char kCharacteristicsInfix[] = "_chr_";
+constexpr size_t kCharacteristicsInfixLength = sizeof(kCharacteristicsInfix) - 1;
+
+static_assert(kTombstoneCharacteristicsInfixLength >= kTombstoneInfixLength &&
+                  kTombstoneCharacteristicsInfixLength >= kCharacteristicsInfixLength,
+              "kMaxKeyFileNamePrefixLength must cover the longest infix");
+
 #if defined(__clang__)
 #define OPTNONE __attribute__((optnone))
 #elif defined(__GNUC__)
@@ -298,6 +315,14 @@ void Blob::setCriticalToDeviceEncryption(bool critical) {
     mBlob->flags = setFlag(mBlob->flags, critical, KEYSTORE_FLAG_CRITICAL_TO_DEVICE_ENCRYPTION);
 }
 
+void Blob::setAutoDelete(bool autoDelete) {
+    mBlob->flags = setFlag(mBlob->flags, autoDelete, KEYSTORE_FLAG_AUTO_DELETE);
+}
+
+void Blob::setPruningExempt(bool pruningExempt) {
+    mBlob->flags = setFlag(mBlob->flags, pruningExempt, KEYSTORE_FLAG_PRUNING_EXEMPT);
+}
+
 void Blob::setFallback(bool fallback) {
     if (fallback) {
         mBlob->flags |= KEYSTORE_FLAG_FALLBACK;
@@ -410,11 +435,15 @@ ResponseCode Blob::readBlob(const std::string& filename, const std::vector<uint8
     }
 
     if (rawBlobIsEncrypted(*rawBlob)) {
+        // The header stays readable, so that callers can tell what kind of key is unavailable.
         if (state == STATE_LOCKED) {
             mBlob = std::move(rawBlob);
             return ResponseCode::LOCKED;
         }
-        if (state == STATE_UNINITIALIZED) return ResponseCode::UNINITIALIZED;
+        if (state == STATE_UNINITIALIZED) {
+            mBlob = std::move(rawBlob);
+            return ResponseCode::UNINITIALIZED;
+        }
     }
 
     if (fileLength < offsetof(blobv3, value)) {
@@ -509,6 +538,18 @@ LockedKeyBlobEntry::readBlobs(const std::vector<uint8_t>& aes_key, State state)
     return result;
 }
 
+std::tuple<ResponseCode, Blob>
+LockedKeyBlobEntry::readCharacteristicsBlob(const std::vector<uint8_t>& aes_key,
+                                            State state) const {
+    std::tuple<ResponseCode, Blob> result;
+    auto& [rc, characteristicsBlob] = result;
+    if (entry_ == nullptr) return rc = ResponseCode::SYSTEM_ERROR, result;
+    if (!entry_->hasCharacteristicsBlob()) return rc = ResponseCode::KEY_NOT_FOUND, result;
+
+    rc = characteristicsBlob.readBlob(entry_->getCharacteristicsBlobPath(), aes_key, state);
+    return result;
+}
+
 ResponseCode LockedKeyBlobEntry::deleteBlobs() const {
     if (entry_ == nullptr) return ResponseCode::NO_ERROR;
 
@@ -531,6 +572,88 @@ ResponseCode LockedKeyBlobEntry::deleteBlobs() const {
     return rc2;
 }
 
+ResponseCode LockedKeyBlobEntry::tombstoneBlobs() const {
+    if (entry_ == nullptr) return ResponseCode::SYSTEM_ERROR;
+
+    const std::string keyBlobPath = entry_->getKeyBlobPath();
+    const std::string tombstonePath = entry_->getTombstoneBlobPath();
+    if (rename(keyBlobPath.c_str(), tombstonePath.c_str()) == -1) {
+        int error = errno;
+        LOG(ERROR) << "could not tombstone key blob file " << keyBlobPath
+                   << " because: " << strerror(error);
+        return error == ENOENT ? ResponseCode::KEY_NOT_FOUND : ResponseCode::SYSTEM_ERROR;
+    }
+    // rename preserves the modification time, but the tombstone must be dated by the deletion.
+    if (utimensat(AT_FDCWD, tombstonePath.c_str(), nullptr, 0) == -1) {
+        ALOGW("Failed to date tombstone \"%s\"", tombstonePath.c_str());
+    }
+
+    if (rename(entry_->getCharacteristicsBlobPath().c_str(),
+               entry_->getTombstoneCharacteristicsBlobPath().c_str()) == -1 &&
+        errno != ENOENT) {
+        // The characteristics cache can be recreated from the key blob, so this is not fatal.
+        ALOGW("Failed to tombstone key characteristics file \"%s\"",
+              entry_->getCharacteristicsBlobPath().c_str());
+    }
+
+    fsyncDirectory(getContainingDirectory(keyBlobPath));
+    return ResponseCode::NO_ERROR;
+}
+
+ResponseCode LockedKeyBlobEntry::restoreTombstonedBlobs() const {
+    if (entry_ == nullptr) return ResponseCode::SYSTEM_ERROR;
+
+    const std::string keyBlobPath = entry_->getKeyBlobPath();
+    if (rename(entry_->getTombstoneBlobPath().c_str(), keyBlobPath.c_str()) == -1) {
+        int error = errno;
+        LOG(ERROR) << "could not restore tombstoned key blob file " << keyBlobPath
+                   << " because: " << strerror(error);
+        return error == ENOENT ? ResponseCode::KEY_NOT_FOUND : ResponseCode::SYSTEM_ERROR;
+    }
+
+    if (rename(entry_->getTombstoneCharacteristicsBlobPath().c_str(),
+               entry_->getCharacteristicsBlobPath().c_str()) == -1 &&
+        errno != ENOENT) {
+        ALOGW("Failed to restore key characteristics file \"%s\"",
+              entry_->getCharacteristicsBlobPath().c_str());
+    }
+
+    fsyncDirectory(getContainingDirectory(keyBlobPath));
+    return ResponseCode::NO_ERROR;
+}
+
+std::tuple<ResponseCode, Blob>
+LockedKeyBlobEntry::readTombstonedKeyBlob(const std::vector<uint8_t>& aes_key, State state) const {
+    std::tuple<ResponseCode, Blob> result;
+    auto& [rc, keyBlob] = result;
+    if (entry_ == nullptr) return rc = ResponseCode::SYSTEM_ERROR, result;
+
+    rc = keyBlob.readBlob(entry_->getTombstoneBlobPath(), aes_key, state);
+    return result;
+}
+
+ResponseCode LockedKeyBlobEntry::deleteTombstonedBlobs() const {
+    if (entry_ == nullptr) return ResponseCode::NO_ERROR;
+
+    // always try to delete both
+    ResponseCode rc1 = (unlink(entry_->getTombstoneBlobPath().c_str()) && errno != ENOENT)
+                           ? ResponseCode::SYSTEM_ERROR
+                           : ResponseCode::NO_ERROR;
+    if (rc1 != ResponseCode::NO_ERROR) {
+        ALOGW("Failed to delete tombstone \"%s\"", entry_->getTombstoneBlobPath().c_str());
+    }
+    ResponseCode rc2 =
+        (unlink(entry_->getTombstoneCharacteristicsBlobPath().c_str()) && errno != ENOENT)
+            ? ResponseCode::SYSTEM_ERROR
+            : ResponseCode::NO_ERROR;
+    if (rc2 != ResponseCode::NO_ERROR) {
+        ALOGW("Failed to delete characteristics tombstone \"%s\"",
+              entry_->getTombstoneCharacteristicsBlobPath().c_str());
+    }
+    if (rc1 != ResponseCode::NO_ERROR) return rc1;
+    return rc2;
+}
+
 keystore::SecurityLevel Blob::getSecurityLevel() const {
     return keystore::flagsToSecurityLevel(mBlob->flags);
 }
@@ -599,6 +722,9 @@ std::set<KeyBlobEntry> LockedKeyBlobEntry::locked_blobs_;
 std::mutex LockedKeyBlobEntry::locked_blobs_mutex_;
 std::condition_variable LockedKeyBlobEntry::locked_blobs_mutex_cond_var_;
 
+// A dot, a uid of up to ten digits and the longest infix.
+const size_t kMaxKeyFileNamePrefixLength = 1 + 10 + kTombstoneCharacteristicsInfixLength;
+
 /* Here is the encoding of key names. This is necessary in order to allow arbitrary
  * characters in key names. Characters in [0-~] are not encoded. Others are encoded
  * into two bytes. The first byte is one of [+-.] which represents the first
@@ -689,10 +815,33 @@ std::string KeyBlobEntry::getCharacteristicsBlobPath() const {
     std::stringstream s;
     if (!masterkey_)
         s << user_dir_ << "/"
-          << "." << uid_ << "_chr_" << encodeKeyName(alias_);
+          << "." << uid_ << kCharacteristicsInfix << encodeKeyName(alias_);
+    return s.str();
+}
+
+std::string KeyBlobEntry::getTombstoneBlobPath() const {
+    std::stringstream s;
+    if (!masterkey_)
+        s << user_dir_ << "/"
+          << "." << uid_ << kTombstoneInfix << encodeKeyName(alias_);
     return s.str();
 }
 
+std::string KeyBlobEntry::getTombstoneCharacteristicsBlobPath() const {
+    std::stringstream s;
+    if (!masterkey_)
+        s << user_dir_ << "/"
+          << "." << uid_ << kTombstoneCharacteristicsInfix << encodeKeyName(alias_);
+    return s.str();
+}
+
+std::optional<time_t> KeyBlobEntry::getTombstoneTime() const {
+    if (masterkey_) return {};
+    struct stat sbuf;
+    if (stat(getTombstoneBlobPath().c_str(), &sbuf) != 0) return {};
+    return sbuf.st_mtime;
+}
+
 bool KeyBlobEntry::hasKeyBlob() const {
     int trys = 3;
     while (trys--) {
@@ -742,6 +891,56 @@ static std::tuple<bool, uid_t, std::string> filename2UidAlias(const std::string&
     return result;
 }
 
+static std::tuple<bool, uid_t, std::string> tombstone2UidAlias(const std::string& filename) {
+    std::tuple<bool, uid_t, std::string> result;
+
+    auto& [success, uid, alias] = result;
+
+    success = false;
+
+    if (filename[0] != '.') return result;
+
+    // The uid must be followed immediately by the tombstone infix. This rules out
+    // characteristics files whose alias happens to contain the infix.
+    auto sep = filename.find('_');
+    if (sep == std::string::npos ||
+        filename.compare(sep, kTombstoneInfixLength, kTombstoneInfix) != 0) {
+        return result;
+    }
+
+    std::stringstream s(filename.substr(1, sep - 1));
+    s >> uid;
+    if (!s) return result;
+
+    alias = decodeKeyName(filename.substr(sep + kTombstoneInfixLength));
+    success = true;
+    return result;
+}
+
+static std::tuple<bool, uid_t, std::string> characteristics2UidAlias(const std::string& filename) {
+    std::tuple<bool, uid_t, std::string> result;
+
+    auto& [success, uid, alias] = result;
+
+    success = false;
+
+    if (filename[0] != '.') return result;
+
+    auto sep = filename.find('_');
+    if (sep == std::string::npos ||
+        filename.compare(sep, kCharacteristicsInfixLength, kCharacteristicsInfix) != 0) {
+        return result;
+    }
+
+    std::stringstream s(filename.substr(1, sep - 1));
+    s >> uid;
+    if (!s) return result;
+
+    alias = decodeKeyName(filename.substr(sep + kCharacteristicsInfixLength));
+    success = true;
+    return result;
+}
+
 std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>>
 LockedKeyBlobEntry::list(const std::string& user_dir,
                          std::function<bool(uid_t, const std::string&)> filter) {
@@ -789,3 +988,79 @@ LockedKeyBlobEntry::list(const std::string& user_dir,
     return std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>&&>{ResponseCode::NO_ERROR,
                                                                      std::move(matches)};
 }
+
+std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>>
+LockedKeyBlobEntry::listTombstones(const std::string& user_dir,
+                                   std::function<bool(uid_t, const std::string&, time_t)> filter) {
+    std::list<LockedKeyBlobEntry> matches;
+
+    // Same fence as in list() above.
+    std::unique_lock<std::mutex> lock(locked_blobs_mutex_);
+    locked_blobs_mutex_cond_var_.wait(lock, [&] { return locked_blobs_.empty(); });
+
+    DIR* dir = opendir(user_dir.c_str());
+    if (!dir) {
+        ALOGW("can't open directory for user: %s", strerror(errno));
+        return std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>&&>{ResponseCode::SYSTEM_ERROR,
+                                                                         std::move(matches)};
+    }
+
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        if (file->d_type != DT_REG) {
+            continue;
+        }
+
+        auto [success, uid, alias] = tombstone2UidAlias(file->d_name);
+        if (!success) continue;
+
+        struct stat sbuf;
+        if (fstatat(dirfd(dir), file->d_name, &sbuf, 0) != 0) {
+            ALOGW("could not stat tombstone \"%s\"", file->d_name);
+            continue;
+        }
+
+        if (!filter(uid, alias, sbuf.st_mtime)) continue;
+
+        auto [iterator, dummy] = locked_blobs_.emplace(alias, user_dir, uid);
+        matches.push_back(*iterator);
+    }
+    closedir(dir);
+    return std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>&&>{ResponseCode::NO_ERROR,
+                                                                     std::move(matches)};
+}
+
+std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>>
+LockedKeyBlobEntry::listOrphanedCharacteristics(const std::string& user_dir) {
+    std::list<LockedKeyBlobEntry> matches;
+
+    // Same fence as in list() above.
+    std::unique_lock<std::mutex> lock(locked_blobs_mutex_);
+    locked_blobs_mutex_cond_var_.wait(lock, [&] { return locked_blobs_.empty(); });
+
+    DIR* dir = opendir(user_dir.c_str());
+    if (!dir) {
+        ALOGW("can't open directory for user: %s", strerror(errno));
+        return std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>&&>{ResponseCode::SYSTEM_ERROR,
+                                                                         std::move(matches)};
+    }
+
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        if (file->d_type != DT_REG) {
+            continue;
+        }
+
+        auto [success, uid, alias] = characteristics2UidAlias(file->d_name);
+        if (!success) continue;
+
+        KeyBlobEntry entry(alias, user_dir, uid);
+        if (entry.hasKeyBlob()) continue;
+
+        auto [iterator, dummy] = locked_blobs_.insert(std::move(entry));
+        matches.push_back(*iterator);
+    }
+    closedir(dir);
+    return std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>&&>{ResponseCode::NO_ERROR,
+                                                                     std::move(matches)};
+}
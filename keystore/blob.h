@@ -28,6 +28,7 @@
 #include <keystore/keystore.h>
 #include <list>
 #include <mutex>
+#include <optional>
 #include <set>
 #include <sstream>
 #include <vector>
@@ -138,6 +139,12 @@ class Blob {
     bool isCriticalToDeviceEncryption() const;
     void setCriticalToDeviceEncryption(bool critical);
 
+    bool isAutoDelete() const { return mBlob->flags & KEYSTORE_FLAG_AUTO_DELETE; }
+    void setAutoDelete(bool autoDelete);
+
+    bool isPruningExempt() const { return mBlob->flags & KEYSTORE_FLAG_PRUNING_EXEMPT; }
+    void setPruningExempt(bool pruningExempt);
+
     bool isFallback() const { return mBlob->flags & KEYSTORE_FLAG_FALLBACK; }
     void setFallback(bool fallback);
 
@@ -199,6 +206,18 @@ class KeyBlobEntry {
     bool hasKeyBlob() const;
     bool hasCharacteristicsBlob() const;
 
+    /*
+     * Soft-deleted entries are kept as hidden tombstone files next to the live entry until the
+     * undo window expires. They are invisible to lookups and listings.
+     */
+    std::string getTombstoneBlobPath() const;
+    std::string getTombstoneCharacteristicsBlobPath() const;
+
+    /*
+     * Returns the time at which the entry was soft-deleted or nullopt if there is no tombstone.
+     */
+    std::optional<time_t> getTombstoneTime() const;
+
     bool operator<(const KeyBlobEntry& rhs) const {
         return std::tie(uid_, alias_, user_dir_) < std::tie(rhs.uid_, rhs.alias_, rhs.user_dir_);
     }
@@ -271,13 +290,45 @@ class LockedKeyBlobEntry {
                             const std::vector<uint8_t>& aes_key, State state) const;
     std::tuple<ResponseCode, Blob, Blob> readBlobs(const std::vector<uint8_t>& aes_key,
                                                    State state) const;
+    /*
+     * Reads only the characteristics blob. Current characteristics caches are not encrypted, so
+     * unlike readBlobs() this works while the user is locked, and the key blob is not touched.
+     */
+    std::tuple<ResponseCode, Blob> readCharacteristicsBlob(const std::vector<uint8_t>& aes_key,
+                                                           State state) const;
     ResponseCode deleteBlobs() const;
 
+    /*
+     * Tombstone support for soft deletion. tombstoneBlobs() hides the live blob files,
+     * restoreTombstonedBlobs() makes them visible again, and deleteTombstonedBlobs() removes
+     * the tombstone for good.
+     */
+    static std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>>
+    listTombstones(const std::string& user_dir,
+                   std::function<bool(uid_t, const std::string&, time_t)> filter);
+    ResponseCode tombstoneBlobs() const;
+    ResponseCode restoreTombstonedBlobs() const;
+    std::tuple<ResponseCode, Blob> readTombstonedKeyBlob(const std::vector<uint8_t>& aes_key,
+                                                         State state) const;
+    ResponseCode deleteTombstonedBlobs() const;
+
+    /*
+     * Lists the entries of user_dir that have a characteristics blob but no key blob. keystore
+     * leaves them behind if it dies between deleting the two files of a key. deleteBlobs()
+     * removes them.
+     */
+    static std::tuple<ResponseCode, std::list<LockedKeyBlobEntry>>
+    listOrphanedCharacteristics(const std::string& user_dir);
+
     inline explicit operator bool() const { return entry_ != nullptr; }
     inline const KeyBlobEntry& operator*() const { return *entry_; }
     inline const KeyBlobEntry* operator->() const { return entry_; }
 };
 
+// Length of the longest file name prefix KeyBlobEntry derives from a key, which is put in front
+// of the encoded alias.
+extern const size_t kMaxKeyFileNamePrefixLength;
+
 // Visible for testing
 std::string encodeKeyName(const std::string& keyName);
 std::string decodeKeyName(const std::string& encodedName);
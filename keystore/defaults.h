@@ -18,6 +18,9 @@
 #ifndef KEYSTORE_DEFAULTS_H_
 #define KEYSTORE_DEFAULTS_H_
 
+#include <stddef.h>
+#include <stdint.h>
+
 /*
  * These must be kept in sync with
  * frameworks/base/keystore/java/android/security/KeyPairGeneratorSpec.java
@@ -39,4 +42,21 @@ constexpr int32_t RSA_DEFAULT_EXPONENT = 0x10001;
 constexpr int32_t RSA_MIN_KEY_SIZE = 512;
 constexpr int32_t RSA_MAX_KEY_SIZE = 8192;
 
+/*
+ * Maximum sizes of caller supplied buffers, enforced before anything is passed on to Keymaster.
+ * Each can be overridden with the property named in the comment.
+ */
+
+/* ro.keystore.max_entropy_size. Keymaster 4 itself rejects more than 2 KiB. */
+constexpr size_t MAX_ENTROPY_SIZE = 2 * 1024;
+/* ro.keystore.max_key_data_size. Also applies to wrapped keys and insert(). */
+constexpr size_t MAX_KEY_DATA_SIZE = 64 * 1024;
+/* ro.keystore.max_masking_key_size. Masking keys are 32 bytes. */
+constexpr size_t MAX_MASKING_KEY_SIZE = 32;
+/* ro.keystore.max_operation_data_size. Applies to update and finish input and signatures. */
+constexpr size_t MAX_OPERATION_DATA_SIZE = 256 * 1024;
+
+/* Maximum number of aliases looked up by a single getKeyCharacteristicsBatch() call. */
+constexpr size_t MAX_KEY_BATCH_SIZE = 256;
+
 #endif /* KEYSTORE_DEFAULTS_H_ */
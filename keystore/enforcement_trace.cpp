@@ -0,0 +1,71 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#define LOG_TAG "keystore"
+
+#include "enforcement_trace.h"
+
+#include <inttypes.h>
+#include <stdio.h>
+
+#include <string>
+
+#include <android-base/properties.h>
+
+namespace keystore {
+
+EnforcementTrace::EnforcementTrace()
+    : mEnabled(android::base::GetBoolProperty("ro.debuggable", false)) {}
+
+void EnforcementTrace::record(EnforcementFailure failure) {
+    if (!mEnabled) return;
+    std::lock_guard<std::mutex> lock(mMutex);
+    if (mFailures.size() == kCapacity) mFailures.pop_front();
+    mFailures.push_back(std::move(failure));
+}
+
+void EnforcementTrace::dump(int fd) const {
+    if (!mEnabled) return;
+    std::lock_guard<std::mutex> lock(mMutex);
+    dprintf(fd, "Recent enforcement failures (oldest first):\n");
+    for (const auto& f : mFailures) {
+        struct tm tm;
+        char when[32];
+        strftime(when, sizeof(when), "%F %T", localtime_r(&f.when, &tm));
+        dprintf(fd, "  %s %s uid=%d purpose=%s result=%d\n", when, f.stage, f.owner,
+                toString(f.purpose).c_str(), f.result);
+
+        dprintf(fd, "    key: sids=[");
+        for (size_t i = 0; i < f.secureIds.size(); ++i) {
+            dprintf(fd, "%s%" PRIu64, i ? "," : "", f.secureIds[i]);
+        }
+        dprintf(fd, "] auth_type=%s timeout=%s unlocked_device_required=%d\n",
+                f.authType ? toString(*f.authType).c_str() : "none",
+                f.authTimeout ? std::to_string(*f.authTimeout).c_str() : "per-op",
+                f.unlockedDeviceRequired);
+
+        dprintf(fd, "    state: device_locked=%d", f.deviceLocked);
+        if (f.tokenFound) {
+            dprintf(fd, " token: user_id=%" PRIu64 " authenticator_id=%" PRIu64 " age=%llds\n",
+                    f.tokenUserId, f.tokenAuthenticatorId,
+                    static_cast<long long>(f.tokenAgeSeconds));
+        } else {
+            dprintf(fd, " token: none\n");
+        }
+    }
+}
+
+}  // namespace keystore
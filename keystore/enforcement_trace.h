@@ -0,0 +1,78 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_ENFORCEMENT_TRACE_H_
+#define KEYSTORE_ENFORCEMENT_TRACE_H_
+
+#include <sys/types.h>
+#include <time.h>
+
+#include <deque>
+#include <mutex>
+#include <optional>
+#include <vector>
+
+#include <keystore/keymaster_types.h>
+
+namespace keystore {
+
+/**
+ * The requirements of a key, and the state keystore found, when an operation was refused by
+ * enforcement. Lets developers see which requirement of an auth-bound key was not met.
+ */
+struct EnforcementFailure {
+    time_t when;
+    const char* stage;
+    uid_t owner;
+    KeyPurpose purpose;
+    std::vector<uint64_t> secureIds;
+    std::optional<HardwareAuthenticatorType> authType;
+    std::optional<uint32_t> authTimeout;
+    bool unlockedDeviceRequired;
+    bool deviceLocked;
+    // The newest auth token for any of secureIds. Only meaningful if tokenFound is true.
+    bool tokenFound;
+    uint64_t tokenUserId;
+    uint64_t tokenAuthenticatorId;
+    time_t tokenAgeSeconds;
+    int32_t result;
+};
+
+/**
+ * EnforcementTrace keeps the most recent enforcement failures in a ring buffer for dumpsys. It only
+ * records anything on debuggable builds, because the trace reveals which authenticators are
+ * enrolled and when the user last authenticated.
+ */
+class EnforcementTrace {
+  public:
+    EnforcementTrace();
+
+    bool enabled() const { return mEnabled; }
+    void record(EnforcementFailure failure);
+    void dump(int fd) const;
+
+  private:
+    static constexpr size_t kCapacity = 32;
+
+    const bool mEnabled;
+    // This mutex protects all data below it.
+    mutable std::mutex mMutex;
+    std::deque<EnforcementFailure> mFailures;
+};
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_ENFORCEMENT_TRACE_H_
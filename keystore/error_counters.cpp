@@ -0,0 +1,94 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#define LOG_TAG "keystore"
+
+#include "error_counters.h"
+
+#include <errno.h>
+#include <inttypes.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include <sstream>
+
+#include <android-base/file.h>
+#include <android-base/parseint.h>
+#include <android-base/strings.h>
+#include <log/log.h>
+
+namespace keystore {
+
+void ErrorCounters::load() {
+    std::string content;
+    if (!android::base::ReadFileToString(mFileName, &content)) {
+        if (errno != ENOENT) ALOGE("couldn't read error counters: %s", strerror(errno));
+        return;
+    }
+
+    std::lock_guard<std::mutex> lock(mMutex);
+    for (const auto& line : android::base::Split(content, "\n")) {
+        if (line.empty()) continue;
+        auto fields = android::base::Split(line, " ");
+        int32_t errorCode;
+        uint64_t count;
+        if (fields.size() != 3 || !android::base::ParseInt(fields[1], &errorCode) ||
+            !android::base::ParseUint(fields[2], &count)) {
+            ALOGW("ignoring malformed error counter \"%s\"", line.c_str());
+            continue;
+        }
+        mCounters[{fields[0], errorCode}] += count;
+    }
+}
+
+void ErrorCounters::record(const std::string& api, int32_t errorCode) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    ++mCounters[{api, errorCode}];
+    if (++mUnpersisted >= kPersistInterval) persist();
+}
+
+void ErrorCounters::dump(int fd) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    dprintf(fd, "Error counters (api, error code, count):\n");
+    for (const auto& [key, count] : mCounters) {
+        dprintf(fd, "  %s %d %" PRIu64 "\n", key.first.c_str(), key.second, count);
+    }
+    if (mUnpersisted) persist();
+}
+
+void ErrorCounters::persist() {
+    std::stringstream content;
+    for (const auto& [key, count] : mCounters) {
+        content << key.first << " " << key.second << " " << count << "\n";
+    }
+
+    std::string tmpFileName = mFileName + ".tmp";
+    if (!android::base::WriteStringToFile(content.str(), tmpFileName, S_IRUSR | S_IWUSR, getuid(),
+                                          getgid())) {
+        ALOGE("couldn't write error counters: %s", strerror(errno));
+        return;
+    }
+    if (rename(tmpFileName.c_str(), mFileName.c_str()) == -1) {
+        ALOGE("couldn't replace error counters: %s", strerror(errno));
+        unlink(tmpFileName.c_str());
+        return;
+    }
+    mUnpersisted = 0;
+}
+
+}  // namespace keystore
@@ -0,0 +1,58 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_ERROR_COUNTERS_H_
+#define KEYSTORE_ERROR_COUNTERS_H_
+
+#include <stdint.h>
+
+#include <map>
+#include <mutex>
+#include <string>
+#include <utility>
+
+namespace keystore {
+
+/**
+ * ErrorCounters counts the error codes returned by each keystore API. The counters survive
+ * restarts, so that a spike in a particular error, e.g. INVALID_KEY_BLOB after an OTA, shows up
+ * in dumpsys without having to reproduce it. The counters are written back to disk every
+ * kPersistInterval errors and whenever they are dumped.
+ */
+class ErrorCounters {
+  public:
+    explicit ErrorCounters(std::string fileName) : mFileName(std::move(fileName)) {}
+
+    void load();
+    void record(const std::string& api, int32_t errorCode);
+    void dump(int fd);
+
+  private:
+    static constexpr uint32_t kPersistInterval = 32;
+
+    // Must be called with mMutex held.
+    void persist();
+
+    const std::string mFileName;
+    // This mutex protects all data below it.
+    std::mutex mMutex;
+    std::map<std::pair<std::string, int32_t>, uint64_t> mCounters;
+    uint32_t mUnpersisted = 0;
+};
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_ERROR_COUNTERS_H_
@@ -0,0 +1,235 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#define LOG_TAG "keystore"
+
+#include "fd_operation_streamer.h"
+
+#include <sys/mman.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include <future>
+#include <limits>
+#include <mutex>
+#include <set>
+#include <thread>
+
+#include <android-base/file.h>
+#include <cutils/ashmem.h>
+#include <log/log.h>
+
+#include "KeyStore.h"
+#include "keymaster_worker.h"
+
+namespace keystore {
+
+using ::android::IBinder;
+using ::android::sp;
+using ::android::security::keymaster::operationFailed;
+using ::android::security::keymaster::OperationResult;
+
+namespace {
+
+// Every stream holds a thread for as long as its file descriptors block.
+constexpr size_t kMaxActiveStreams = 8;
+
+std::mutex activeStreamsMutex;
+std::set<sp<IBinder>> activeStreams;
+
+}  // anonymous namespace
+
+FdOperationStreamer::FdOperationStreamer(KeyStore* keyStore, std::shared_ptr<KeymasterWorker> dev,
+                                         sp<IBinder> token, AuthorizationSet params,
+                                         android::base::unique_fd input,
+                                         android::base::unique_fd output, size_t chunkSize,
+                                         Callback cb)
+    : keyStore_(keyStore), dev_(std::move(dev)), token_(std::move(token)),
+      params_(std::move(params)), input_(std::move(input)), output_(std::move(output)),
+      chunkSize_(chunkSize), cb_(std::move(cb)) {}
+
+FdOperationStreamer::~FdOperationStreamer() {
+    if (outputMap_) munmap(outputMap_, outputMapSize_);
+}
+
+bool FdOperationStreamer::isStreamableFd(int fd) {
+    struct stat st;
+    if (fstat(fd, &st) == -1) return false;
+    return S_ISREG(st.st_mode) || ashmem_valid(fd);
+}
+
+bool FdOperationStreamer::isStreaming(const sp<IBinder>& token) {
+    std::lock_guard<std::mutex> lock(activeStreamsMutex);
+    return activeStreams.count(token) != 0;
+}
+
+KeyStoreServiceReturnCode FdOperationStreamer::update() {
+    return start();
+}
+
+KeyStoreServiceReturnCode FdOperationStreamer::finish(hidl_vec<uint8_t> signature,
+                                                      hidl_vec<uint8_t> entropy) {
+    finish_ = true;
+    signature_ = std::move(signature);
+    entropy_ = std::move(entropy);
+    return start();
+}
+
+KeyStoreServiceReturnCode FdOperationStreamer::start() {
+    {
+        std::lock_guard<std::mutex> lock(activeStreamsMutex);
+        if (activeStreams.count(token_)) {
+            ALOGW("refusing to stream an operation that is already being streamed");
+            return ResponseCode::BACKEND_BUSY;
+        }
+        if (activeStreams.size() >= kMaxActiveStreams) {
+            ALOGW("refusing to stream an operation, %zu streams are active", activeStreams.size());
+            return ResponseCode::BACKEND_BUSY;
+        }
+        activeStreams.insert(token_);
+    }
+    auto self = shared_from_this();
+    std::thread([self] { self->done(self->stream()); }).detach();
+    return ResponseCode::NO_ERROR;
+}
+
+// Submits a request to the worker and waits for its result on the calling thread.
+template <typename Request> static OperationResult awaitResult(Request request) {
+    std::promise<OperationResult> promise;
+    auto future = promise.get_future();
+    request([&promise](OperationResult result) { promise.set_value(std::move(result)); });
+    return future.get();
+}
+
+OperationResult FdOperationStreamer::stream() {
+    // Input that was read but not yet consumed by the Keymaster.
+    std::vector<uint8_t> pending;
+    int consumed = 0;
+    while (true) {
+        if (pending.empty()) {
+            pending.resize(chunkSize_);
+            ssize_t n = TEMP_FAILURE_RETRY(read(input_, pending.data(), pending.size()));
+            if (n == -1) {
+                ALOGE("failed to read operation input: %s", strerror(errno));
+                return fail(ResponseCode::SYSTEM_ERROR);
+            }
+            pending.resize(n);
+            if (n == 0) break;
+        }
+
+        auto result = awaitResult([&](KeymasterWorker::update_cb cb) {
+            dev_->update(token_, std::move(params_), pending, std::move(cb));
+        });
+        params_ = {};
+        if (!result.resultCode.isOk()) return result;
+
+        auto rc = writeOutput(result.data);
+        if (!rc.isOk()) return fail(rc);
+        // A Keymaster that accepts nothing would keep us looping forever.
+        if (result.inputConsumed <= 0 || size_t(result.inputConsumed) > pending.size()) {
+            ALOGE("Keymaster consumed %d of %zu input bytes", result.inputConsumed,
+                  pending.size());
+            return fail(ErrorCode::INVALID_INPUT_LENGTH);
+        }
+        // The total is reported as an int, like the input consumed by a single update.
+        if (result.inputConsumed > std::numeric_limits<int>::max() - consumed) {
+            ALOGE("operation input exceeds %d bytes", std::numeric_limits<int>::max());
+            return fail(ErrorCode::INVALID_INPUT_LENGTH);
+        }
+        pending.erase(pending.begin(), pending.begin() + result.inputConsumed);
+        consumed += result.inputConsumed;
+    }
+
+    OperationResult result;
+    if (finish_) {
+        result = awaitResult([&](KeymasterWorker::finish_cb cb) {
+            dev_->finish(token_, std::move(params_), {} /* input */, std::move(signature_),
+                         std::move(entropy_), std::move(cb));
+        });
+        if (!result.resultCode.isOk()) return result;
+        // The operation is gone, so there is nothing left to abort.
+        auto rc = writeOutput(result.data);
+        if (!rc.isOk()) return operationFailed(rc);
+        result.data = {};
+    } else {
+        result.resultCode = ResponseCode::NO_ERROR;
+    }
+    result.inputConsumed = consumed;
+    return result;
+}
+
+KeyStoreServiceReturnCode FdOperationStreamer::writeOutput(const hidl_vec<uint8_t>& data) {
+    if (data.size() == 0) return ResponseCode::NO_ERROR;
+    if (!ashmem_valid(output_)) {
+        if (!android::base::WriteFully(output_, data.data(), data.size())) {
+            ALOGE("failed to write operation output: %s", strerror(errno));
+            return ResponseCode::SYSTEM_ERROR;
+        }
+        return ResponseCode::NO_ERROR;
+    }
+
+    if (!outputMap_) {
+        int size = ashmem_get_size_region(output_);
+        if (size <= 0) {
+            ALOGE("operation output region has no size");
+            return ResponseCode::SYSTEM_ERROR;
+        }
+        void* map = mmap(nullptr, size, PROT_READ | PROT_WRITE, MAP_SHARED, output_, 0);
+        if (map == MAP_FAILED) {
+            ALOGE("failed to map operation output region: %s", strerror(errno));
+            return ResponseCode::SYSTEM_ERROR;
+        }
+        outputMap_ = static_cast<uint8_t*>(map);
+        outputMapSize_ = size;
+    }
+    // An ashmem region only supports seeking once it is mapped.
+    off_t offset = lseek(output_, 0, SEEK_CUR);
+    if (offset == -1) {
+        ALOGE("failed to get operation output offset: %s", strerror(errno));
+        return ResponseCode::SYSTEM_ERROR;
+    }
+    if (size_t(offset) > outputMapSize_ || data.size() > outputMapSize_ - offset) {
+        ALOGE("operation output of %zu bytes does not fit at offset %jd of a %zu byte region",
+              data.size(), intmax_t(offset), outputMapSize_);
+        return ErrorCode::INSUFFICIENT_BUFFER_SPACE;
+    }
+    memcpy(outputMap_ + offset, data.data(), data.size());
+    if (lseek(output_, offset + data.size(), SEEK_SET) == -1) {
+        ALOGE("failed to advance operation output offset: %s", strerror(errno));
+        return ResponseCode::SYSTEM_ERROR;
+    }
+    return ResponseCode::NO_ERROR;
+}
+
+OperationResult FdOperationStreamer::fail(const KeyStoreServiceReturnCode& error) {
+    // The operation is still alive in the Keymaster and must not be left half fed.
+    std::promise<void> aborted;
+    auto future = aborted.get_future();
+    dev_->abort(token_, [&aborted](KeyStoreServiceReturnCode) { aborted.set_value(); });
+    future.wait();
+    return operationFailed(error);
+}
+
+void FdOperationStreamer::done(OperationResult result) {
+    {
+        std::lock_guard<std::mutex> lock(activeStreamsMutex);
+        activeStreams.erase(token_);
+    }
+    if (finish_ || !result.resultCode.isOk()) keyStore_->removeOperationDevice(token_);
+    cb_(std::move(result));
+}
+
+}  // namespace keystore
@@ -0,0 +1,102 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_FD_OPERATION_STREAMER_H_
+#define KEYSTORE_FD_OPERATION_STREAMER_H_
+
+#include <android-base/unique_fd.h>
+#include <binder/IBinder.h>
+#include <keystore/OperationResult.h>
+#include <keystore/keymaster_types.h>
+
+#include <functional>
+#include <memory>
+#include <vector>
+
+namespace keystore {
+
+class KeyStore;
+class KeymasterWorker;
+
+/**
+ * FdOperationStreamer feeds input that is too large for a binder transaction from a file
+ * descriptor to an operation, and writes the output to another file descriptor. The input is
+ * passed to the Keymaster in chunks of at most chunkSize bytes. Every chunk is a separate worker
+ * request, so a large payload does not starve other operations on the same Keymaster.
+ *
+ * Only regular files and ashmem regions are accepted. The file descriptors are read and written
+ * on a thread of the streamer's own, never on the worker thread, because a regular file may be
+ * backed by FUSE and block. Output is written at the current offset of the output descriptor,
+ * which advances like with write(). Ashmem regions have no write(), so their output is copied
+ * into a mapping of the region and must fit into it.
+ *
+ * An operation can only be streamed once at a time, and the number of concurrent streams is
+ * bounded, so that blocking file descriptors cannot pile up threads.
+ */
+class FdOperationStreamer : public std::enable_shared_from_this<FdOperationStreamer> {
+  public:
+    using Callback = std::function<void(::android::security::keymaster::OperationResult)>;
+
+    FdOperationStreamer(KeyStore* keyStore, std::shared_ptr<KeymasterWorker> dev,
+                        ::android::sp<::android::IBinder> token, AuthorizationSet params,
+                        android::base::unique_fd input, android::base::unique_fd output,
+                        size_t chunkSize, Callback cb);
+    ~FdOperationStreamer();
+
+    // Returns whether fd refers to a regular file or an ashmem region.
+    static bool isStreamableFd(int fd);
+
+    // Returns whether the operation of token is being streamed. Plain updates of such an
+    // operation must be refused, or their input would be interleaved with the stream.
+    static bool isStreaming(const ::android::sp<::android::IBinder>& token);
+
+    // Streams the whole input through update(). Reports the total input consumed to the
+    // callback. The output data of the reported result is always empty. Fails with
+    // BACKEND_BUSY, without calling the callback, if the operation is already being streamed
+    // or too many streams are active.
+    KeyStoreServiceReturnCode update();
+
+    // Like update(), but then finishes the operation and writes the output of finish() to the
+    // output file descriptor as well.
+    KeyStoreServiceReturnCode finish(hidl_vec<uint8_t> signature, hidl_vec<uint8_t> entropy);
+
+  private:
+    KeyStoreServiceReturnCode start();
+    ::android::security::keymaster::OperationResult stream();
+    KeyStoreServiceReturnCode writeOutput(const hidl_vec<uint8_t>& data);
+    ::android::security::keymaster::OperationResult fail(const KeyStoreServiceReturnCode& error);
+    void done(::android::security::keymaster::OperationResult result);
+
+    KeyStore* keyStore_;
+    std::shared_ptr<KeymasterWorker> dev_;
+    ::android::sp<::android::IBinder> token_;
+    // Operation parameters are only passed with the first chunk.
+    AuthorizationSet params_;
+    android::base::unique_fd input_;
+    android::base::unique_fd output_;
+    const size_t chunkSize_;
+    Callback cb_;
+    bool finish_ = false;
+    hidl_vec<uint8_t> signature_;
+    hidl_vec<uint8_t> entropy_;
+    // The mapping of an ashmem output region, created on the first write.
+    uint8_t* outputMap_ = nullptr;
+    size_t outputMapSize_ = 0;
+};
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_FD_OPERATION_STREAMER_H_
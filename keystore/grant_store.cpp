@@ -14,20 +14,35 @@
  * limitations under the License.
  */
 
+#define LOG_TAG "keystore"
+
 #include "grant_store.h"
 
 #include "blob.h"
+#include "keystore_utils.h"
 #include <algorithm>
 #include <sstream>
 
+#include <errno.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include <android-base/file.h>
+#include <android-base/parseint.h>
+#include <android-base/strings.h>
+#include <log/log.h>
+
 namespace keystore {
 
 static constexpr uint64_t kInvalidGrantNo = std::numeric_limits<uint64_t>::max();
 static const char* kKeystoreGrantInfix = "_KEYSTOREGRANT_";
 static constexpr size_t kKeystoreGrantInfixLength = 15;
 
-Grant::Grant(const KeyBlobEntry& entry, const uint64_t grant_no)
-    : entry_(entry), grant_no_(grant_no) {}
+static const char* kCertOnlyField = "cert_only";
+
+Grant::Grant(const KeyBlobEntry& entry, const uint64_t grant_no, const bool cert_only)
+    : entry_(entry), grant_no_(grant_no), cert_only_(cert_only) {}
 
 static std::pair<uint64_t, std::string> parseGrantAlias(const std::string& grantAlias) {
     auto pos = grantAlias.rfind(kKeystoreGrantInfix);
@@ -40,7 +55,8 @@ static std::pair<uint64_t, std::string> parseGrantAlias(const std::string& grant
     return {grant_no, wrapped_alias};
 }
 
-std::string GrantStore::put(const uid_t uid, const LockedKeyBlobEntry& lockedEntry) {
+std::string GrantStore::put(const uid_t uid, const LockedKeyBlobEntry& lockedEntry,
+                            bool certOnly) {
     std::unique_lock<std::shared_mutex> lock(mutex_);
     std::stringstream s;
     KeyBlobEntry blobEntry = *lockedEntry;
@@ -52,8 +68,17 @@ std::string GrantStore::put(const uid_t uid, const LockedKeyBlobEntry& lockedEnt
     auto iterator =
         std::find_if(uid_grant_list.begin(), uid_grant_list.end(),
                      [&](const Grant& entry) { return success = entry.entry_ == blobEntry; });
-    while (!success) {
-        std::tie(iterator, success) = uid_grant_list.emplace(blobEntry, std::rand());
+    if (!success) {
+        while (!success) {
+            std::tie(iterator, success) = uid_grant_list.emplace(blobEntry, std::rand(), certOnly);
+        }
+        persist();
+    } else if (iterator->cert_only_ != certOnly) {
+        // Set elements are immutable, so the grant is replaced under the same number.
+        uint64_t grant_no = iterator->grant_no_;
+        uid_grant_list.erase(iterator);
+        iterator = uid_grant_list.emplace(blobEntry, grant_no, certOnly).first;
+        persist();
     }
     s << iterator->grant_no_;
     return s.str();
@@ -80,6 +105,7 @@ bool GrantStore::removeByFileAlias(const uid_t granteeUid, const LockedKeyBlobEn
     for (auto i = uid_grant_list.begin(); i != uid_grant_list.end(); ++i) {
         if (i->entry_ == *lockedEntry) {
             uid_grant_list.erase(i);
+            persist();
             return true;
         }
     }
@@ -88,19 +114,119 @@ bool GrantStore::removeByFileAlias(const uid_t granteeUid, const LockedKeyBlobEn
 
 void GrantStore::removeAllGrantsToKey(const uid_t granterUid, const std::string& alias) {
     std::unique_lock<std::shared_mutex> lock(mutex_);
+    bool removed = false;
     for (auto& uid_grant_list : grants_) {
         for (auto i = uid_grant_list.second.begin(); i != uid_grant_list.second.end(); ++i) {
             if (i->entry_.alias() == alias && i->entry_.uid() == granterUid) {
                 uid_grant_list.second.erase(i);
+                removed = true;
                 break;
             }
         }
     }
+    if (removed) persist();
+}
+
+size_t GrantStore::removeDanglingGrants(bool dryRun) {
+    std::unique_lock<std::shared_mutex> lock(mutex_);
+    size_t count = 0;
+    for (auto& uid_grant_list : grants_) {
+        for (auto i = uid_grant_list.second.begin(); i != uid_grant_list.second.end();) {
+            if (i->entry_.hasKeyBlob()) {
+                ++i;
+                continue;
+            }
+            ++count;
+            i = dryRun ? std::next(i) : uid_grant_list.second.erase(i);
+        }
+    }
+    if (count && !dryRun) persist();
+    return count;
 }
 
 void GrantStore::removeAllGrantsToUid(const uid_t granteeUid) {
     std::unique_lock<std::shared_mutex> lock(mutex_);
-    grants_.erase(granteeUid);
+    if (grants_.erase(granteeUid)) persist();
+}
+
+void GrantStore::removeAllGrantsOfUser(const uid_t userId) {
+    std::unique_lock<std::shared_mutex> lock(mutex_);
+    bool removed = false;
+    for (auto i = grants_.begin(); i != grants_.end();) {
+        if (get_user_id(i->first) == userId) {
+            i = grants_.erase(i);
+            removed = true;
+            continue;
+        }
+        auto& grants = i->second;
+        for (auto grant = grants.begin(); grant != grants.end();) {
+            if (get_user_id(grant->entry_.uid()) == userId) {
+                grant = grants.erase(grant);
+                removed = true;
+            } else {
+                ++grant;
+            }
+        }
+        ++i;
+    }
+    if (removed) persist();
+}
+
+/*
+ * The grant file has one line per grant:
+ *     <grantee uid> <grant number> <granter uid> <user dir> <encoded alias> [cert_only]
+ * Aliases are stored encoded like in key file names, so they contain neither blanks nor line
+ * breaks.
+ */
+void GrantStore::load() {
+    if (fileName_.empty()) return;
+    std::string content;
+    if (!android::base::ReadFileToString(fileName_, &content)) {
+        if (errno != ENOENT) ALOGE("couldn't read grants: %s", strerror(errno));
+        return;
+    }
+
+    std::unique_lock<std::shared_mutex> lock(mutex_);
+    for (const auto& line : android::base::Split(content, "\n")) {
+        if (line.empty()) continue;
+        auto fields = android::base::Split(line, " ");
+        uid_t granteeUid, granterUid;
+        uint64_t grant_no;
+        bool certOnly = fields.size() == 6 && fields[5] == kCertOnlyField;
+        if ((fields.size() != 5 && !certOnly) ||
+            !android::base::ParseUint(fields[0], &granteeUid) ||
+            !android::base::ParseUint(fields[1], &grant_no) || grant_no == kInvalidGrantNo ||
+            !android::base::ParseUint(fields[2], &granterUid)) {
+            ALOGW("ignoring malformed grant \"%s\"", line.c_str());
+            continue;
+        }
+        grants_[granteeUid].emplace(KeyBlobEntry(decodeKeyName(fields[4]), fields[3], granterUid),
+                                    grant_no, certOnly);
+    }
+}
+
+void GrantStore::persist() const {
+    if (fileName_.empty()) return;
+    std::stringstream content;
+    for (const auto& [granteeUid, uid_grant_list] : grants_) {
+        for (const auto& grant : uid_grant_list) {
+            content << granteeUid << " " << grant.grant_no_ << " " << grant.entry_.uid() << " "
+                    << grant.entry_.user_dir() << " " << encodeKeyName(grant.entry_.alias());
+            if (grant.cert_only_) content << " " << kCertOnlyField;
+            content << "\n";
+        }
+    }
+
+    std::string tmpFileName = fileName_ + ".tmp";
+    if (!android::base::WriteStringToFile(content.str(), tmpFileName, S_IRUSR | S_IWUSR, getuid(),
+                                          getgid())) {
+        ALOGE("couldn't write grants: %s", strerror(errno));
+        return;
+    }
+    if (rename(tmpFileName.c_str(), fileName_.c_str()) == -1) {
+        ALOGE("couldn't replace grants: %s", strerror(errno));
+        unlink(tmpFileName.c_str());
+    }
 }
 
 }  // namespace keystore
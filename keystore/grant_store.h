@@ -43,10 +43,11 @@ using ReadLockedGrant =
  */
 class Grant {
 public:
-  Grant(const KeyBlobEntry& entry, const uint64_t grant_no);
+  Grant(const KeyBlobEntry& entry, const uint64_t grant_no, const bool cert_only = false);
   KeyBlobEntry entry_;
 
   uint64_t grant_no_;  ///< numeric grant identifier - randomly assigned
+  bool cert_only_;     ///< the grantee may only read the entry as a certificate
 
   // NOLINTNEXTLINE(google-explicit-constructor)
   operator const uint64_t&() const { return grant_no_; }
@@ -64,17 +65,31 @@ public:
 class GrantStore {
 public:
     GrantStore() : grants_() {}
-    std::string put(const uid_t uid, const LockedKeyBlobEntry& blobfile);
+    // Grants of a GrantStore with a file name survive restarts. Every change is written to the
+    // file, and load() reads it back.
+    explicit GrantStore(std::string fileName) : grants_(), fileName_(std::move(fileName)) {}
+    void load();
+    // Granting an entry again returns the same alias. The grant then takes the new certOnly.
+    std::string put(const uid_t uid, const LockedKeyBlobEntry& blobfile, bool certOnly = false);
     ReadLockedGrant get(const uid_t uid, const std::string& alias) const;
     bool removeByFileAlias(const uid_t granteeUid, const LockedKeyBlobEntry& lockedEntry);
     void removeAllGrantsToKey(const uid_t granterUid, const std::string& alias);
     void removeAllGrantsToUid(const uid_t granteeUid);
+    // Removes every grant whose grantee or granter belongs to userId.
+    void removeAllGrantsOfUser(const uid_t userId);
+    // Removes the grants whose key no longer exists and returns how many there were. With
+    // dryRun they are only counted.
+    size_t removeDanglingGrants(bool dryRun);
 
     // GrantStore is neither copyable nor movable.
     GrantStore(const GrantStore&) = delete;
     GrantStore& operator=(const GrantStore&) = delete;
 private:
+    // Must be called with mutex_ held exclusively.
+    void persist() const;
+
     std::unordered_map<uid_t, std::set<Grant, std::less<>>> grants_;
+    const std::string fileName_;
     mutable std::shared_mutex mutex_;
 };
 
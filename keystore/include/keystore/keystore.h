@@ -59,10 +59,28 @@ enum class ResponseCode : int32_t {
     ABORT_CALLED = 18,
     PRUNED = 19,
     BINDER_DIED = 20,
+
+    /**
+     * The operation could not be started before the deadline supplied by the caller.
+     */
+    BACKEND_BUSY = 21,
+
+    /**
+     * The Keymaster died while the operation was in progress and took the operation state with it.
+     * The operation cannot be continued. Callers may start a new operation once it is back.
+     */
+    BACKEND_RESTARTED = 22,
+
+    /**
+     * Keystore aborted the operation because it was idle for longer than the idle timeout. Like
+     * the three response codes above, this is also logged as the reason the operation ended.
+     */
+    OPERATION_EXPIRED = 23,
 };
 
 /*
- * All the flags for import and insert calls.
+ * All the flags for import and insert calls. They double as the flags byte of the blob header,
+ * whose bit 7 is reserved to mark an extended flags field once the byte runs out.
  */
 enum KeyStoreFlag : uint8_t {
     KEYSTORE_FLAG_NONE = 0,
@@ -80,6 +98,29 @@ enum KeyStoreFlag : uint8_t {
     // only be available to system uid.
     KEYSTORE_FLAG_CRITICAL_TO_DEVICE_ENCRYPTION = 1 << 3,
     KEYSTORE_FLAG_STRONGBOX = 1 << 4,
+    // KEYSTORE_FLAG_AUTO_DELETE makes keystore delete a key by itself once the key's
+    // USAGE_EXPIRE_DATETIME has passed. Meant for short-lived keys, e.g. per-session transport
+    // keys, that their owners would otherwise leave behind.
+    KEYSTORE_FLAG_AUTO_DELETE = 1 << 5,
+    // KEYSTORE_FLAG_PRUNING_EXEMPT marks keys whose operations are started as non-pruneable,
+    // e.g. payment or identity keys whose operations must not be aborted under load. Only the
+    // system uid can set it, through setKeyPruningExempt.
+    KEYSTORE_FLAG_PRUNING_EXEMPT = 1 << 6,
 };
 
+/*
+ * KEYSTORE_FLAG_TEST_KEY marks keys created by test suites, so that they can be removed in bulk
+ * with deleteTestKeys. Only the system uid and debuggable builds may set it. It is not stored in
+ * the blob header; keystore records test keys separately.
+ */
+constexpr int32_t KEYSTORE_FLAG_TEST_KEY = 1 << 8;
+
+/*
+ * The flags callers may pass to generateKey, importKey, insert and addRngEntropy. Super-encryption
+ * is decided by keystore from the key parameters and cannot be requested.
+ */
+constexpr int32_t KEYSTORE_CALLER_FLAGS =
+    KEYSTORE_FLAG_ENCRYPTED | KEYSTORE_FLAG_FALLBACK | KEYSTORE_FLAG_CRITICAL_TO_DEVICE_ENCRYPTION |
+    KEYSTORE_FLAG_STRONGBOX | KEYSTORE_FLAG_TEST_KEY | KEYSTORE_FLAG_AUTO_DELETE;
+
 #endif
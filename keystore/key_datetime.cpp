@@ -0,0 +1,65 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "key_datetime.h"
+
+#include <time.h>
+
+#include <limits>
+
+#include <android-base/stringprintf.h>
+
+namespace keystore {
+
+using std::chrono::milliseconds;
+
+bool isValidDateTimeParameter(uint64_t millis) {
+    return millis <= uint64_t(std::numeric_limits<int64_t>::max());
+}
+
+KeyDateTime dateTimeFromParameter(uint64_t millis) {
+    // Older keys may store "never" as UINT64_MAX. Casting would make that a date before the epoch.
+    if (!isValidDateTimeParameter(millis)) return KeyDateTime::max();
+    return KeyDateTime(milliseconds(int64_t(millis)));
+}
+
+uint64_t dateTimeToParameter(KeyDateTime dateTime) {
+    return uint64_t(dateTime.time_since_epoch().count());
+}
+
+std::optional<KeyDateTime> currentDateTime() {
+    auto now = std::chrono::time_point_cast<milliseconds>(std::chrono::system_clock::now());
+    if (now.time_since_epoch().count() < 0) return {};
+    return now;
+}
+
+std::string formatDateTime(KeyDateTime dateTime) {
+    int64_t millis = dateTime.time_since_epoch().count();
+    if (millis < 0 || millis / 1000 > std::numeric_limits<time_t>::max()) {
+        return std::to_string(millis);
+    }
+
+    time_t seconds = millis / 1000;
+    struct tm tm;
+    // Four digit years keep the format sortable.
+    if (!gmtime_r(&seconds, &tm) || tm.tm_year + 1900 > 9999) return std::to_string(millis);
+
+    char date[20];
+    strftime(date, sizeof(date), "%Y-%m-%dT%H:%M:%S", &tm);
+    return android::base::StringPrintf("%s.%03dZ", date, int(millis % 1000));
+}
+
+}  // namespace keystore
@@ -0,0 +1,61 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_KEY_DATETIME_H_
+#define KEYSTORE_KEY_DATETIME_H_
+
+#include <stdint.h>
+
+#include <chrono>
+#include <optional>
+#include <string>
+
+namespace keystore {
+
+/**
+ * The value of a DATE key parameter, e.g. ACTIVE_DATETIME or USAGE_EXPIRE_DATETIME. Keymaster
+ * defines these as milliseconds since the Unix epoch in UTC.
+ */
+using KeyDateTime = std::chrono::time_point<std::chrono::system_clock, std::chrono::milliseconds>;
+
+/**
+ * DATE parameters are transported as unsigned 64-bit values, but keystore and the Keymaster
+ * compare them as signed milliseconds. Values above INT64_MAX are rejected.
+ */
+bool isValidDateTimeParameter(uint64_t millis);
+
+/**
+ * Values above INT64_MAX, e.g. a UINT64_MAX "never" stored by older keys, are clamped to
+ * KeyDateTime::max(), so they stay in the far future.
+ */
+KeyDateTime dateTimeFromParameter(uint64_t millis);
+uint64_t dateTimeToParameter(KeyDateTime dateTime);
+
+/**
+ * Returns the current wall clock time with millisecond precision, or nothing if the clock is not
+ * set, i.e., reports a time before the epoch.
+ */
+std::optional<KeyDateTime> currentDateTime();
+
+/**
+ * Formats dateTime as ISO 8601 in UTC, e.g. "2020-09-01T12:00:00.000Z". Dates that cannot be
+ * represented, such as the INT64_MAX used for "never", are formatted as raw milliseconds.
+ */
+std::string formatDateTime(KeyDateTime dateTime);
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_KEY_DATETIME_H_
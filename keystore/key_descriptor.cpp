@@ -0,0 +1,76 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "key_descriptor.h"
+
+#include <limits.h>
+
+#include <limits>
+#include <sstream>
+
+#include "blob.h"
+
+namespace keystore {
+
+static constexpr char kAppDomain[] = "app:";
+static constexpr size_t kAppDomainLength = sizeof(kAppDomain) - 1;
+
+std::string toString(const KeyDescriptor& descriptor) {
+    std::stringstream s;
+    s << kAppDomain << descriptor.uid << ":" << descriptor.alias;
+    return s.str();
+}
+
+std::optional<KeyDescriptor> parseKeyDescriptor(const std::string& str) {
+    if (str.compare(0, kAppDomainLength, kAppDomain) != 0) return {};
+
+    auto sep = str.find(':', kAppDomainLength);
+    if (sep == std::string::npos || sep == kAppDomainLength) return {};
+
+    uint64_t uid = 0;
+    for (size_t i = kAppDomainLength; i < sep; ++i) {
+        if (str[i] < '0' || str[i] > '9') return {};
+        uid = uid * 10 + (str[i] - '0');
+        if (uid > std::numeric_limits<uid_t>::max()) return {};
+    }
+
+    std::string alias = str.substr(sep + 1);
+    if (alias.empty()) return {};
+
+    return KeyDescriptor{uid_t(uid), std::move(alias)};
+}
+
+std::optional<std::string> validateKeyDescriptor(const std::string& alias, int32_t uid) {
+    if (uid < -1) {
+        std::stringstream s;
+        s << "invalid uid " << uid;
+        return s.str();
+    }
+    if (alias.empty()) return std::string("empty alias");
+    return {};
+}
+
+std::optional<std::string> validateNewKeyAlias(const std::string& alias) {
+    size_t encodedLength = encodeKeyName(alias).size();
+    if (encodedLength + kMaxKeyFileNamePrefixLength > NAME_MAX) {
+        std::stringstream s;
+        s << "alias too long (" << alias.size() << " bytes, " << encodedLength << " encoded)";
+        return s.str();
+    }
+    return {};
+}
+
+}  // namespace keystore
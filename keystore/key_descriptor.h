@@ -0,0 +1,66 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_KEY_DESCRIPTOR_H_
+#define KEYSTORE_KEY_DESCRIPTOR_H_
+
+#include <stdint.h>
+#include <sys/types.h>
+
+#include <optional>
+#include <string>
+
+namespace keystore {
+
+/**
+ * KeyDescriptor names a key by the uid namespace it lives in and its alias. Its canonical string
+ * form is "app:<uid>:<alias>", which is what log messages, reports and tools should use to refer
+ * to a key. The alias is the remainder of the string and may itself contain ':'.
+ */
+struct KeyDescriptor {
+    uid_t uid;
+    std::string alias;
+
+    bool operator==(const KeyDescriptor& rhs) const {
+        return uid == rhs.uid && alias == rhs.alias;
+    }
+};
+
+std::string toString(const KeyDescriptor& descriptor);
+
+/**
+ * Parses the canonical string form of a KeyDescriptor. Returns nullopt if the domain is not
+ * "app", if the uid is not a decimal number in range or if the alias is empty.
+ */
+std::optional<KeyDescriptor> parseKeyDescriptor(const std::string& str);
+
+/**
+ * Checks an alias and uid as supplied by a client at the binder boundary, where uid -1 stands for
+ * the caller's own uid. Returns nullopt if they are acceptable and the reason otherwise. Aliases
+ * must be non-empty.
+ */
+std::optional<std::string> validateKeyDescriptor(const std::string& alias, int32_t uid);
+
+/**
+ * Checks the alias of an entry that is about to be created. It must be short enough for every
+ * file name derived from it. Existing entries with longer aliases must remain accessible, so
+ * this only applies to new ones.
+ */
+std::optional<std::string> validateNewKeyAlias(const std::string& alias);
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_KEY_DESCRIPTOR_H_
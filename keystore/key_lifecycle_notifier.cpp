@@ -0,0 +1,90 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#define LOG_TAG "keystore"
+
+#include "key_lifecycle_notifier.h"
+
+#include <algorithm>
+
+#include <binder/IInterface.h>
+#include <log/log.h>
+#include <utils/String16.h>
+
+namespace keystore {
+
+using android::IBinder;
+using android::IInterface;
+using android::sp;
+using android::wp;
+
+KeyLifecycleNotifier::KeyLifecycleNotifier(IBinder::DeathRecipient* deathRecipient)
+    : mDeathRecipient(deathRecipient) {}
+
+bool KeyLifecycleNotifier::isRegistered(const sp<IBinder>& binder) const {
+    return std::any_of(mRegistrations.begin(), mRegistrations.end(), [&](const auto& r) {
+        return IInterface::asBinder(r.listener) == binder;
+    });
+}
+
+void KeyLifecycleNotifier::registerListener(const sp<IKeystoreKeyLifecycleListener>& listener,
+                                            int32_t uid) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    auto binder = IInterface::asBinder(listener);
+    // Only link once per binder, all of its registrations go away together.
+    if (!isRegistered(binder)) binder->linkToDeath(mDeathRecipient);
+    mRegistrations.push_back({listener, uid});
+}
+
+bool KeyLifecycleNotifier::unregisterListener(const sp<IKeystoreKeyLifecycleListener>& listener) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    auto binder = IInterface::asBinder(listener);
+    auto end = std::remove_if(mRegistrations.begin(), mRegistrations.end(), [&](const auto& r) {
+        return IInterface::asBinder(r.listener) == binder;
+    });
+    if (end == mRegistrations.end()) return false;
+    mRegistrations.erase(end, mRegistrations.end());
+    binder->unlinkToDeath(mDeathRecipient);
+    return true;
+}
+
+void KeyLifecycleNotifier::notify(int32_t event, uid_t uid, const std::string& alias) {
+    std::vector<sp<IKeystoreKeyLifecycleListener>> listeners;
+    {
+        std::lock_guard<std::mutex> lock(mMutex);
+        for (const auto& r : mRegistrations) {
+            if (r.uid == -1 || uid_t(r.uid) == uid) listeners.push_back(r.listener);
+        }
+    }
+    // The listener interface is oneway, so this never blocks on the receiving side.
+    android::String16 alias16(alias.c_str());
+    for (const auto& listener : listeners) {
+        listener->onKeyEvent(event, int32_t(uid), alias16);
+    }
+}
+
+void KeyLifecycleNotifier::binderDied(const wp<IBinder>& who) {
+    // This is also called for other binders, so nothing may be registered for who.
+    std::lock_guard<std::mutex> lock(mMutex);
+    mRegistrations.erase(std::remove_if(mRegistrations.begin(), mRegistrations.end(),
+                                        [&](const auto& r) {
+                                            return IInterface::asBinder(r.listener) ==
+                                                   who.unsafe_get();
+                                        }),
+                         mRegistrations.end());
+}
+
+}  // namespace keystore
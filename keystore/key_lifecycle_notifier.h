@@ -0,0 +1,67 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_KEY_LIFECYCLE_NOTIFIER_H_
+#define KEYSTORE_KEY_LIFECYCLE_NOTIFIER_H_
+
+#include <android/security/keystore/IKeystoreKeyLifecycleListener.h>
+#include <binder/IBinder.h>
+#include <utils/StrongPointer.h>
+
+#include <mutex>
+#include <string>
+#include <vector>
+
+namespace keystore {
+
+using ::android::security::keystore::IKeystoreKeyLifecycleListener;
+
+/**
+ * KeyLifecycleNotifier keeps track of the listeners interested in key creation and deletion and
+ * forwards key events to them. A listener registers for a single uid namespace or, with uid -1,
+ * for all of them.
+ */
+class KeyLifecycleNotifier {
+  public:
+    explicit KeyLifecycleNotifier(android::IBinder::DeathRecipient* deathRecipient);
+
+    void registerListener(const android::sp<IKeystoreKeyLifecycleListener>& listener, int32_t uid);
+    // Removes all registrations of listener. Returns false if there were none.
+    bool unregisterListener(const android::sp<IKeystoreKeyLifecycleListener>& listener);
+
+    void notify(int32_t event, uid_t uid, const std::string& alias);
+
+    // Called by KeyStore when a client binder has died.
+    void binderDied(const android::wp<android::IBinder>& who);
+
+  private:
+    struct Registration {
+        android::sp<IKeystoreKeyLifecycleListener> listener;
+        int32_t uid;
+    };
+
+    // Must be called with mMutex held.
+    bool isRegistered(const android::sp<android::IBinder>& binder) const;
+
+    // This mutex protects all data below it.
+    std::mutex mMutex;
+    std::vector<Registration> mRegistrations;
+    android::IBinder::DeathRecipient* mDeathRecipient;
+};
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_KEY_LIFECYCLE_NOTIFIER_H_
@@ -18,11 +18,61 @@
 #include "key_operation_log_handler.h"
 #include "key_creation_log_handler.h"
 
+#include <android-base/logging.h>
 #include <keystore/keystore_hidl_support.h>
 #include <statslog.h>
 
+#include <inttypes.h>
+#include <stdio.h>
+
+#include <map>
+#include <mutex>
+#include <tuple>
+
 namespace keystore {
 
+namespace {
+
+struct OperationThroughput {
+    uint64_t operations = 0;
+    uint64_t inputBytes = 0;
+    std::chrono::steady_clock::duration keymasterTime{};
+};
+
+// Number of successful operations per algorithm and security level between two throughput reports.
+constexpr uint64_t kThroughputReportInterval = 100;
+
+std::mutex throughputLock;
+std::map<std::pair<int32_t, SecurityLevel>, OperationThroughput> throughputStats;
+
+struct AbnormalTerminations {
+    uint64_t count = 0;
+    std::chrono::steady_clock::duration totalAge{};
+    std::chrono::steady_clock::duration maxAge{};
+};
+
+std::mutex terminationLock;
+std::map<std::tuple<const char*, KeyPurpose, SecurityLevel>, AbnormalTerminations>
+    terminationStats;
+
+// Returns why an operation that did not succeed ended, or nullptr if its client aborted it.
+const char* abnormalTerminationReason(int32_t responseCode) {
+    switch (responseCode) {
+    case static_cast<int32_t>(ResponseCode::ABORT_CALLED):
+        return nullptr;
+    case static_cast<int32_t>(ResponseCode::PRUNED):
+        return "pruned";
+    case static_cast<int32_t>(ResponseCode::BINDER_DIED):
+        return "client_died";
+    case static_cast<int32_t>(ResponseCode::OPERATION_EXPIRED):
+        return "expired";
+    default:
+        return "failed";
+    }
+}
+
+}  // namespace
+
 template <typename Tag>
 int32_t getOptionalEnumTagValue(const AuthorizationSet& authorization_set, Tag tag) {
     auto tagValue = authorization_set.GetTagValue(tag);
@@ -111,6 +161,69 @@ int32_t generateBitMapForBlockModeValue(const AuthorizationSet& authorization_se
     return 0;
 }
 
+/*
+ * Accumulates the rate at which the Keymaster consumes update() and finish() input, per algorithm
+ * and security level, and reports it periodically. This makes it possible to compare TEE and
+ * StrongBox performance in the field and to spot driver regressions after vendor updates.
+ */
+static void logKeystoreOperationThroughput(const Operation& op, int32_t algorithm) {
+    if (!op.device || op.inputBytes == 0) return;
+    SecurityLevel securityLevel = op.device->halVersion().securityLevel;
+
+    std::lock_guard<std::mutex> lock(throughputLock);
+    auto& stats = throughputStats[{algorithm, securityLevel}];
+    ++stats.operations;
+    stats.inputBytes += op.inputBytes;
+    stats.keymasterTime += op.keymasterTime;
+    if (stats.operations % kThroughputReportInterval != 0) return;
+
+    auto micros =
+        std::chrono::duration_cast<std::chrono::microseconds>(stats.keymasterTime).count();
+    if (micros <= 0) return;
+    LOG(INFO) << "Keymaster throughput for " << toString(static_cast<Algorithm>(algorithm))
+              << " on " << toString(securityLevel) << ": "
+              << stats.inputBytes * 1000000 / micros << " bytes/s over " << stats.operations
+              << " operations";
+}
+
+/*
+ * Counts operations that ended abnormally together with their age, so that the reliability of
+ * operations in the field can be quantified. The key event atom only tells that an operation did
+ * not succeed, but neither on which security level nor how long it had been running.
+ */
+static void logKeystoreOperationAbnormalTermination(const Operation& op, int32_t responseCode) {
+    const char* reason = abnormalTerminationReason(responseCode);
+    if (!reason) return;
+    SecurityLevel securityLevel =
+        op.device ? op.device->halVersion().securityLevel : SecurityLevel::SOFTWARE;
+    auto age = std::chrono::steady_clock::now() - op.startTime;
+    LOG(INFO) << toString(op.purpose) << " operation of uid " << op.owner << " on "
+              << toString(securityLevel) << " " << reason << " with " << responseCode << " after "
+              << std::chrono::duration_cast<std::chrono::milliseconds>(age).count() << " ms";
+
+    std::lock_guard<std::mutex> lock(terminationLock);
+    auto& stats = terminationStats[{reason, op.purpose, securityLevel}];
+    ++stats.count;
+    stats.totalAge += age;
+    if (age > stats.maxAge) stats.maxAge = age;
+}
+
+void dumpAbnormalOperationTerminations(int fd) {
+    using std::chrono::duration_cast;
+    using std::chrono::milliseconds;
+
+    std::lock_guard<std::mutex> lock(terminationLock);
+    dprintf(fd, "Abnormally ended operations (reason, purpose, security level, count, mean age ms, "
+                "max age ms):\n");
+    for (const auto& [key, stats] : terminationStats) {
+        const auto& [reason, purpose, securityLevel] = key;
+        dprintf(fd, "  %s %s %s %" PRIu64 " %" PRId64 " %" PRId64 "\n", reason,
+                toString(purpose).c_str(), toString(securityLevel).c_str(), stats.count,
+                int64_t(duration_cast<milliseconds>(stats.totalAge).count() / stats.count),
+                int64_t(duration_cast<milliseconds>(stats.maxAge).count()));
+    }
+}
+
 void logKeystoreKeyOperationEvent(const Operation& op, bool wasOperationSuccessful,
                                   int32_t responseCode) {
     AuthorizationSet authorization_set(op.characteristics.softwareEnforced);
@@ -131,6 +244,13 @@ void logKeystoreKeyOperationEvent(const Operation& op, bool wasOperationSuccessf
         getOptionalEnumTagValue(authorization_set, TAG_BLOB_USAGE_REQUIREMENTS),
         android::util::KEYSTORE_KEY_EVENT_REPORTED__TYPE__KEY_OPERATION, wasOperationSuccessful,
         responseCode);
+
+    if (wasOperationSuccessful) {
+        logKeystoreOperationThroughput(op,
+                                       getOptionalEnumTagValue(authorization_set, TAG_ALGORITHM));
+    } else {
+        logKeystoreOperationAbnormalTermination(op, responseCode);
+    }
 }
 
 }  // namespace keystore
\ No newline at end of file
@@ -23,6 +23,12 @@ namespace keystore {
 
 void logKeystoreKeyOperationEvent(const Operation& op, bool wasSuccessful, int32_t errorCode);
 
+/**
+ * Writes the operations that ended abnormally, i.e. were pruned, expired, lost their client or
+ * failed, per reason, purpose and security level to fd.
+ */
+void dumpAbnormalOperationTerminations(int fd);
+
 }  // namespace keystore
 
 #endif  // KEY_OPERATION_LOG_HANDLER_H_
\ No newline at end of file
@@ -0,0 +1,116 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "key_parameter_diff.h"
+
+#include "key_datetime.h"
+
+#include <algorithm>
+#include <iomanip>
+#include <sstream>
+
+namespace keystore {
+
+namespace {
+
+std::string formatEnumValue(Tag tag, uint32_t value) {
+    switch (tag) {
+    case Tag::ALGORITHM:
+        return toString(static_cast<Algorithm>(value));
+    case Tag::PURPOSE:
+        return toString(static_cast<KeyPurpose>(value));
+    case Tag::DIGEST:
+        return toString(static_cast<Digest>(value));
+    case Tag::PADDING:
+        return toString(static_cast<PaddingMode>(value));
+    case Tag::BLOCK_MODE:
+        return toString(static_cast<BlockMode>(value));
+    case Tag::EC_CURVE:
+        return toString(static_cast<EcCurve>(value));
+    case Tag::ORIGIN:
+        return toString(static_cast<keymaster::KeyOrigin>(value));
+    default:
+        return std::to_string(value);
+    }
+}
+
+bool contains(const AuthorizationSet& set, const KeyParameter& param) {
+    return std::find(set.begin(), set.end(), param) != set.end();
+}
+
+}  // namespace
+
+std::string formatKeyParameter(const KeyParameter& param) {
+    std::stringstream s;
+    s << toString(param.tag) << "=";
+    switch (typeFromTag(param.tag)) {
+    case TagType::ENUM:
+    case TagType::ENUM_REP:
+        s << formatEnumValue(param.tag, param.f.integer);
+        break;
+    case TagType::UINT:
+    case TagType::UINT_REP:
+        s << param.f.integer;
+        break;
+    case TagType::ULONG:
+    case TagType::ULONG_REP:
+        s << param.f.longInteger;
+        break;
+    case TagType::DATE:
+        s << formatDateTime(dateTimeFromParameter(param.f.longInteger));
+        break;
+    case TagType::BOOL:
+        s << "true";
+        break;
+    case TagType::BIGNUM:
+    case TagType::BYTES:
+        s << std::hex << std::setfill('0');
+        for (uint8_t b : param.blob) s << std::setw(2) << unsigned(b);
+        break;
+    default:
+        s << "?";
+        break;
+    }
+    return s.str();
+}
+
+std::vector<std::string> diffKeyCharacteristics(const AuthorizationSet& storedHw,
+                                                const AuthorizationSet& storedSw,
+                                                const AuthorizationSet& reportedHw,
+                                                const AuthorizationSet& reportedSw) {
+    std::vector<std::string> differences;
+    auto report = [&](const KeyParameter& param, const char* what) {
+        differences.push_back(formatKeyParameter(param) + ": " + what);
+    };
+
+    for (const auto& param : reportedHw) {
+        if (contains(storedHw, param)) continue;
+        report(param, contains(storedSw, param) ? "enforced by hardware, stored as software"
+                                                : "reported by hardware, not stored");
+    }
+    for (const auto& param : storedHw) {
+        if (contains(reportedHw, param)) continue;
+        report(param, contains(reportedSw, param) ? "stored as hardware, enforced by software"
+                                                  : "stored as hardware, not reported");
+    }
+    for (const auto& param : reportedSw) {
+        if (contains(storedHw, param) || contains(storedSw, param)) continue;
+        report(param, "reported by software, not stored");
+    }
+    return differences;
+}
+
+}  // namespace keystore
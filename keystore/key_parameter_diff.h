@@ -0,0 +1,49 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_KEY_PARAMETER_DIFF_H_
+#define KEYSTORE_KEY_PARAMETER_DIFF_H_
+
+#include <string>
+#include <vector>
+
+#include <keystore/keymaster_types.h>
+
+namespace keystore {
+
+/**
+ * Formats a key parameter as "<tag>=<value>", e.g. "ALGORITHM=EC" or "KEY_SIZE=256". Byte strings
+ * are printed in hex.
+ */
+std::string formatKeyParameter(const KeyParameter& param);
+
+/**
+ * Compares the key characteristics keystore has cached for a key with the ones its Keymaster
+ * currently reports, and describes every difference in one line, e.g.
+ * "KEY_SIZE=256: reported by hardware, not stored".
+ *
+ * The cached software list legitimately contains parameters that keystore enforces itself, so
+ * parameters only found there are not reported. Parameters that moved between the hardware and
+ * the software list are. An empty result means the cache agrees with the Keymaster.
+ */
+std::vector<std::string> diffKeyCharacteristics(const AuthorizationSet& storedHw,
+                                                const AuthorizationSet& storedSw,
+                                                const AuthorizationSet& reportedHw,
+                                                const AuthorizationSet& reportedSw);
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_KEY_PARAMETER_DIFF_H_
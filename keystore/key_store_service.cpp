@@ -23,8 +23,12 @@
 
 #include <algorithm>
 #include <atomic>
+#include <future>
+#include <iomanip>
+#include <random>
 #include <sstream>
 
+#include <android-base/properties.h>
 #include <android-base/scopeguard.h>
 #include <binder/IInterface.h>
 #include <binder/IPCThreadState.h>
@@ -41,7 +45,12 @@
 #include <keymasterV4_0/keymaster_utils.h>
 
 #include "defaults.h"
+#include "fd_operation_streamer.h"
+#include "key_datetime.h"
+#include "key_descriptor.h"
 #include "key_attestation_log_handler.h"
+#include "key_operation_log_handler.h"
+#include "key_parameter_diff.h"
 #include "keystore_keymaster_enforcement.h"
 #include "keystore_utils.h"
 #include <keystore/keystore_attestation_id.h>
@@ -70,9 +79,14 @@ using ::android::security::keystore::ICredstoreTokenCallback;
 using ::android::security::keystore::IKeystoreOperationResultCallback;
 using ::android::security::keystore::IKeystoreResponseCallback;
 using ::android::security::keystore::KeystoreResponse;
+using V4_1_ErrorCode = ::android::hardware::keymaster::V4_1::ErrorCode;
 
 constexpr double kIdRotationPeriod = 30 * 24 * 60 * 60; /* Thirty days, in seconds */
 const char* kTimestampFilePath = "timestamp";
+// Keeps a page of listPaged well below the binder transaction limit even for long aliases.
+constexpr size_t kMaxListPageSize = 1000;
+// Each sampled key costs a HAL round trip on the binder thread, which blocks all other clients.
+constexpr int32_t kMaxIntegrityScanSample = 32;
 
 bool containsTag(const hidl_vec<KeyParameter>& params, Tag tag) {
     return params.end() !=
@@ -80,7 +94,37 @@ bool containsTag(const hidl_vec<KeyParameter>& params, Tag tag) {
                         [&](const KeyParameter& param) { return param.tag == tag; });
 }
 
-#define AIDL_RETURN(rc) (*_aidl_return = KeyStoreServiceReturnCode(rc).getErrorCode(), Status::ok())
+/*
+ * Super-encrypted keys outlive the user's lock screen key in memory: they stay on disk while the
+ * user is locked, and also after the lock screen was removed. Loading one then fails with the
+ * state of the user, LOCKED or UNINITIALIZED. Callers are told that the user has to authenticate
+ * instead, which is what they can act on.
+ */
+KeyStoreServiceReturnCode keyLoadError(KeyStoreServiceReturnCode rc, const Blob& keyBlob) {
+    if ((rc == ResponseCode::LOCKED || rc == ResponseCode::UNINITIALIZED) && keyBlob &&
+        keyBlob.isSuperEncrypted()) {
+        return ErrorCode::KEY_USER_NOT_AUTHENTICATED;
+    }
+    return rc;
+}
+
+bool isEarlyBootOnly(const hidl_vec<KeyParameter>& params) {
+    using V4_1_Tag = ::android::hardware::keymaster::V4_1::Tag;
+    return containsTag(params, static_cast<Tag>(V4_1_Tag::EARLY_BOOT_ONLY));
+}
+
+bool hasInvalidDateTime(const hidl_vec<KeyParameter>& params) {
+    return std::any_of(params.begin(), params.end(), [](const KeyParameter& param) {
+        return typeFromTag(param.tag) == TagType::DATE &&
+               !isValidDateTimeParameter(param.f.longInteger);
+    });
+}
+
+// Like AIDL_RETURN, for implementations shared by several APIs that count under the given name.
+#define AIDL_RETURN_AS(api, rc)                                                                    \
+    (*_aidl_return = countResult(api, KeyStoreServiceReturnCode(rc)), Status::ok())
+
+#define AIDL_RETURN(rc) AIDL_RETURN_AS(__func__, rc)
 
 std::pair<KeyStoreServiceReturnCode, bool> hadFactoryResetSinceIdRotation() {
     struct stat sbuf;
@@ -142,6 +186,52 @@ KeyStoreServiceReturnCode updateParamsForAttestation(uid_t callingUid, Authoriza
     return ResponseCode::NO_ERROR;
 }
 
+struct InputSizeLimits {
+    size_t entropy;
+    size_t keyData;
+    size_t maskingKey;
+    size_t operationData;
+};
+
+const InputSizeLimits& inputSizeLimits() {
+    using android::base::GetUintProperty;
+    static const InputSizeLimits limits = {
+        GetUintProperty<size_t>("ro.keystore.max_entropy_size", MAX_ENTROPY_SIZE),
+        GetUintProperty<size_t>("ro.keystore.max_key_data_size", MAX_KEY_DATA_SIZE),
+        GetUintProperty<size_t>("ro.keystore.max_masking_key_size", MAX_MASKING_KEY_SIZE),
+        GetUintProperty<size_t>("ro.keystore.max_operation_data_size", MAX_OPERATION_DATA_SIZE),
+    };
+    return limits;
+}
+
+bool checkInputSize(const ::std::vector<uint8_t>& input, size_t limit, const char* what,
+                    const char* method) {
+    if (input.size() > limit) {
+        ALOGW("%s: %s too large (%zu > %zu bytes)", method, what, input.size(), limit);
+        return false;
+    }
+    return true;
+}
+
+KeyStoreServiceReturnCode dupStreamableFds(const ::android::os::ParcelFileDescriptor& input,
+                                           const ::android::os::ParcelFileDescriptor& output,
+                                           android::base::unique_fd* inputFd,
+                                           android::base::unique_fd* outputFd,
+                                           const char* method) {
+    if (!FdOperationStreamer::isStreamableFd(input.get()) ||
+        !FdOperationStreamer::isStreamableFd(output.get())) {
+        ALOGW("%s: input and output must be regular files or ashmem regions", method);
+        return ErrorCode::INVALID_ARGUMENT;
+    }
+    inputFd->reset(fcntl(input.get(), F_DUPFD_CLOEXEC, 0));
+    outputFd->reset(fcntl(output.get(), F_DUPFD_CLOEXEC, 0));
+    if (*inputFd == -1 || *outputFd == -1) {
+        ALOGE("%s: failed to duplicate file descriptors: %s", method, strerror(errno));
+        return ResponseCode::SYSTEM_ERROR;
+    }
+    return ResponseCode::NO_ERROR;
+}
+
 }  // anonymous namespace
 
 Status KeyStoreService::getState(int32_t userId, int32_t* aidl_return) {
@@ -154,6 +244,10 @@ Status KeyStoreService::getState(int32_t userId, int32_t* aidl_return) {
 }
 
 Status KeyStoreService::get(const String16& name, int32_t uid, ::std::vector<uint8_t>* item) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return Status::fromServiceSpecificError(
+            KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode());
+    }
     uid_t targetUid = getEffectiveUid(uid);
     if (!checkBinderPermission(P_GET, targetUid)) {
         // see keystore/keystore.h
@@ -183,6 +277,18 @@ Status KeyStoreService::get(const String16& name, int32_t uid, ::std::vector<uin
 
 Status KeyStoreService::insert(const String16& name, const ::std::vector<uint8_t>& item,
                                int targetUid, int32_t flags, int32_t* aidl_return) {
+    if (!checkKeyDescriptor(name, targetUid, __func__) || !checkNewKeyAlias(name, __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
+    if (!checkInputSize(item, inputSizeLimits().keyData, "item", __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_INPUT_LENGTH).getErrorCode();
+        return Status::ok();
+    }
+    if (!areCallerFlagsValid(flags)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
     targetUid = getEffectiveUid(targetUid);
     KeyStoreServiceReturnCode result =
         checkBinderPermissionAndKeystoreState(P_INSERT, targetUid, flags & KEYSTORE_FLAG_ENCRYPTED);
@@ -190,6 +296,10 @@ Status KeyStoreService::insert(const String16& name, const ::std::vector<uint8_t
         *aidl_return = result.getErrorCode();
         return Status::ok();
     }
+    if (!checkUidWritable(targetUid, __func__) || !checkTestKeyFlag(flags, __func__)) {
+        *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
+        return Status::ok();
+    }
 
     String8 name8(name);
     auto lockedEntry = mKeyStore->getLockedBlobEntryIfNotExists(name8.string(), targetUid);
@@ -203,13 +313,21 @@ Status KeyStoreService::insert(const String16& name, const ::std::vector<uint8_t
     Blob keyBlob(&item[0], item.size(), nullptr, 0, ::TYPE_GENERIC);
     keyBlob.setEncrypted(flags & KEYSTORE_FLAG_ENCRYPTED);
 
-    *aidl_return = static_cast<int32_t>(mKeyStore->put(lockedEntry, keyBlob, {}));
+    ResponseCode rc = mKeyStore->put(lockedEntry, keyBlob, {});
+    if (rc == ResponseCode::NO_ERROR) {
+        mKeyStore->setTestKey(lockedEntry, flags & KEYSTORE_FLAG_TEST_KEY);
+    }
+    *aidl_return = static_cast<int32_t>(rc);
     return Status::ok();
 }
 
 Status KeyStoreService::del(const String16& name, int targetUid, int32_t* aidl_return) {
+    if (!checkKeyDescriptor(name, targetUid, __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
     targetUid = getEffectiveUid(targetUid);
-    if (!checkBinderPermission(P_DELETE, targetUid)) {
+    if (!checkBinderPermission(P_DELETE, targetUid) || !checkUidWritable(targetUid, __func__)) {
         *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
         return Status::ok();
     }
@@ -221,13 +339,37 @@ Status KeyStoreService::del(const String16& name, int targetUid, int32_t* aidl_r
         return Status::ok();
     }
 
-    ResponseCode result = mKeyStore->del(lockedEntry);
+    *aidl_return = static_cast<int32_t>(mKeyStore->softDel(lockedEntry));
+    return Status::ok();
+}
+
+Status KeyStoreService::undelete(const String16& name, int targetUid, int32_t* aidl_return) {
+    if (!checkKeyDescriptor(name, targetUid, __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
+    targetUid = getEffectiveUid(targetUid);
+    if (!checkBinderPermission(P_INSERT, targetUid) || !checkUidWritable(targetUid, __func__)) {
+        *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
+        return Status::ok();
+    }
+    String8 name8(name);
+    ALOGI("undelete %s %d", name8.string(), targetUid);
+    auto lockedEntry = mKeyStore->getLockedBlobEntryIfTombstoned(name8.string(), targetUid);
+    if (!lockedEntry) {
+        *aidl_return = static_cast<int32_t>(ResponseCode::KEY_NOT_FOUND);
+        return Status::ok();
+    }
 
-    *aidl_return = static_cast<int32_t>(result);
+    *aidl_return = static_cast<int32_t>(mKeyStore->undelete(lockedEntry));
     return Status::ok();
 }
 
 Status KeyStoreService::exist(const String16& name, int targetUid, int32_t* aidl_return) {
+    if (!checkKeyDescriptor(name, targetUid, __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
     targetUid = getEffectiveUid(targetUid);
     if (!checkBinderPermission(P_EXIST, targetUid)) {
         *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
@@ -273,6 +415,115 @@ Status KeyStoreService::list(const String16& prefix, int32_t targetUid,
     return Status::ok();
 }
 
+Status KeyStoreService::listFiltered(const String16& prefix, int32_t uid, int32_t algorithm,
+                                     bool authBoundOnly, int32_t securityLevel,
+                                     ::std::vector<::android::String16>* aliases,
+                                     int32_t* _aidl_return) {
+    aliases->clear();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!checkBinderPermission(P_LIST, targetUid)) {
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    const String8 prefix8(prefix);
+    const std::string stdPrefix(prefix8.string());
+
+    auto userState = mKeyStore->getUserStateDB().getUserStateByUid(targetUid);
+    const std::string userDirName = userState->getUserDirName();
+    auto encryptionKey = userState->getEncryptionKey();
+    auto state = userState->getState();
+    // unlock the user state
+    userState = {};
+
+    ResponseCode rc;
+    std::list<LockedKeyBlobEntry> internal_matches;
+    std::tie(rc, internal_matches) =
+        LockedKeyBlobEntry::list(userDirName, [&](uid_t entryUid, const std::string& alias) {
+            return entryUid == targetUid &&
+                   std::mismatch(stdPrefix.begin(), stdPrefix.end(), alias.begin(), alias.end())
+                           .first == stdPrefix.end();
+        });
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(rc);
+
+    for (LockedKeyBlobEntry& entry : internal_matches) {
+        // The key blob of a locked user is still read, only its payload stays encrypted.
+        auto [rc, keyBlob, charBlob] = entry.readBlobs(encryptionKey, state);
+        if (rc != ResponseCode::NO_ERROR && rc != ResponseCode::LOCKED) continue;
+        if (keyBlob.getType() != TYPE_KEYMASTER_10) continue;
+
+        if (securityLevel != -1 &&
+            keyBlob.getSecurityLevel() != static_cast<SecurityLevel>(securityLevel)) {
+            continue;
+        }
+
+        AuthorizationSet characteristics;
+        if (charBlob) {
+            auto [success, hwEnforced, swEnforced] = charBlob.getKeyCharacteristics();
+            if (success) {
+                characteristics = std::move(hwEnforced);
+                characteristics.append(swEnforced.begin(), swEnforced.end());
+            }
+        }
+
+        if (algorithm != -1 &&
+            !characteristics.Contains(TAG_ALGORITHM, static_cast<Algorithm>(algorithm))) {
+            continue;
+        }
+        // Same criteria as listUidsOfAuthBoundKeys.
+        if (authBoundOnly && !keyBlob.isEncrypted() &&
+            !characteristics.Contains(TAG_USER_SECURE_ID)) {
+            continue;
+        }
+
+        aliases->push_back(String16(entry->alias().substr(prefix8.size()).c_str()));
+    }
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::listPaged(const String16& prefix, int32_t uid,
+                                  const String16& startPastAlias, int32_t maxCount,
+                                  ::std::vector<::android::String16>* aliases,
+                                  int32_t* _aidl_return) {
+    aliases->clear();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!checkBinderPermission(P_LIST, targetUid)) {
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    const String8 prefix8(prefix);
+    const std::string stdPrefix(prefix8.string());
+    const std::string cursor(String8(startPastAlias).string());
+    size_t pageSize = kMaxListPageSize;
+    if (maxCount > 0) pageSize = std::min(pageSize, size_t(maxCount));
+
+    auto userDirName = mKeyStore->getUserStateDB().getUserStateByUid(targetUid)->getUserDirName();
+
+    ResponseCode rc;
+    std::list<LockedKeyBlobEntry> internal_matches;
+    std::tie(rc, internal_matches) =
+        LockedKeyBlobEntry::list(userDirName, [&](uid_t entryUid, const std::string& alias) {
+            return entryUid == targetUid &&
+                   std::mismatch(stdPrefix.begin(), stdPrefix.end(), alias.begin(), alias.end())
+                           .first == stdPrefix.end() &&
+                   alias.compare(stdPrefix.size(), std::string::npos, cursor) > 0;
+        });
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(rc);
+
+    std::vector<std::string> matches;
+    for (LockedKeyBlobEntry& entry : internal_matches) {
+        matches.push_back(entry->alias().substr(stdPrefix.size()));
+    }
+    // Unlock the entries before sorting a potentially large namespace.
+    internal_matches.clear();
+
+    if (matches.size() > pageSize) {
+        std::partial_sort(matches.begin(), matches.begin() + pageSize, matches.end());
+        matches.resize(pageSize);
+    } else {
+        std::sort(matches.begin(), matches.end());
+    }
+    for (const auto& alias : matches) aliases->push_back(String16(alias.c_str()));
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
 /*
  * This method will return the uids of all auth bound keys for the calling user.
  * This is intended to be used for alerting the user about which apps will be affected
@@ -343,6 +594,398 @@ Status KeyStoreService::listUidsOfAuthBoundKeys(std::vector<std::string>* uidsOu
     return Status::ok();
 }
 
+Status KeyStoreService::listOperations(std::vector<std::string>* operationsOut,
+                                       int32_t* aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission listOperations denied for aid %d", appId);
+        *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
+        return Status::ok();
+    }
+
+    for (auto secLevel : {SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT,
+                          SecurityLevel::STRONGBOX}) {
+        auto dev = mKeyStore->getDevice(secLevel);
+        if (!dev) continue;
+
+        std::promise<std::vector<OperationDescriptor>> descriptorsPromise;
+        auto descriptorsFuture = descriptorsPromise.get_future();
+        dev->listOperations([&descriptorsPromise](std::vector<OperationDescriptor> descriptors) {
+            descriptorsPromise.set_value(std::move(descriptors));
+        });
+
+        // Aliases are never reported. The key id is a hash of the key blob, which is enough to
+        // correlate operations on the same key.
+        for (const auto& descriptor : descriptorsFuture.get()) {
+            std::stringstream s;
+            s << "uid=" << descriptor.owner << " keyid=" << std::hex << std::setw(16)
+              << std::setfill('0') << descriptor.keyid << std::dec
+              << " purpose=" << toString(descriptor.purpose) << " age=" << descriptor.age.count()
+              << "s securityLevel=" << toString(secLevel);
+            operationsOut->push_back(s.str());
+        }
+    }
+    *aidl_return = static_cast<int32_t>(ResponseCode::NO_ERROR);
+    return Status::ok();
+}
+
+Status KeyStoreService::scanKeyIntegrity(int32_t userId, int32_t sampleSize,
+                                         std::vector<std::string>* rejectedOut,
+                                         int32_t* aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission scanKeyIntegrity denied for aid %d", appId);
+        *aidl_return = static_cast<int32_t>(ResponseCode::PERMISSION_DENIED);
+        return Status::ok();
+    }
+    if (sampleSize <= 0) {
+        *aidl_return = static_cast<int32_t>(ResponseCode::NO_ERROR);
+        return Status::ok();
+    }
+    sampleSize = std::min(sampleSize, kMaxIntegrityScanSample);
+
+    auto userState = mKeyStore->getUserStateDB().getUserState(userId);
+    const std::string userDirName = userState->getUserDirName();
+    auto encryptionKey = userState->getEncryptionKey();
+    auto state = userState->getState();
+    // unlock the user state
+    userState = {};
+
+    // The entries are only collected here and locked one at a time below, so that the scan never
+    // blocks more than the key whose blob is being checked.
+    std::vector<KeyBlobEntry> sample;
+    ResponseCode rc;
+    std::tie(rc, std::ignore) =
+        LockedKeyBlobEntry::list(userDirName, [&](uid_t uid, const std::string& alias) {
+            sample.emplace_back(alias, userDirName, uid);
+            return false;
+        });
+    if (rc != ResponseCode::NO_ERROR) {
+        ALOGE("Error listing blob entries for user %d", userId);
+        return Status::fromServiceSpecificError(static_cast<int32_t>(rc));
+    }
+
+    std::shuffle(sample.begin(), sample.end(), std::mt19937(std::random_device()()));
+
+    size_t checked = 0;
+    for (auto& sampled : sample) {
+        if (checked >= static_cast<size_t>(sampleSize)) break;
+        // The lock keeps the blob from being upgraded, and the old one deleted, while the HAL
+        // looks at it.
+        auto entry = LockedKeyBlobEntry::get(std::move(sampled));
+
+        // Blobs that cannot be decrypted right now (e.g. super-encrypted while the user is
+        // locked) are not a sign of a HAL problem and are skipped.
+        auto [rc, keyBlob, charBlob] = entry.readBlobs(encryptionKey, state);
+        if (rc != ResponseCode::NO_ERROR || keyBlob.getType() != TYPE_KEYMASTER_10) continue;
+
+        auto dev = mKeyStore->getDevice(keyBlob);
+        if (!dev) continue;
+
+        std::promise<KeyStoreServiceReturnCode> resultPromise;
+        auto resultFuture = resultPromise.get_future();
+        dev->checkKeyBlob(std::move(keyBlob), [&resultPromise](KeyStoreServiceReturnCode rc) {
+            resultPromise.set_value(rc);
+        });
+        auto result = resultFuture.get();
+        ++checked;
+
+        // Keystore does not keep the APPLICATION_ID and APPLICATION_DATA a key is bound to, so
+        // such keys are rejected here like damaged ones. A rejection is therefore only reported to
+        // the caller and not logged as an integrity violation.
+        if (!result.isOk()) {
+            std::stringstream s;
+            s << toString(KeyDescriptor{entry->uid(), entry->alias()})
+              << " error=" << result.getErrorCode();
+            if (result == ErrorCode::INVALID_KEY_BLOB) s << " (or bound to an application id)";
+            rejectedOut->push_back(s.str());
+        }
+    }
+
+    ALOGI("scanKeyIntegrity: user %d, %zu keys checked, %zu rejected", userId, checked,
+          rejectedOut->size());
+    *aidl_return = static_cast<int32_t>(ResponseCode::NO_ERROR);
+    return Status::ok();
+}
+
+Status KeyStoreService::registerKeyLifecycleListener(
+    const sp<IKeystoreKeyLifecycleListener>& listener, int32_t uid, int32_t* _aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission registerKeyLifecycleListener denied for aid %d", appId);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (!listener || uid < -1) {
+        return AIDL_RETURN(ResponseCode::SYSTEM_ERROR);
+    }
+
+    mKeyStore->getKeyLifecycleNotifier().registerListener(listener, uid);
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::unregisterKeyLifecycleListener(
+    const sp<IKeystoreKeyLifecycleListener>& listener, int32_t* _aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission unregisterKeyLifecycleListener denied for aid %d", appId);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (!listener || !mKeyStore->getKeyLifecycleNotifier().unregisterListener(listener)) {
+        return AIDL_RETURN(ResponseCode::KEY_NOT_FOUND);
+    }
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::setUidReadOnly(int32_t uid, bool readOnly, int32_t* _aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission setUidReadOnly denied for aid %d", appId);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (uid < 0) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    ALOGI("setUidReadOnly %d %d", uid, readOnly);
+    return AIDL_RETURN(mKeyStore->setUidReadOnly(uid, readOnly));
+}
+
+Status KeyStoreService::checkConsistency(bool repair, std::vector<std::string>* reportOut,
+                                         int32_t* _aidl_return) {
+    reportOut->clear();
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission checkConsistency denied for aid %d", appId);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+
+    auto report = mKeyStore->checkConsistency(repair);
+    reportOut->push_back("orphaned_characteristics " +
+                         std::to_string(report.orphanedCharacteristics));
+    reportOut->push_back("stale_temp_files " + std::to_string(report.staleTempFiles));
+    reportOut->push_back("dangling_grants " + std::to_string(report.danglingGrants));
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::deleteTestKeys(int32_t userId, int32_t* _aidl_return) {
+    const int32_t callingUid = IPCThreadState::self()->getCallingUid();
+    const int32_t appId = get_app_id(callingUid);
+    if (appId != AID_SYSTEM) {
+        ALOGE("Permission deleteTestKeys denied for aid %d", appId);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (userId < 0) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    size_t deleted = mKeyStore->deleteTestKeys(userId);
+    ALOGI("deleteTestKeys deleted %zu keys of user %d", deleted, userId);
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::getKeyAvailability(const ::android::sp<IKeystoreResponseCallback>& cb,
+                                           const String16& name, int32_t uid, int32_t purpose,
+                                           int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!is_granted_to(callingUid, targetUid)) {
+        ALOGW("uid %d not permitted to act for uid %d in getKeyAvailability", callingUid,
+              targetUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+
+    String8 name8(name);
+    Blob keyBlob;
+    Blob charBlob;
+    LockedKeyBlobEntry lockedEntry;
+    ResponseCode rc;
+
+    std::tie(rc, keyBlob, charBlob, lockedEntry) =
+        mKeyStore->getKeyForName(name8, targetUid, TYPE_KEYMASTER_10);
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(keyLoadError(rc, keyBlob));
+
+    auto dev = mKeyStore->getDevice(keyBlob);
+    dev->checkKeyAvailability(std::move(lockedEntry), std::move(keyBlob), std::move(charBlob),
+                              static_cast<KeyPurpose>(purpose),
+                              [cb](KeyStoreServiceReturnCode rc) { cb->onFinished(rc); });
+
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::getKeyCharacteristicsBatch(
+    const ::std::vector<String16>& names, int32_t uid, ::std::vector<int32_t>* statuses,
+    ::std::vector<::android::security::keymaster::KeyCharacteristics>* characteristics,
+    int32_t* _aidl_return) {
+    statuses->clear();
+    characteristics->clear();
+    if (names.size() > MAX_KEY_BATCH_SIZE) {
+        ALOGW("%s: too many aliases (%zu > %zu)", __func__, names.size(), MAX_KEY_BATCH_SIZE);
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!is_granted_to(callingUid, targetUid)) {
+        ALOGW("uid %d not permitted to act for uid %d in getKeyCharacteristicsBatch", callingUid,
+              targetUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+
+    for (const auto& name : names) {
+        ::android::security::keymaster::KeyCharacteristics entry;
+        auto rc = getCachedKeyCharacteristics(name, uid, targetUid, &entry);
+        statuses->push_back(rc.getErrorCode());
+        characteristics->push_back(std::move(entry));
+    }
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::getKeyEntry(const String16& name, int32_t uid,
+                                    ::android::security::keymaster::KeyCharacteristics* out,
+                                    int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!is_granted_to(callingUid, targetUid)) {
+        ALOGW("uid %d not permitted to act for uid %d in getKeyEntry", callingUid, targetUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+
+    auto userState = mKeyStore->getUserStateDB().getUserStateByUid(targetUid);
+    auto encryptionKey = userState->getEncryptionKey();
+    auto state = userState->getState();
+    // unlock the user state
+    userState = {};
+
+    auto lockedEntry = mKeyStore->getLockedBlobEntryIfExists(String8(name).string(), targetUid);
+    if (!lockedEntry) return AIDL_RETURN(ResponseCode::KEY_NOT_FOUND);
+
+    auto [rc, charBlob] = lockedEntry.readCharacteristicsBlob(encryptionKey, state);
+    // Legacy or missing caches are only rebuilt by the Keymaster worker.
+    if (rc == ResponseCode::KEY_NOT_FOUND ||
+        (rc == ResponseCode::NO_ERROR && charBlob.getType() != TYPE_KEY_CHARACTERISTICS_CACHE)) {
+        return AIDL_RETURN(ErrorCode::KEY_REQUIRES_UPGRADE);
+    }
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(rc);
+
+    auto [success, hwEnforced, swEnforced] = charBlob.getKeyCharacteristics();
+    if (!success) {
+        ALOGE("Failed to read cached key characteristics");
+        return AIDL_RETURN(ResponseCode::SYSTEM_ERROR);
+    }
+
+    KeyCharacteristics keyCharacteristics;
+    keyCharacteristics.hardwareEnforced = hwEnforced.hidl_data();
+    keyCharacteristics.softwareEnforced = swEnforced.hidl_data();
+    *out = ::android::security::keymaster::KeyCharacteristics(std::move(keyCharacteristics));
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::diffKeyCharacteristics(const String16& name, int32_t uid,
+                                               ::std::vector<::std::string>* differences,
+                                               int32_t* _aidl_return) {
+    differences->clear();
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    uid_t targetUid = getEffectiveUid(uid);
+    if (!is_granted_to(callingUid, targetUid)) {
+        ALOGW("uid %d not permitted to act for uid %d in diffKeyCharacteristics", callingUid,
+              targetUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+
+    Blob keyBlob;
+    Blob charBlob;
+    LockedKeyBlobEntry lockedEntry;
+    ResponseCode rc;
+    std::tie(rc, keyBlob, charBlob, lockedEntry) =
+        mKeyStore->getKeyForName(String8(name), targetUid, TYPE_KEYMASTER_10);
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(rc);
+
+    // Legacy or missing caches are only rebuilt by the Keymaster worker.
+    if (!charBlob || charBlob.getType() != TYPE_KEY_CHARACTERISTICS_CACHE) {
+        return AIDL_RETURN(ErrorCode::KEY_REQUIRES_UPGRADE);
+    }
+    bool success;
+    AuthorizationSet storedHw, storedSw;
+    std::tie(success, storedHw, storedSw) = charBlob.getKeyCharacteristics();
+    if (!success) {
+        ALOGE("Failed to read cached key characteristics");
+        return AIDL_RETURN(ResponseCode::SYSTEM_ERROR);
+    }
+
+    auto dev = mKeyStore->getDevice(keyBlob);
+    if (!dev) return AIDL_RETURN(ErrorCode::HARDWARE_TYPE_UNAVAILABLE);
+
+    std::promise<std::tuple<KeyStoreServiceReturnCode, KeyCharacteristics>> resultPromise;
+    auto resultFuture = resultPromise.get_future();
+    dev->getHalKeyCharacteristics(
+        std::move(keyBlob),
+        [&resultPromise](KeyStoreServiceReturnCode rc, KeyCharacteristics characteristics) {
+            resultPromise.set_value({rc, std::move(characteristics)});
+        });
+    auto [halRc, reported] = resultFuture.get();
+    if (!halRc.isOk()) return AIDL_RETURN(halRc);
+
+    *differences = ::keystore::diffKeyCharacteristics(storedHw, storedSw,
+                                                      AuthorizationSet(reported.hardwareEnforced),
+                                                      AuthorizationSet(reported.softwareEnforced));
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::getRawKeyCharacteristics(
+    const String16& name, int32_t uid, ::android::security::keymaster::KeyCharacteristics* out,
+    int32_t* _aidl_return) {
+    const uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    if (!android::base::GetBoolProperty("ro.debuggable", false) ||
+        (get_app_id(callingUid) != AID_SYSTEM && callingUid != AID_ROOT)) {
+        ALOGE("Permission getRawKeyCharacteristics denied for uid %d", callingUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    Blob keyBlob;
+    Blob charBlob;
+    LockedKeyBlobEntry lockedEntry;
+    ResponseCode rc;
+    std::tie(rc, keyBlob, charBlob, lockedEntry) =
+        mKeyStore->getKeyForName(String8(name), getEffectiveUid(uid), TYPE_KEYMASTER_10);
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(keyLoadError(rc, keyBlob));
+
+    auto dev = mKeyStore->getDevice(keyBlob);
+    if (!dev) return AIDL_RETURN(ErrorCode::HARDWARE_TYPE_UNAVAILABLE);
+
+    std::promise<std::tuple<KeyStoreServiceReturnCode, KeyCharacteristics>> resultPromise;
+    auto resultFuture = resultPromise.get_future();
+    dev->getHalKeyCharacteristics(
+        std::move(keyBlob),
+        [&resultPromise](KeyStoreServiceReturnCode rc, KeyCharacteristics characteristics) {
+            resultPromise.set_value({rc, std::move(characteristics)});
+        });
+    auto [halRc, reported] = resultFuture.get();
+    if (!halRc.isOk()) return AIDL_RETURN(halRc);
+
+    *out = ::android::security::keymaster::KeyCharacteristics(std::move(reported));
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
 Status KeyStoreService::onUserPasswordChanged(int32_t userId, const String16& password,
                                               int32_t* aidl_return) {
     if (!checkBinderPermission(P_PASSWORD)) {
@@ -413,6 +1056,7 @@ Status KeyStoreService::onUserRemoved(int32_t userId, int32_t* aidl_return) {
     }
 
     mKeyStore->resetUser(userId, false);
+    mKeyStore->forgetUser(userId);
     *aidl_return = static_cast<int32_t>(ResponseCode::NO_ERROR);
     return Status::ok();
 }
@@ -478,6 +1122,10 @@ Status KeyStoreService::isEmpty(int32_t userId, int32_t* aidl_return) {
 
 Status KeyStoreService::grant(const String16& name, int32_t granteeUid,
                               ::android::String16* aidl_return) {
+    if (!checkKeyDescriptor(name, granteeUid, __func__)) {
+        *aidl_return = String16();
+        return Status::ok();
+    }
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
     auto result =
         checkBinderPermissionAndKeystoreState(P_GRANT, /*targetUid=*/-1, /*checkUnlocked=*/false);
@@ -497,7 +1145,38 @@ Status KeyStoreService::grant(const String16& name, int32_t granteeUid,
     return Status::ok();
 }
 
+Status KeyStoreService::grantCertificate(const String16& name, int32_t granteeUid,
+                                         ::android::String16* _aidl_return) {
+    *_aidl_return = String16();
+    if (!checkKeyDescriptor(name, granteeUid, __func__)) return Status::ok();
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    auto result =
+        checkBinderPermissionAndKeystoreState(P_GRANT, /*targetUid=*/-1, /*checkUnlocked=*/false);
+    if (!result.isOk()) return Status::ok();
+
+    String8 name8(name);
+    auto lockedEntry = mKeyStore->getLockedBlobEntryIfExists(name8.string(), callingUid);
+    if (!lockedEntry) return Status::ok();
+
+    // Key material and key characteristics must not become readable through such a grant.
+    auto [rc, blob, charBlob] = mKeyStore->get(lockedEntry);
+    if (rc != ResponseCode::NO_ERROR) return Status::ok();
+    if (blob.getType() != TYPE_GENERIC) {
+        ALOGW("uid %d cannot grant %s as a certificate, it is not a generic entry", callingUid,
+              name8.string());
+        return Status::ok();
+    }
+
+    *_aidl_return =
+        String16(mKeyStore->addGrant(lockedEntry, granteeUid, true /* certOnly */).c_str());
+    return Status::ok();
+}
+
 Status KeyStoreService::ungrant(const String16& name, int32_t granteeUid, int32_t* aidl_return) {
+    if (!checkKeyDescriptor(name, granteeUid, __func__)) {
+        *aidl_return = KeyStoreServiceReturnCode(ErrorCode::INVALID_ARGUMENT).getErrorCode();
+        return Status::ok();
+    }
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
     KeyStoreServiceReturnCode result =
         checkBinderPermissionAndKeystoreState(P_GRANT, /*targetUid=*/-1, /*checkUnlocked=*/false);
@@ -509,15 +1188,20 @@ Status KeyStoreService::ungrant(const String16& name, int32_t granteeUid, int32_
     String8 name8(name);
 
     auto lockedEntry = mKeyStore->getLockedBlobEntryIfExists(name8.string(), callingUid);
-    if (!lockedEntry) {
+    if (!lockedEntry || !mKeyStore->removeGrant(lockedEntry, granteeUid)) {
         *aidl_return = static_cast<int32_t>(ResponseCode::KEY_NOT_FOUND);
+        return Status::ok();
     }
 
-    *aidl_return = mKeyStore->removeGrant(lockedEntry, granteeUid);
+    *aidl_return = static_cast<int32_t>(ResponseCode::NO_ERROR);
     return Status::ok();
 }
 
 Status KeyStoreService::getmtime(const String16& name, int32_t uid, int64_t* time) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        *time = -1L;
+        return Status::ok();
+    }
     uid_t targetUid = getEffectiveUid(uid);
     if (!checkBinderPermission(P_GET, targetUid)) {
         ALOGW("permission denied for %d: getmtime", targetUid);
@@ -562,7 +1246,8 @@ Status KeyStoreService::is_hardware_backed(const String16& keyType, int32_t* aid
 
 Status KeyStoreService::clear_uid(int64_t targetUid64, int32_t* _aidl_return) {
     uid_t targetUid = getEffectiveUid(targetUid64);
-    if (!checkBinderPermissionSelfOrSystem(P_CLEAR_UID, targetUid)) {
+    if (!checkBinderPermissionSelfOrSystem(P_CLEAR_UID, targetUid) ||
+        !checkUidWritable(targetUid, __func__)) {
         return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
     }
     ALOGI("clear_uid %" PRId64, targetUid64);
@@ -594,12 +1279,23 @@ Status KeyStoreService::clear_uid(int64_t targetUid64, int32_t* _aidl_return) {
         }
         mKeyStore->del(lockedEntry);
     }
+
+    // Soft-deleted entries of the uid go as well. The listed entries must be unlocked first.
+    entries.clear();
+    mKeyStore->purgeTombstones(get_user_id(targetUid),
+                               [&](uid_t uid, time_t) -> bool { return uid == targetUid; });
     return AIDL_RETURN(ResponseCode::NO_ERROR);
 }
 
 Status KeyStoreService::addRngEntropy(
     const ::android::sp<::android::security::keystore::IKeystoreResponseCallback>& cb,
     const ::std::vector<uint8_t>& entropy, int32_t flags, int32_t* _aidl_return) {
+    if (!checkInputSize(entropy, inputSizeLimits().entropy, "entropy", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
+    if (!areCallerFlagsValid(flags)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
     auto device = mKeyStore->getDevice(flagsToSecurityLevel(flags));
     if (!device) {
         return AIDL_RETURN(ErrorCode::HARDWARE_TYPE_UNAVAILABLE);
@@ -616,6 +1312,15 @@ Status KeyStoreService::generateKey(
     const ::android::sp<::android::security::keystore::IKeystoreKeyCharacteristicsCallback>& cb,
     const String16& name, const KeymasterArguments& params, const ::std::vector<uint8_t>& entropy,
     int uid, int flags, int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__) || !checkNewKeyAlias(name, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    if (!checkInputSize(entropy, inputSizeLimits().entropy, "entropy", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
+    if (!areCallerFlagsValid(flags)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
     uid = getEffectiveUid(uid);
     auto logOnScopeExit = android::base::make_scope_guard([&] {
         if (__android_log_security()) {
@@ -629,6 +1334,9 @@ Status KeyStoreService::generateKey(
     if (!rc.isOk()) {
         return AIDL_RETURN(rc);
     }
+    if (!checkUidWritable(uid, __func__) || !checkTestKeyFlag(flags, __func__)) {
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
     if ((flags & KEYSTORE_FLAG_CRITICAL_TO_DEVICE_ENCRYPTION) && get_app_id(uid) != AID_SYSTEM) {
         ALOGE("Non-system uid %d cannot set FLAG_CRITICAL_TO_DEVICE_ENCRYPTION", uid);
         return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
@@ -640,11 +1348,26 @@ Status KeyStoreService::generateKey(
         }
     }
 
+    // Only Keymaster 4.1 devices know about early boot themselves.
+    if (mKeyStore->isEarlyBootEnded() && isEarlyBootOnly(params.getParameters())) {
+        return AIDL_RETURN(KeyStoreServiceReturnCode(int32_t(V4_1_ErrorCode::EARLY_BOOT_ENDED)));
+    }
+    if ((flags & KEYSTORE_FLAG_AUTO_DELETE) &&
+        !containsTag(params.getParameters(), Tag::USAGE_EXPIRE_DATETIME)) {
+        ALOGE("KEYSTORE_FLAG_AUTO_DELETE requires USAGE_EXPIRE_DATETIME");
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    if (hasInvalidDateTime(params.getParameters())) {
+        ALOGE("Date parameters must be milliseconds since the epoch");
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
     SecurityLevel securityLevel = flagsToSecurityLevel(flags);
     auto dev = mKeyStore->getDevice(securityLevel);
     if (!dev) {
         return AIDL_RETURN(ErrorCode::HARDWARE_TYPE_UNAVAILABLE);
     }
+    mKeyStore->relievePendingDeletionPressure(securityLevel);
 
     String8 name8(name);
     auto lockedEntry = mKeyStore->getLockedBlobEntryIfNotExists(name8.string(), uid);
@@ -656,11 +1379,12 @@ Status KeyStoreService::generateKey(
 
     dev->generateKey(
         std::move(lockedEntry), params.getParameters(), entropy, flags,
-        [cb, uid, name](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+        [this, cb, uid, name](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
             if (__android_log_security()) {
                 android_log_event_list(SEC_TAG_AUTH_KEY_GENERATED)
                     << rc.isOk() << String8(name) << int32_t(uid) << LOG_ID_SECURITY;
             }
+            countResult("generateKey", rc);
             cb->onFinished(rc,
                            android::security::keymaster::KeyCharacteristics(keyCharacteristics));
         });
@@ -673,6 +1397,9 @@ Status KeyStoreService::getKeyCharacteristics(
     const String16& name, const ::android::security::keymaster::KeymasterBlob& clientId,
     const ::android::security::keymaster::KeymasterBlob& appData, int32_t uid,
     int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
 
     uid_t targetUid = getEffectiveUid(uid);
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
@@ -693,7 +1420,7 @@ Status KeyStoreService::getKeyCharacteristics(
         mKeyStore->getKeyForName(name8, targetUid, TYPE_KEYMASTER_10);
 
     if (rc != ResponseCode::NO_ERROR) {
-        return AIDL_RETURN(rc);
+        return AIDL_RETURN(keyLoadError(rc, keyBlob));
     }
 
     auto dev = mKeyStore->getDevice(keyBlob);
@@ -707,7 +1434,8 @@ Status KeyStoreService::getKeyCharacteristics(
     dev->getKeyCharacteristics(
         std::move(lockedEntry), clientId.getData(), appData.getData(), std::move(keyBlob),
         std::move(charBlob),
-        [cb](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+        [this, cb](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+            countResult("getKeyCharacteristics", rc);
             cb->onFinished(rc,
                            android::security::keymaster::KeyCharacteristics(keyCharacteristics));
         });
@@ -719,6 +1447,15 @@ Status KeyStoreService::importKey(
     const ::android::sp<::android::security::keystore::IKeystoreKeyCharacteristicsCallback>& cb,
     const String16& name, const KeymasterArguments& params, int32_t format,
     const ::std::vector<uint8_t>& keyData, int uid, int flags, int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__) || !checkNewKeyAlias(name, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    if (!checkInputSize(keyData, inputSizeLimits().keyData, "key data", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
+    if (!areCallerFlagsValid(flags)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
     uid = getEffectiveUid(uid);
     auto logOnScopeExit = android::base::make_scope_guard([&] {
         if (__android_log_security()) {
@@ -733,17 +1470,35 @@ Status KeyStoreService::importKey(
         LOG(ERROR) << "permissission denied";
         return AIDL_RETURN(rc);
     }
+    if (!checkUidWritable(uid, __func__) || !checkTestKeyFlag(flags, __func__)) {
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
     if ((flags & KEYSTORE_FLAG_CRITICAL_TO_DEVICE_ENCRYPTION) && get_app_id(uid) != AID_SYSTEM) {
         ALOGE("Non-system uid %d cannot set FLAG_CRITICAL_TO_DEVICE_ENCRYPTION", uid);
         return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
     }
 
+    // Only Keymaster 4.1 devices know about early boot themselves.
+    if (mKeyStore->isEarlyBootEnded() && isEarlyBootOnly(params.getParameters())) {
+        return AIDL_RETURN(KeyStoreServiceReturnCode(int32_t(V4_1_ErrorCode::EARLY_BOOT_ENDED)));
+    }
+    if ((flags & KEYSTORE_FLAG_AUTO_DELETE) &&
+        !containsTag(params.getParameters(), Tag::USAGE_EXPIRE_DATETIME)) {
+        ALOGE("KEYSTORE_FLAG_AUTO_DELETE requires USAGE_EXPIRE_DATETIME");
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    if (hasInvalidDateTime(params.getParameters())) {
+        ALOGE("Date parameters must be milliseconds since the epoch");
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
     SecurityLevel securityLevel = flagsToSecurityLevel(flags);
     auto dev = mKeyStore->getDevice(securityLevel);
     if (!dev) {
         LOG(ERROR) << "importKey - cound not get keymaster device";
         return AIDL_RETURN(ErrorCode::HARDWARE_TYPE_UNAVAILABLE);
     }
+    mKeyStore->relievePendingDeletionPressure(securityLevel);
 
     String8 name8(name);
     auto lockedEntry = mKeyStore->getLockedBlobEntryIfNotExists(name8.string(), uid);
@@ -757,11 +1512,12 @@ Status KeyStoreService::importKey(
 
     dev->importKey(
         std::move(lockedEntry), params.getParameters(), KeyFormat(format), keyData, flags,
-        [cb, uid, name](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+        [this, cb, uid, name](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
             if (__android_log_security()) {
                 android_log_event_list(SEC_TAG_KEY_IMPORTED)
                     << rc.isOk() << String8(name) << int32_t(uid) << LOG_ID_SECURITY;
             }
+            countResult("importKey", rc);
             cb->onFinished(rc,
                            android::security::keymaster::KeyCharacteristics(keyCharacteristics));
         });
@@ -775,6 +1531,9 @@ Status KeyStoreService::exportKey(
     const ::android::security::keymaster::KeymasterBlob& clientId,
     const ::android::security::keymaster::KeymasterBlob& appData, int32_t uid,
     int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
 
     uid_t targetUid = getEffectiveUid(uid);
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
@@ -793,14 +1552,17 @@ Status KeyStoreService::exportKey(
     std::tie(rc, keyBlob, charBlob, lockedEntry) =
         mKeyStore->getKeyForName(name8, targetUid, TYPE_KEYMASTER_10);
     if (!rc.isOk()) {
-        return AIDL_RETURN(rc);
+        return AIDL_RETURN(keyLoadError(rc, keyBlob));
     }
 
     auto dev = mKeyStore->getDevice(keyBlob);
 
     dev->exportKey(std::move(lockedEntry), KeyFormat(format), clientId.getData(), appData.getData(),
                    std::move(keyBlob), std::move(charBlob),
-                   [cb](ExportResult exportResult) { cb->onFinished(exportResult); });
+                   [this, cb](ExportResult exportResult) {
+                       countResult("exportKey", exportResult.resultCode);
+                       cb->onFinished(exportResult);
+                   });
 
     return AIDL_RETURN(ResponseCode::NO_ERROR);
 }
@@ -810,18 +1572,50 @@ Status KeyStoreService::begin(const sp<IKeystoreOperationResultCallback>& cb,
                               bool pruneable, const KeymasterArguments& params,
                               const ::std::vector<uint8_t>& entropy, int32_t uid,
                               int32_t* _aidl_return) {
+    return beginImpl(cb, appToken, name, purpose, pruneable, params, entropy, uid,
+                     0 /* no deadline */, __func__, _aidl_return);
+}
+
+Status KeyStoreService::beginWithDeadline(const sp<IKeystoreOperationResultCallback>& cb,
+                                          const sp<IBinder>& appToken, const String16& name,
+                                          int32_t purpose, bool pruneable,
+                                          const KeymasterArguments& params,
+                                          const ::std::vector<uint8_t>& entropy, int32_t uid,
+                                          int64_t timeoutMillis, int32_t* _aidl_return) {
+    return beginImpl(cb, appToken, name, purpose, pruneable, params, entropy, uid, timeoutMillis,
+                     __func__, _aidl_return);
+}
+
+Status KeyStoreService::beginImpl(const sp<IKeystoreOperationResultCallback>& cb,
+                                  const sp<IBinder>& appToken, const String16& name,
+                                  int32_t purpose, bool pruneable, const KeymasterArguments& params,
+                                  const ::std::vector<uint8_t>& entropy, int32_t uid,
+                                  int64_t timeoutMillis, const char* api, int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(name, uid, api)) {
+        return AIDL_RETURN_AS(api, ErrorCode::INVALID_ARGUMENT);
+    }
+    if (!checkInputSize(entropy, inputSizeLimits().entropy, "entropy", api)) {
+        return AIDL_RETURN_AS(api, ErrorCode::INVALID_INPUT_LENGTH);
+    }
+    std::optional<std::chrono::steady_clock::time_point> deadline;
+    if (timeoutMillis > 0) {
+        deadline = std::chrono::steady_clock::now() + std::chrono::milliseconds(timeoutMillis);
+    }
+
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
     uid_t targetUid = getEffectiveUid(uid);
     if (!is_granted_to(callingUid, targetUid)) {
-        ALOGW("uid %d not permitted to act for uid %d in begin", callingUid, targetUid);
-        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+        ALOGW("uid %d not permitted to act for uid %d in %s", callingUid, targetUid, api);
+        return AIDL_RETURN_AS(api, ResponseCode::PERMISSION_DENIED);
     }
+    // Non-pruneable operations are forced: they are never pruned to make room for others. Only the
+    // system uid may start them.
     if (!pruneable && get_app_id(callingUid) != AID_SYSTEM) {
         ALOGE("Non-system uid %d trying to start non-pruneable operation", callingUid);
-        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+        return AIDL_RETURN_AS(api, ResponseCode::PERMISSION_DENIED);
     }
     if (!checkAllowedOperationParams(params.getParameters())) {
-        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+        return AIDL_RETURN_AS(api, ErrorCode::INVALID_ARGUMENT);
     }
 
     String8 name8(name);
@@ -833,25 +1627,25 @@ Status KeyStoreService::begin(const sp<IKeystoreOperationResultCallback>& cb,
     std::tie(rc, keyBlob, charBlob, lockedEntry) =
         mKeyStore->getKeyForName(name8, targetUid, TYPE_KEYMASTER_10);
 
-    if (rc == ResponseCode::LOCKED && keyBlob.isSuperEncrypted()) {
-        return AIDL_RETURN(ErrorCode::KEY_USER_NOT_AUTHENTICATED);
-    }
-    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(rc);
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN_AS(api, keyLoadError(rc, keyBlob));
 
     auto dev = mKeyStore->getDevice(keyBlob);
     AuthorizationSet opParams = params.getParameters();
 
-    dev->begin(std::move(lockedEntry), appToken, std::move(keyBlob), std::move(charBlob), pruneable,
-               static_cast<KeyPurpose>(purpose), std::move(opParams), entropy,
-               [this, cb, dev](OperationResult result_) {
+    dev->begin(std::move(lockedEntry), callingUid, appToken, std::move(keyBlob),
+               std::move(charBlob), pruneable, static_cast<KeyPurpose>(purpose),
+               std::move(opParams), entropy, deadline,
+               [this, cb, dev, api](OperationResult result_) {
                    if (result_.resultCode.isOk() ||
                        result_.resultCode == ResponseCode::OP_AUTH_NEEDED) {
                        mKeyStore->addOperationDevice(result_.token, dev);
+                   } else {
+                       countResult(api, result_.resultCode);
                    }
                    cb->onFinished(result_);
                });
 
-    return AIDL_RETURN(ResponseCode::NO_ERROR);
+    return AIDL_RETURN_AS(api, ResponseCode::NO_ERROR);
 }
 
 Status KeyStoreService::update(const ::android::sp<IKeystoreOperationResultCallback>& cb,
@@ -861,22 +1655,62 @@ Status KeyStoreService::update(const ::android::sp<IKeystoreOperationResultCallb
     if (!checkAllowedOperationParams(params.getParameters())) {
         return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
     }
+    if (!checkInputSize(input, inputSizeLimits().operationData, "input", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
 
     auto dev = mKeyStore->getOperationDevice(token);
     if (!dev) {
-        return AIDL_RETURN(ErrorCode::INVALID_OPERATION_HANDLE);
+        return AIDL_RETURN(operationNotFoundError(token));
+    }
+    if (FdOperationStreamer::isStreaming(token)) {
+        ALOGW("%s: operation is being streamed", __func__);
+        return AIDL_RETURN(ResponseCode::BACKEND_BUSY);
     }
 
     dev->update(token, params.getParameters(), input, [this, cb, token](OperationResult result_) {
         if (!result_.resultCode.isOk()) {
             mKeyStore->removeOperationDevice(token);
         }
+        countResult("update", result_.resultCode);
         cb->onFinished(result_);
     });
 
     return AIDL_RETURN(ResponseCode::NO_ERROR);
 }
 
+Status KeyStoreService::updateWithFds(const ::android::sp<IKeystoreOperationResultCallback>& cb,
+                                      const ::android::sp<::android::IBinder>& token,
+                                      const KeymasterArguments& params,
+                                      const ::android::os::ParcelFileDescriptor& input,
+                                      const ::android::os::ParcelFileDescriptor& output,
+                                      int32_t* _aidl_return) {
+    if (!checkAllowedOperationParams(params.getParameters())) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    android::base::unique_fd inputFd, outputFd;
+    auto rc = dupStreamableFds(input, output, &inputFd, &outputFd, __func__);
+    if (!rc.isOk()) return AIDL_RETURN(rc);
+
+    auto dev = mKeyStore->getOperationDevice(token);
+    if (!dev) {
+        return AIDL_RETURN(operationNotFoundError(token));
+    }
+
+    rc = std::make_shared<FdOperationStreamer>(mKeyStore.get(), dev, token,
+                                               params.getParameters(), std::move(inputFd),
+                                               std::move(outputFd),
+                                               inputSizeLimits().operationData,
+                                               [this, cb](OperationResult result_) {
+                                                   countResult("updateWithFds",
+                                                               result_.resultCode);
+                                                   cb->onFinished(result_);
+                                               })
+             ->update();
+
+    return AIDL_RETURN(rc);
+}
+
 Status KeyStoreService::finish(const ::android::sp<IKeystoreOperationResultCallback>& cb,
                                const ::android::sp<::android::IBinder>& token,
                                const ::android::security::keymaster::KeymasterArguments& params,
@@ -886,27 +1720,76 @@ Status KeyStoreService::finish(const ::android::sp<IKeystoreOperationResultCallb
     if (!checkAllowedOperationParams(params.getParameters())) {
         return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
     }
+    const auto& limits = inputSizeLimits();
+    if (!checkInputSize(input, limits.operationData, "input", __func__) ||
+        !checkInputSize(signature, limits.operationData, "signature", __func__) ||
+        !checkInputSize(entropy, limits.entropy, "entropy", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
 
     auto dev = mKeyStore->getOperationDevice(token);
     if (!dev) {
-        return AIDL_RETURN(ErrorCode::INVALID_OPERATION_HANDLE);
+        return AIDL_RETURN(operationNotFoundError(token));
+    }
+    if (FdOperationStreamer::isStreaming(token)) {
+        ALOGW("%s: operation is being streamed", __func__);
+        return AIDL_RETURN(ResponseCode::BACKEND_BUSY);
     }
 
     dev->finish(token, params.getParameters(), input, signature, entropy,
                 [this, cb, token](OperationResult result_) {
                     mKeyStore->removeOperationDevice(token);
+                    countResult("finish", result_.resultCode);
                     cb->onFinished(result_);
                 });
 
     return AIDL_RETURN(ResponseCode::NO_ERROR);
 }
 
+Status KeyStoreService::finishWithFds(const ::android::sp<IKeystoreOperationResultCallback>& cb,
+                                      const ::android::sp<::android::IBinder>& token,
+                                      const KeymasterArguments& params,
+                                      const ::android::os::ParcelFileDescriptor& input,
+                                      const ::std::vector<uint8_t>& signature,
+                                      const ::std::vector<uint8_t>& entropy,
+                                      const ::android::os::ParcelFileDescriptor& output,
+                                      int32_t* _aidl_return) {
+    if (!checkAllowedOperationParams(params.getParameters())) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    const auto& limits = inputSizeLimits();
+    if (!checkInputSize(signature, limits.operationData, "signature", __func__) ||
+        !checkInputSize(entropy, limits.entropy, "entropy", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
+    android::base::unique_fd inputFd, outputFd;
+    auto rc = dupStreamableFds(input, output, &inputFd, &outputFd, __func__);
+    if (!rc.isOk()) return AIDL_RETURN(rc);
+
+    auto dev = mKeyStore->getOperationDevice(token);
+    if (!dev) {
+        return AIDL_RETURN(operationNotFoundError(token));
+    }
+
+    rc = std::make_shared<FdOperationStreamer>(mKeyStore.get(), dev, token,
+                                               params.getParameters(), std::move(inputFd),
+                                               std::move(outputFd), limits.operationData,
+                                               [this, cb](OperationResult result_) {
+                                                   countResult("finishWithFds",
+                                                               result_.resultCode);
+                                                   cb->onFinished(result_);
+                                               })
+             ->finish(signature, entropy);
+
+    return AIDL_RETURN(rc);
+}
+
 Status KeyStoreService::abort(const ::android::sp<IKeystoreResponseCallback>& cb,
                               const ::android::sp<::android::IBinder>& token,
                               int32_t* _aidl_return) {
     auto dev = mKeyStore->getOperationDevice(token);
     if (!dev) {
-        return AIDL_RETURN(ErrorCode::INVALID_OPERATION_HANDLE);
+        return AIDL_RETURN(operationNotFoundError(token));
     }
 
     dev->abort(token, [this, cb, token](KeyStoreServiceReturnCode rc) {
@@ -1025,6 +1908,9 @@ Status KeyStoreService::attestKey(
     const ::android::sp<::android::security::keystore::IKeystoreCertificateChainCallback>& cb,
     const String16& name, const KeymasterArguments& params, int32_t* _aidl_return) {
     // check null output if method signature is updated and return ErrorCode::OUTPUT_PARAMETER_NULL
+    if (!checkKeyDescriptor(name, UID_SELF, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
     if (!checkAllowedOperationParams(params.getParameters())) {
         return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
     }
@@ -1054,7 +1940,7 @@ Status KeyStoreService::attestKey(
         mKeyStore->getKeyForName(name8, callingUid, TYPE_KEYMASTER_10);
 
     if (!rc.isOk()) {
-        return AIDL_RETURN(rc);
+        return AIDL_RETURN(keyLoadError(rc, keyBlob));
     }
 
     logErrorOnReturn.Disable();
@@ -1198,10 +2084,19 @@ Status KeyStoreService::importWrappedKey(
     const ::android::String16& wrappingKeyAlias, const ::std::vector<uint8_t>& maskingKey,
     const KeymasterArguments& params, int64_t rootSid, int64_t fingerprintSid,
     int32_t* _aidl_return) {
+    if (!checkKeyDescriptor(wrappedKeyAlias, UID_SELF, __func__) ||
+        !checkNewKeyAlias(wrappedKeyAlias, __func__) ||
+        !checkKeyDescriptor(wrappingKeyAlias, UID_SELF, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+    if (!checkInputSize(wrappedKey, inputSizeLimits().keyData, "wrapped key", __func__) ||
+        !checkInputSize(maskingKey, inputSizeLimits().maskingKey, "masking key", __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_INPUT_LENGTH);
+    }
 
     uid_t callingUid = IPCThreadState::self()->getCallingUid();
 
-    if (!checkBinderPermission(P_INSERT, callingUid)) {
+    if (!checkBinderPermission(P_INSERT, callingUid) || !checkUidWritable(callingUid, __func__)) {
         return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
     }
 
@@ -1234,7 +2129,9 @@ Status KeyStoreService::importWrappedKey(
     dev->importWrappedKey(
         std::move(wrappingLockedEntry), std::move(wrappedLockedEntry), wrappedKey, maskingKey,
         params.getParameters(), std::move(wrappingKeyBlob), std::move(wrappingCharBlob), rootSid,
-        fingerprintSid, [cb](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+        fingerprintSid,
+        [this, cb](KeyStoreServiceReturnCode rc, KeyCharacteristics keyCharacteristics) {
+            countResult("importWrappedKey", rc);
             cb->onFinished(rc,
                            ::android::security::keymaster::KeyCharacteristics(keyCharacteristics));
         });
@@ -1260,6 +2157,89 @@ Status KeyStoreService::isConfirmationPromptSupported(bool* aidl_return) {
     return mKeyStore->getConfirmationManager().isConfirmationPromptSupported(aidl_return);
 }
 
+bool KeyStoreService::checkKeyDescriptor(const String16& name, int32_t targetUid,
+                                         const char* method) {
+    auto error = validateKeyDescriptor(String8(name).string(), targetUid);
+    if (error) {
+        ALOGW("%s: invalid key descriptor: %s", method, error->c_str());
+        return false;
+    }
+    return true;
+}
+
+bool KeyStoreService::checkNewKeyAlias(const String16& name, const char* method) {
+    auto error = validateNewKeyAlias(String8(name).string());
+    if (error) {
+        ALOGW("%s: invalid key alias: %s", method, error->c_str());
+        return false;
+    }
+    return true;
+}
+
+bool KeyStoreService::checkUidWritable(uid_t targetUid, const char* method) {
+    if (mKeyStore->isUidReadOnly(targetUid)) {
+        ALOGW("%s: keys of uid %d are read-only", method, targetUid);
+        return false;
+    }
+    return true;
+}
+
+KeyStoreServiceReturnCode KeyStoreService::operationNotFoundError(const sp<IBinder>& token) {
+    if (auto reason = mKeyStore->getOperationDropReason(token)) return *reason;
+    return ErrorCode::INVALID_OPERATION_HANDLE;
+}
+
+bool KeyStoreService::checkTestKeyFlag(int32_t flags, const char* method) {
+    if (!(flags & KEYSTORE_FLAG_TEST_KEY)) return true;
+    uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    if (get_app_id(callingUid) == AID_SYSTEM ||
+        android::base::GetBoolProperty("ro.debuggable", false)) {
+        return true;
+    }
+    ALOGE("%s: uid %d cannot set KEYSTORE_FLAG_TEST_KEY", method, callingUid);
+    return false;
+}
+
+KeyStoreServiceReturnCode KeyStoreService::getCachedKeyCharacteristics(
+    const String16& name, int32_t uid, uid_t targetUid,
+    ::android::security::keymaster::KeyCharacteristics* out) {
+    if (!checkKeyDescriptor(name, uid, "getKeyCharacteristicsBatch")) {
+        return ErrorCode::INVALID_ARGUMENT;
+    }
+
+    Blob keyBlob;
+    Blob charBlob;
+    LockedKeyBlobEntry lockedEntry;
+    ResponseCode rc;
+    std::tie(rc, keyBlob, charBlob, lockedEntry) =
+        mKeyStore->getKeyForName(String8(name), targetUid, TYPE_KEYMASTER_10);
+    if (rc != ResponseCode::NO_ERROR) return keyLoadError(rc, keyBlob);
+
+    // Legacy or missing caches are only rebuilt by the Keymaster worker.
+    if (!charBlob || charBlob.getType() != TYPE_KEY_CHARACTERISTICS_CACHE) {
+        return ErrorCode::KEY_REQUIRES_UPGRADE;
+    }
+
+    bool success;
+    AuthorizationSet hwEnforced, swEnforced;
+    std::tie(success, hwEnforced, swEnforced) = charBlob.getKeyCharacteristics();
+    if (!success) {
+        ALOGE("Failed to read cached key characteristics");
+        return ResponseCode::SYSTEM_ERROR;
+    }
+
+    KeyCharacteristics keyCharacteristics;
+    keyCharacteristics.hardwareEnforced = hwEnforced.hidl_data();
+    keyCharacteristics.softwareEnforced = swEnforced.hidl_data();
+    *out = ::android::security::keymaster::KeyCharacteristics(std::move(keyCharacteristics));
+    return ResponseCode::NO_ERROR;
+}
+
+int32_t KeyStoreService::countResult(const char* api, const KeyStoreServiceReturnCode& rc) {
+    if (!rc.isOk()) mKeyStore->getErrorCounters().record(api, rc.getErrorCode());
+    return rc.getErrorCode();
+}
+
 /**
  * Get the effective target uid for a binder operation that takes an
  * optional uid as the target.
@@ -1391,4 +2371,81 @@ Status KeyStoreService::onKeyguardVisibilityChanged(bool isShowing, int32_t user
     return AIDL_RETURN(ResponseCode::NO_ERROR);
 }
 
+Status KeyStoreService::earlyBootEnded(int32_t* _aidl_return) {
+    // vold ends early boot, and it runs as root.
+    const uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    if (get_app_id(callingUid) != AID_SYSTEM && callingUid != AID_ROOT) {
+        ALOGE("Permission earlyBootEnded denied for uid %d", callingUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (!mKeyStore->setEarlyBootEnded()) return AIDL_RETURN(ResponseCode::NO_ERROR);
+
+    LOG(INFO) << "early boot ended";
+    for (auto securityLevel :
+         {SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX}) {
+        auto dev = mKeyStore->getDevice(securityLevel);
+        if (!dev) continue;
+        dev->earlyBootEnded([securityLevel](Return<V4_1_ErrorCode> rc) {
+            if (!rc.isOk()) {
+                LOG(ERROR) << "earlyBootEnded on " << toString(securityLevel)
+                           << " failed: " << rc.description();
+                return;
+            }
+            V4_1_ErrorCode ret = rc;
+            // Keymaster 4.0 devices don't know about early boot and report UNIMPLEMENTED.
+            if (ret != V4_1_ErrorCode::OK && ret != V4_1_ErrorCode::UNIMPLEMENTED) {
+                LOG(ERROR) << "earlyBootEnded on " << toString(securityLevel)
+                           << " failed: " << toString(ret);
+            }
+        });
+    }
+    return AIDL_RETURN(ResponseCode::NO_ERROR);
+}
+
+Status KeyStoreService::setKeyPruningExempt(const String16& name, int32_t uid, bool exempt,
+                                            int32_t* _aidl_return) {
+    const uid_t callingUid = IPCThreadState::self()->getCallingUid();
+    if (get_app_id(callingUid) != AID_SYSTEM) {
+        ALOGE("Permission setKeyPruningExempt denied for uid %d", callingUid);
+        return AIDL_RETURN(ResponseCode::PERMISSION_DENIED);
+    }
+    if (!checkKeyDescriptor(name, uid, __func__)) {
+        return AIDL_RETURN(ErrorCode::INVALID_ARGUMENT);
+    }
+
+    uid_t targetUid = getEffectiveUid(uid);
+    Blob keyBlob;
+    Blob charBlob;
+    LockedKeyBlobEntry lockedEntry;
+    ResponseCode rc;
+    std::tie(rc, keyBlob, charBlob, lockedEntry) =
+        mKeyStore->getKeyForName(String8(name), targetUid, TYPE_KEYMASTER_10);
+    if (rc != ResponseCode::NO_ERROR) return AIDL_RETURN(keyLoadError(rc, keyBlob));
+    if (keyBlob.isPruningExempt() == exempt) return AIDL_RETURN(ResponseCode::NO_ERROR);
+
+    keyBlob.setPruningExempt(exempt);
+    // The characteristics are left as they are.
+    rc = mKeyStore->put(lockedEntry, std::move(keyBlob), {});
+    if (rc == ResponseCode::NO_ERROR) {
+        LOG(INFO) << "key " << lockedEntry->alias() << " of uid " << targetUid
+                  << (exempt ? " is now" : " is no longer") << " pruning exempt";
+    }
+    return AIDL_RETURN(rc);
+}
+
+status_t KeyStoreService::dump(int fd, const Vector<String16>& /* args */) {
+    if (!checkCallingPermission(String16("android.permission.DUMP"))) {
+        dprintf(fd, "Permission Denial: can't dump keystore from pid=%d, uid=%d\n",
+                IPCThreadState::self()->getCallingPid(), IPCThreadState::self()->getCallingUid());
+        return PERMISSION_DENIED;
+    }
+    mKeyStore->dumpStartup(fd);
+    mKeyStore->getErrorCounters().dump(fd);
+    mKeyStore->getEnforcementTrace().dump(fd);
+    mKeyStore->getSlowCallTracker().dump(fd);
+    mKeyStore->dumpBeginRetryLimitHits(fd);
+    dumpAbnormalOperationTerminations(fd);
+    return NO_ERROR;
+}
+
 }  // namespace keystore
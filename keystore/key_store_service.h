@@ -44,6 +44,11 @@ namespace keystore {
 // java/android/security/IKeystoreService.aidl Note that all generated methods return binder::Status
 // and use last arguments to send actual result to the caller. Private methods don't need to handle
 // binder::Status. Input parameters cannot be null unless annotated with @nullable in .aidl file.
+//
+// Methods must not reveal whether a key they may not access exists. PERMISSION_DENIED is reserved
+// for checks on the caller and the target namespace, which are made before any key is looked up
+// and therefore cannot depend on the key. Everything that depends on a particular key, e.g. an
+// alias that is missing, not granted to the caller or granted across users, is KEY_NOT_FOUND.
 class KeyStoreService : public android::security::keystore::BnKeystoreService {
   public:
     explicit KeyStoreService(sp<KeyStore> keyStore) : mKeyStore(keyStore) {}
@@ -59,12 +64,64 @@ class KeyStoreService : public android::security::keystore::BnKeystoreService {
                                      int32_t* _aidl_return) override;
     ::android::binder::Status del(const ::android::String16& name, int32_t uid,
                                   int32_t* _aidl_return) override;
+    ::android::binder::Status undelete(const ::android::String16& name, int32_t uid,
+                                       int32_t* _aidl_return) override;
     ::android::binder::Status exist(const ::android::String16& name, int32_t uid,
                                     int32_t* _aidl_return) override;
     ::android::binder::Status list(const ::android::String16& namePrefix, int32_t uid,
                                    ::std::vector<::android::String16>* _aidl_return) override;
+    ::android::binder::Status listFiltered(const ::android::String16& namePrefix, int32_t uid,
+                                           int32_t algorithm, bool authBoundOnly,
+                                           int32_t securityLevel,
+                                           ::std::vector<::android::String16>* aliases,
+                                           int32_t* _aidl_return) override;
+    ::android::binder::Status listPaged(const ::android::String16& namePrefix, int32_t uid,
+                                        const ::android::String16& startPastAlias,
+                                        int32_t maxCount,
+                                        ::std::vector<::android::String16>* aliases,
+                                        int32_t* _aidl_return) override;
     ::android::binder::Status listUidsOfAuthBoundKeys(std::vector<::std::string>* uids,
                                                       int32_t* _aidl_return) override;
+    ::android::binder::Status listOperations(std::vector<::std::string>* operations,
+                                             int32_t* _aidl_return) override;
+    ::android::binder::Status scanKeyIntegrity(int32_t userId, int32_t sampleSize,
+                                               std::vector<::std::string>* rejected,
+                                               int32_t* _aidl_return) override;
+    ::android::binder::Status registerKeyLifecycleListener(
+        const ::android::sp<::android::security::keystore::IKeystoreKeyLifecycleListener>& listener,
+        int32_t uid, int32_t* _aidl_return) override;
+    ::android::binder::Status unregisterKeyLifecycleListener(
+        const ::android::sp<::android::security::keystore::IKeystoreKeyLifecycleListener>& listener,
+        int32_t* _aidl_return) override;
+    ::android::binder::Status setUidReadOnly(int32_t uid, bool readOnly,
+                                             int32_t* _aidl_return) override;
+    ::android::binder::Status deleteTestKeys(int32_t userId, int32_t* _aidl_return) override;
+    ::android::binder::Status checkConsistency(bool repair, ::std::vector<::std::string>* report,
+                                               int32_t* _aidl_return) override;
+    ::android::binder::Status getKeyAvailability(
+        const ::android::sp<::android::security::keystore::IKeystoreResponseCallback>& cb,
+        const ::android::String16& alias, int32_t uid, int32_t purpose,
+        int32_t* _aidl_return) override;
+    ::android::binder::Status getKeyCharacteristicsBatch(
+        const ::std::vector<::android::String16>& aliases, int32_t uid,
+        ::std::vector<int32_t>* statuses,
+        ::std::vector<::android::security::keymaster::KeyCharacteristics>* characteristics,
+        int32_t* _aidl_return) override;
+    ::android::binder::Status
+    getKeyEntry(const ::android::String16& alias, int32_t uid,
+                ::android::security::keymaster::KeyCharacteristics* characteristics,
+                int32_t* _aidl_return) override;
+    ::android::binder::Status earlyBootEnded(int32_t* _aidl_return) override;
+    ::android::binder::Status setKeyPruningExempt(const ::android::String16& alias, int32_t uid,
+                                                  bool exempt, int32_t* _aidl_return) override;
+    ::android::binder::Status
+    diffKeyCharacteristics(const ::android::String16& alias, int32_t uid,
+                           ::std::vector<::std::string>* differences,
+                           int32_t* _aidl_return) override;
+    ::android::binder::Status getRawKeyCharacteristics(
+        const ::android::String16& alias, int32_t uid,
+        ::android::security::keymaster::KeyCharacteristics* characteristics,
+        int32_t* _aidl_return) override;
 
     ::android::binder::Status onUserPasswordChanged(int32_t userId,
                                                     const ::android::String16& newPassword,
@@ -75,6 +132,9 @@ class KeyStoreService : public android::security::keystore::BnKeystoreService {
     ::android::binder::Status isEmpty(int32_t userId, int32_t* _aidl_return) override;
     ::android::binder::Status grant(const ::android::String16& name, int32_t granteeUid,
                                     ::android::String16* _aidl_return) override;
+    ::android::binder::Status grantCertificate(const ::android::String16& name,
+                                               int32_t granteeUid,
+                                               ::android::String16* _aidl_return) override;
     ::android::binder::Status ungrant(const ::android::String16& name, int32_t granteeUid,
                                       int32_t* _aidl_return) override;
     ::android::binder::Status getmtime(const ::android::String16& name, int32_t uid,
@@ -116,17 +176,37 @@ class KeyStoreService : public android::security::keystore::BnKeystoreService {
           int32_t purpose, bool pruneable,
           const ::android::security::keymaster::KeymasterArguments& params,
           const ::std::vector<uint8_t>& entropy, int32_t uid, int32_t* _aidl_return) override;
+    ::android::binder::Status beginWithDeadline(
+        const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
+        const ::android::sp<::android::IBinder>& appToken, const ::android::String16& alias,
+        int32_t purpose, bool pruneable,
+        const ::android::security::keymaster::KeymasterArguments& params,
+        const ::std::vector<uint8_t>& entropy, int32_t uid, int64_t timeoutMillis,
+        int32_t* _aidl_return) override;
     ::android::binder::Status
     update(const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
            const ::android::sp<::android::IBinder>& token,
            const ::android::security::keymaster::KeymasterArguments& params,
            const ::std::vector<uint8_t>& input, int32_t* _aidl_return) override;
+    ::android::binder::Status updateWithFds(
+        const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
+        const ::android::sp<::android::IBinder>& token,
+        const ::android::security::keymaster::KeymasterArguments& params,
+        const ::android::os::ParcelFileDescriptor& input,
+        const ::android::os::ParcelFileDescriptor& output, int32_t* _aidl_return) override;
     ::android::binder::Status
     finish(const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
            const ::android::sp<::android::IBinder>& token,
            const ::android::security::keymaster::KeymasterArguments& params,
            const ::std::vector<uint8_t>& input, const ::std::vector<uint8_t>& signature,
            const ::std::vector<uint8_t>& entropy, int32_t* _aidl_return) override;
+    ::android::binder::Status finishWithFds(
+        const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
+        const ::android::sp<::android::IBinder>& token,
+        const ::android::security::keymaster::KeymasterArguments& params,
+        const ::android::os::ParcelFileDescriptor& input, const ::std::vector<uint8_t>& signature,
+        const ::std::vector<uint8_t>& entropy, const ::android::os::ParcelFileDescriptor& output,
+        int32_t* _aidl_return) override;
     ::android::binder::Status
     abort(const ::android::sp<::android::security::keystore::IKeystoreResponseCallback>& cb,
           const ::android::sp<::android::IBinder>& token, int32_t* _aidl_return) override;
@@ -168,6 +248,8 @@ class KeyStoreService : public android::security::keystore::BnKeystoreService {
     ::android::binder::Status onKeyguardVisibilityChanged(bool isShowing, int32_t userId,
                                                           int32_t* _aidl_return) override;
 
+    ::android::status_t dump(int fd, const ::android::Vector<::android::String16>& args) override;
+
   private:
     static const int32_t UID_SELF = -1;
 
@@ -177,6 +259,63 @@ class KeyStoreService : public android::security::keystore::BnKeystoreService {
      */
     uid_t getEffectiveUid(int32_t targetUid);
 
+    /**
+     * Check that a key alias and target uid supplied by the caller are well formed before they
+     * are used to look up any key. Logs the reason on failure.
+     */
+    bool checkKeyDescriptor(const ::android::String16& name, int32_t targetUid,
+                            const char* method);
+
+    /**
+     * Check that the alias of an entry about to be created is short enough for every file name
+     * derived from it. Logs the reason on failure.
+     */
+    bool checkNewKeyAlias(const ::android::String16& name, const char* method);
+
+    /**
+     * Check that the keys of targetUid may be created, replaced or deleted, i.e., that the uid
+     * has not been marked read-only. Logs the refusal on failure.
+     */
+    bool checkUidWritable(uid_t targetUid, const char* method);
+
+    /**
+     * Check that the caller may tag keys with KEYSTORE_FLAG_TEST_KEY if flags contains it. This
+     * is limited to the system uid and to debuggable builds.
+     */
+    bool checkTestKeyFlag(int32_t flags, const char* method);
+
+    /**
+     * The error for an operation token without an operation: OPERATION_EXPIRED if keystore
+     * aborted the operation because it was idle for too long, BACKEND_RESTARTED if it was dropped
+     * because its Keymaster restarted, INVALID_OPERATION_HANDLE otherwise.
+     */
+    KeyStoreServiceReturnCode
+    operationNotFoundError(const ::android::sp<::android::IBinder>& token);
+
+    /**
+     * Count rc against the error counters of api unless it indicates success. Returns the error
+     * code to be handed to the caller.
+     */
+    int32_t countResult(const char* api, const KeyStoreServiceReturnCode& rc);
+
+    /**
+     * Shared by begin and beginWithDeadline. Results are counted under api.
+     */
+    ::android::binder::Status beginImpl(
+        const ::android::sp<::android::security::keystore::IKeystoreOperationResultCallback>& cb,
+        const ::android::sp<::android::IBinder>& appToken, const ::android::String16& alias,
+        int32_t purpose, bool pruneable,
+        const ::android::security::keymaster::KeymasterArguments& params,
+        const ::std::vector<uint8_t>& entropy, int32_t uid, int64_t timeoutMillis,
+        const char* api, int32_t* _aidl_return);
+
+    /**
+     * Read the characteristics of a key from its cache file, without asking Keymaster.
+     */
+    KeyStoreServiceReturnCode
+    getCachedKeyCharacteristics(const ::android::String16& name, int32_t uid, uid_t targetUid,
+                                ::android::security::keymaster::KeyCharacteristics* out);
+
     /**
      * Check if the caller of the current binder method has the required
      * permission and if acting on other uids the grants to do so.
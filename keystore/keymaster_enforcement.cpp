@@ -73,7 +73,8 @@ ErrorCode KeymasterEnforcement::AuthorizeOperation(const KeyPurpose purpose, con
                                                    const AuthorizationSet& auth_set,
                                                    const AuthorizationSet& operation_params,
                                                    const HardwareAuthToken& auth_token,
-                                                   uint64_t op_handle, bool is_begin_operation) {
+                                                   uint64_t op_handle, bool is_begin_operation,
+                                                   bool record_use) {
     if (is_public_key_algorithm(auth_set)) {
         switch (purpose) {
         case KeyPurpose::ENCRYPT:
@@ -91,7 +92,7 @@ ErrorCode KeymasterEnforcement::AuthorizeOperation(const KeyPurpose purpose, con
     };
 
     if (is_begin_operation)
-        return AuthorizeBegin(purpose, keyid, auth_set, operation_params, auth_token);
+        return AuthorizeBegin(purpose, keyid, auth_set, operation_params, auth_token, record_use);
     else
         return AuthorizeUpdateOrFinish(auth_set, auth_token, op_handle);
 }
@@ -147,7 +148,8 @@ ErrorCode KeymasterEnforcement::AuthorizeUpdateOrFinish(const AuthorizationSet&
 ErrorCode KeymasterEnforcement::AuthorizeBegin(const KeyPurpose purpose, const km_id_t keyid,
                                                const AuthorizationSet& auth_set,
                                                const AuthorizationSet& operation_params,
-                                               NullOr<const HardwareAuthToken&> auth_token) {
+                                               NullOr<const HardwareAuthToken&> auth_token,
+                                               bool record_use) {
     // Find some entries that may be needed to handle KM_TAG_USER_SECURE_ID
     int auth_timeout_index = -1;
     int auth_type_index = -1;
@@ -343,6 +345,8 @@ ErrorCode KeymasterEnforcement::AuthorizeBegin(const KeyPurpose purpose, const k
         operation_params.Contains(Tag::NONCE))
         return ErrorCode::CALLER_NONCE_PROHIBITED;
 
+    if (!record_use) return ErrorCode::OK;
+
     if (min_ops_timeout != UINT32_MAX) {
         if (!access_time_map_.UpdateKeyAccessTime(keyid, get_current_time(), min_ops_timeout)) {
             ALOGE("Rate-limited keys table full.  Entries will time out.");
@@ -94,12 +94,15 @@ class KeymasterEnforcement {
      * Iterates through the authorization set and returns the corresponding keymaster error. Will
      * return KM_ERROR_OK if all criteria is met for the given purpose in the authorization set with
      * the given operation params and handle. Used for encrypt, decrypt sign, and verify.
+     *
+     * If record_use is false, a successful begin check is not counted as a use of the key, i.e., it
+     * affects neither KM_TAG_MIN_SECONDS_BETWEEN_OPS nor KM_TAG_MAX_USES_PER_BOOT.
      */
     ErrorCode AuthorizeOperation(const KeyPurpose purpose, const km_id_t keyid,
                                  const AuthorizationSet& auth_set,
                                  const AuthorizationSet& operation_params,
                                  const HardwareAuthToken& auth_token, uint64_t op_handle,
-                                 bool is_begin_operation);
+                                 bool is_begin_operation, bool record_use = true);
 
     /**
      * Iterates through the authorization set and returns the corresponding keymaster error. Will
@@ -109,7 +112,7 @@ class KeymasterEnforcement {
     ErrorCode AuthorizeBegin(const KeyPurpose purpose, const km_id_t keyid,
                              const AuthorizationSet& auth_set,
                              const AuthorizationSet& operation_params,
-                             NullOr<const HardwareAuthToken&> auth_token);
+                             NullOr<const HardwareAuthToken&> auth_token, bool record_use = true);
 
     /**
      * Iterates through the authorization set and returns the corresponding keymaster error. Will
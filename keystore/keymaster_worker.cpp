@@ -21,9 +21,11 @@
 #include "keystore_utils.h"
 
 #include <android-base/logging.h>
+#include <android-base/properties.h>
 
 #include <log/log_event_list.h>
 
+#include <private/android_filesystem_config.h>
 #include <private/android_logger.h>
 
 #include "KeyStore.h"
@@ -40,6 +42,20 @@ using namespace std::chrono;
 
 constexpr size_t kMaxOperations = 15;
 
+// Default per-uid share of kMaxOperations, overridable with ro.keystore.max_operations_per_uid.
+// System uids may use all slots.
+constexpr size_t kMaxOperationsPerUid = 8;
+
+// Default cap on non-pruneable operations up to which keys marked pruning exempt get their
+// operations started as non-pruneable, overridable with ro.keystore.max_pruning_exempt_operations.
+// The rest of kMaxOperations stays available for pruning.
+constexpr size_t kMaxPruningExemptOperations = 5;
+
+// How often begin() is retried after the HAL reported TOO_MANY_OPERATIONS, and the delay before
+// the first retry. The delay doubles with every attempt.
+constexpr size_t kMaxBeginRetries = 4;
+constexpr milliseconds kBeginRetryBaseDelay = 1ms;
+
 using AndroidKeymasterArguments = android::security::keymaster::KeymasterArguments;
 using android::security::keymaster::ExportResult;
 using android::security::keymaster::operationFailed;
@@ -93,7 +109,8 @@ void KeymasterWorker::logIfKeymasterVendorError(ErrorCode ec) const {
     keymasterDevice_->logIfKeymasterVendorError(ec);
 }
 
-void KeymasterWorker::deleteOldKeyOnUpgrade(const LockedKeyBlobEntry& blobfile, Blob keyBlob) {
+void KeymasterWorker::deleteOldKeyOnUpgrade(const LockedKeyBlobEntry& blobfile, Blob keyBlob,
+                                            const std::optional<KeyBlobEntry>& pending) {
     // if we got the blob successfully, we try and delete it from the keymaster device
     auto& dev = keymasterDevice_;
     uid_t uid = blobfile->uid();
@@ -110,6 +127,10 @@ void KeymasterWorker::deleteOldKeyOnUpgrade(const LockedKeyBlobEntry& blobfile,
         if (!success) {
             LOG(ERROR) << "Keymaster delete for key " << alias << " of uid " << uid << " failed";
         }
+        // A blob the device no longer recognizes has nothing left to delete.
+        if (success || ret == ErrorCode::INVALID_KEY_BLOB) {
+            KeyStore::completePendingDeletion(pending);
+        }
     }
 }
 
@@ -156,6 +177,8 @@ KeymasterWorker::upgradeKeyBlob(const LockedKeyBlobEntry& lockedEntry,
         newBlob.setEncrypted(blob.isEncrypted());
         newBlob.setSuperEncrypted(blob.isSuperEncrypted());
         newBlob.setCriticalToDeviceEncryption(blob.isCriticalToDeviceEncryption());
+        newBlob.setAutoDelete(blob.isAutoDelete());
+        newBlob.setPruningExempt(blob.isPruningExempt());
 
         error = keyStore_->put(lockedEntry, newBlob, charBlob);
         if (!error.isOk()) {
@@ -163,7 +186,11 @@ KeymasterWorker::upgradeKeyBlob(const LockedKeyBlobEntry& lockedEntry,
             return;
         }
 
-        deleteOldKeyOnUpgrade(lockedEntry, std::move(blob));
+        // The old blob is only queued for deletion once the upgraded blob is on disk. A crash in
+        // between may leak the old blob inside the Keymaster, but a pending deletion is never
+        // the only copy of a key.
+        auto pending = keyStore_->queuePendingDeletion(blob, lockedEntry->uid());
+        deleteOldKeyOnUpgrade(lockedEntry, std::move(blob), pending);
         blob = std::move(newBlob);
     };
 
@@ -336,6 +363,45 @@ KeymasterWorker::getAuthToken(const KeyCharacteristics& characteristics, uint64_
     return {rc, std::move(authToken)};
 }
 
+void KeymasterWorker::traceEnforcementFailure(const char* stage, uid_t owner, KeyPurpose purpose,
+                                              const KeyCharacteristics& characteristics,
+                                              const KeyStoreServiceReturnCode& rc) {
+    auto& trace = keyStore_->getEnforcementTrace();
+    if (!trace.enabled()) return;
+
+    AuthorizationSet key_auths(characteristics.hardwareEnforced);
+    key_auths.append(characteristics.softwareEnforced.begin(),
+                     characteristics.softwareEnforced.end());
+
+    EnforcementFailure failure = {};
+    failure.when = time(nullptr);
+    failure.stage = stage;
+    failure.owner = owner;
+    failure.purpose = purpose;
+    for (const auto& param : key_auths) {
+        auto sid = authorizationValue(TAG_USER_SECURE_ID, param);
+        if (sid.isOk()) failure.secureIds.push_back(sid.value());
+    }
+    auto authType = key_auths.GetTagValue(TAG_USER_AUTH_TYPE);
+    if (authType.isOk()) failure.authType = authType.value();
+    auto authTimeout = key_auths.GetTagValue(TAG_AUTH_TIMEOUT);
+    if (authTimeout.isOk()) failure.authTimeout = authTimeout.value();
+    failure.unlockedDeviceRequired = key_auths.Contains(TAG_UNLOCKED_DEVICE_REQUIRED);
+    failure.deviceLocked = keyStore_->getEnforcementPolicy().is_device_locked(get_user_id(owner));
+
+    auto newest = keyStore_->getAuthTokenTable().FindNewestTokenForKey(key_auths);
+    if (newest) {
+        const auto& [token, age] = *newest;
+        failure.tokenFound = true;
+        failure.tokenUserId = token.userId;
+        failure.tokenAuthenticatorId = token.authenticatorId;
+        failure.tokenAgeSeconds = age;
+    }
+    failure.result = rc.getErrorCode();
+
+    trace.record(std::move(failure));
+}
+
 KeyStoreServiceReturnCode KeymasterWorker::abort(const sp<IBinder>& token,
                                                  ResponseCode reason_for_abort) {
     auto op = operationMap_.removeOperation(token, false /* wasOpSuccessful */,
@@ -349,10 +415,14 @@ KeyStoreServiceReturnCode KeymasterWorker::abort(const sp<IBinder>& token,
 }
 
 /**
- * Prune the oldest pruneable operation.
+ * Prune the longest idle pruneable operation of the uid that holds the most operations, so that
+ * callers with few operations are not starved by a greedy one.
  */
 bool KeymasterWorker::pruneOperation() {
-    sp<IBinder> oldest = operationMap_.getOldestPruneableOperation();
+    return pruneOperation(operationMap_.getPruneCandidate());
+}
+
+bool KeymasterWorker::pruneOperation(const sp<IBinder>& oldest) {
     ALOGD("Trying to prune operation %p", oldest.get());
     size_t op_count_before_abort = operationMap_.getOperationCount();
     // We mostly ignore errors from abort() because all we care about is whether at least
@@ -366,25 +436,43 @@ bool KeymasterWorker::pruneOperation() {
     return true;
 }
 
+SlowCallTracker::Timer KeymasterWorker::timeCall(const char* api) {
+    return keyStore_->getSlowCallTracker().start(api, keymasterDevice_->halVersion().securityLevel);
+}
+
 // My IDE defines "CAPTURE_MOVE(x) x" because it does not understand generalized lambda captures.
 // It should never be redefined by a build system though.
 #ifndef CAPTURE_MOVE
 #define CAPTURE_MOVE(x) x = std::move(x)
 #endif
 
-void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken, Blob keyBlob,
-                            Blob charBlob, bool pruneable, KeyPurpose purpose,
+void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, uid_t callingUid,
+                            sp<IBinder> appToken, Blob keyBlob, Blob charBlob, bool pruneable,
+                            KeyPurpose purpose,
                             AuthorizationSet opParams, hidl_vec<uint8_t> entropy,
+                            std::optional<std::chrono::steady_clock::time_point> deadline,
                             worker_begin_cb worker_cb) {
 
-    Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), CAPTURE_MOVE(appToken),
+    Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), callingUid, CAPTURE_MOVE(appToken),
                         CAPTURE_MOVE(keyBlob), CAPTURE_MOVE(charBlob), pruneable, purpose,
-                        CAPTURE_MOVE(opParams), CAPTURE_MOVE(entropy),
+                        CAPTURE_MOVE(opParams), CAPTURE_MOVE(entropy), deadline,
                         CAPTURE_MOVE(worker_cb)]() mutable {
         // Concurrently executed
+        auto callTimer = timeCall("begin");
 
         auto& dev = keymasterDevice_;
 
+        auto deadlineExpired = [&deadline] {
+            return deadline && std::chrono::steady_clock::now() >= *deadline;
+        };
+
+        // The request may have waited in the queue behind slow requests. If the caller has
+        // already given up there is no point in starting the operation.
+        if (deadlineExpired()) {
+            ALOGW("begin deadline expired before the request was dequeued");
+            return worker_cb(operationFailed(ResponseCode::BACKEND_BUSY));
+        }
+
         KeyCharacteristics characteristics;
 
         {
@@ -415,6 +503,7 @@ void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken
         // the client will need to authorize that operation before calling
         // update. Any other auth issues stop here.
         if (!authRc.isOk() && authRc != ResponseCode::OP_AUTH_NEEDED) {
+            traceEnforcementFailure("begin", callingUid, purpose, characteristics, authRc);
             return worker_cb(operationFailed(authRc));
         }
 
@@ -442,18 +531,57 @@ void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken
             purpose, *keyid, key_auths, opParams, authToken, 0 /* op_handle */,
             true /* is_begin_operation */);
         if (!rc.isOk()) {
+            traceEnforcementFailure("begin", callingUid, purpose, characteristics, rc);
             return worker_cb(operationFailed(rc));
         }
 
-        // If there are more than kMaxOperations, abort the oldest operation that was started as
-        // pruneable.
+        // A single uid must not be able to take all slots. If it is at its limit, make room by
+        // pruning its own oldest pruneable operation instead of somebody else's. Forced, i.e.,
+        // non-pruneable, operations are not subject to the limit.
+        static const size_t maxOperationsPerUid = android::base::GetUintProperty<size_t>(
+            "ro.keystore.max_operations_per_uid", kMaxOperationsPerUid);
+        size_t uidLimit = get_app_id(callingUid) == AID_SYSTEM || !pruneable
+                              ? kMaxOperations
+                              : maxOperationsPerUid;
+        while (operationMap_.getOperationCountForUid(callingUid) >= uidLimit) {
+            auto oldest = operationMap_.getOldestPruneableOperationForUid(callingUid);
+            if (!oldest || !pruneOperation(oldest)) {
+                ALOGW("uid %d reached its limit of %zu concurrent operations", callingUid,
+                      uidLimit);
+                return worker_cb(operationFailed(ErrorCode::TOO_MANY_OPERATIONS));
+            }
+        }
+
+        // If there are more than kMaxOperations, prune an operation of the greediest uid.
         while (operationMap_.getOperationCount() >= kMaxOperations) {
             ALOGD("Reached or exceeded concurrent operations limit");
+            if (deadlineExpired()) {
+                return worker_cb(operationFailed(ResponseCode::BACKEND_BUSY));
+            }
             if (!pruneOperation()) {
                 break;
             }
         }
 
+        // Operations on pruning exempt keys are not pruned, unless so many operations are already
+        // non-pruneable that pruning could no longer make room for anybody else.
+        if (pruneable && keyBlob.isPruningExempt()) {
+            static const size_t maxExemptOperations = android::base::GetUintProperty<size_t>(
+                "ro.keystore.max_pruning_exempt_operations", kMaxPruningExemptOperations);
+            if (operationMap_.getNonPruneableOperationCount() < maxExemptOperations) {
+                pruneable = false;
+            } else {
+                ALOGW("Reached the limit of %zu non-pruneable operations, starting operation on "
+                      "pruning exempt key of uid %d as pruneable",
+                      maxExemptOperations, lockedEntry->uid());
+            }
+        }
+
+        if (deadlineExpired()) {
+            ALOGW("begin deadline expired while preparing the operation");
+            return worker_cb(operationFailed(ResponseCode::BACKEND_BUSY));
+        }
+
         android::security::keymaster::OperationResult result;
 
         auto hidlCb = [&](ErrorCode ret, const hidl_vec<KeyParameter>& outParams,
@@ -470,7 +598,12 @@ void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken
             result.outParams = outParams;
         };
 
+        size_t retries = 0;
         do {
+            if (retries > 0) {
+                // Give the HAL a moment to actually release the slot of the pruned operation.
+                std::this_thread::sleep_for(kBeginRetryBaseDelay * (1 << (retries - 1)));
+            }
             rc = KS_HANDLE_HIDL_ERROR(dev, dev->begin(purpose, blob2hidlVec(keyBlob),
                                                       opParams.hidl_data(), authToken, hidlCb));
             if (!rc.isOk()) {
@@ -491,9 +624,22 @@ void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken
                     return worker_cb(operationFailed(ResponseCode::SYSTEM_ERROR));
                 }
             }
-            // If there are too many operations abort the oldest operation that was
-            // started as pruneable and try again.
-        } while (result.resultCode == ErrorCode::TOO_MANY_OPERATIONS && pruneOperation());
+            // If there are too many operations prune an operation of the greediest uid and
+            // try again.
+        } while (result.resultCode == ErrorCode::TOO_MANY_OPERATIONS &&
+                 retries++ < kMaxBeginRetries && !deadlineExpired() && pruneOperation());
+
+        if (result.resultCode == ErrorCode::TOO_MANY_OPERATIONS && deadlineExpired()) {
+            ALOGW("begin deadline expired while waiting for an operation slot");
+            return worker_cb(operationFailed(ResponseCode::BACKEND_BUSY));
+        }
+
+        if (result.resultCode == ErrorCode::TOO_MANY_OPERATIONS && retries > kMaxBeginRetries) {
+            size_t hits = ++beginRetryLimitHits_;
+            LOG(WARNING) << "begin() still gets TOO_MANY_OPERATIONS after " << kMaxBeginRetries
+                         << " retries (limit hit " << hits << " times)";
+            return worker_cb(operationFailed(ResponseCode::BACKEND_BUSY));
+        }
 
         rc = result.resultCode;
         if (!rc.isOk()) {
@@ -504,7 +650,8 @@ void KeymasterWorker::begin(LockedKeyBlobEntry lockedEntry, sp<IBinder> appToken
         // It is safe to use characteristics after the following line but it will be empty.
         sp<IBinder> operationToken =
             operationMap_.addOperation(result.handle, *keyid, purpose, dev, appToken,
-                                       std::move(characteristics), opParams.hidl_data(), pruneable);
+                                       std::move(characteristics), opParams.hidl_data(), pruneable,
+                                       callingUid);
         assert(characteristics.hardwareEnforced.size() == 0);
         assert(characteristics.softwareEnforced.size() == 0);
         result.token = operationToken;
@@ -589,10 +736,25 @@ class Finalize {
 
 }  // namespace
 
+/*
+ * Operation state lives in the Keymaster only, so an operation cannot outlive the Keymaster process
+ * that began it. A dead Keymaster is reported as BACKEND_RESTARTED rather than as a generic error,
+ * so that callers know that the operation, not their input, was the problem.
+ */
+static KeyStoreServiceReturnCode operationHidlError(const std::shared_ptr<Operation>& op,
+                                                    const Return<void>& ret) {
+    if (ret.isDeadObject()) {
+        LOG(ERROR) << "Keymaster died during operation " << op->handle;
+        return ResponseCode::BACKEND_RESTARTED;
+    }
+    return KS_HANDLE_HIDL_ERROR(op->device, ret);
+}
+
 void KeymasterWorker::update(sp<IBinder> token, AuthorizationSet params, hidl_vec<uint8_t> data,
                              update_cb worker_cb) {
     Worker::addRequest([this, CAPTURE_MOVE(token), CAPTURE_MOVE(params), CAPTURE_MOVE(data),
                         CAPTURE_MOVE(worker_cb)]() {
+        auto callTimer = timeCall("update");
         KeyStoreServiceReturnCode rc;
         auto op = operationMap_.getOperation(token);
         if (!op) {
@@ -606,7 +768,10 @@ void KeymasterWorker::update(sp<IBinder> token, AuthorizationSet params, hidl_ve
         });
 
         rc = getOperationAuthTokenIfNeeded(op);
-        if (!rc.isOk()) return worker_cb(operationFailed(rc));
+        if (!rc.isOk()) {
+            traceEnforcementFailure("update", op->owner, op->purpose, op->characteristics, rc);
+            return worker_cb(operationFailed(rc));
+        }
 
         // Check that all key authorization policy requirements are met.
         AuthorizationSet key_auths(op->characteristics.hardwareEnforced);
@@ -616,7 +781,10 @@ void KeymasterWorker::update(sp<IBinder> token, AuthorizationSet params, hidl_ve
         rc = keyStore_->getEnforcementPolicy().AuthorizeOperation(op->purpose, op->keyid, key_auths,
                                                                   params, op->authToken, op->handle,
                                                                   false /* is_begin_operation */);
-        if (!rc.isOk()) return worker_cb(operationFailed(rc));
+        if (!rc.isOk()) {
+            traceEnforcementFailure("update", op->owner, op->purpose, op->characteristics, rc);
+            return worker_cb(operationFailed(rc));
+        }
 
         OperationResult result;
         auto hidlCb = [&](ErrorCode ret, uint32_t inputConsumed,
@@ -631,9 +799,12 @@ void KeymasterWorker::update(sp<IBinder> token, AuthorizationSet params, hidl_ve
             }
         };
 
-        rc = KS_HANDLE_HIDL_ERROR(op->device,
-                                  op->device->update(op->handle, params.hidl_data(), data,
-                                                     op->authToken, op->verificationToken, hidlCb));
+        auto updateStart = std::chrono::steady_clock::now();
+        rc = operationHidlError(op, op->device->update(op->handle, params.hidl_data(), data,
+                                                       op->authToken, op->verificationToken,
+                                                       hidlCb));
+        op->keymasterTime += std::chrono::steady_clock::now() - updateStart;
+        if (rc.isOk() && result.resultCode.isOk()) op->inputBytes += result.inputConsumed;
 
         // just a reminder: on success result->resultCode was set in the callback. So we only
         // overwrite it if there was a communication error indicated by the ErrorCode.
@@ -671,6 +842,7 @@ void KeymasterWorker::finish(sp<IBinder> token, AuthorizationSet params, hidl_ve
     Worker::addRequest([this, CAPTURE_MOVE(token), CAPTURE_MOVE(params), CAPTURE_MOVE(input),
                         CAPTURE_MOVE(signature), CAPTURE_MOVE(entropy),
                         CAPTURE_MOVE(worker_cb)]() mutable {
+        auto callTimer = timeCall("finish");
         KeyStoreServiceReturnCode rc;
         auto op = operationMap_.getOperation(token);
         if (!op) {
@@ -690,7 +862,10 @@ void KeymasterWorker::finish(sp<IBinder> token, AuthorizationSet params, hidl_ve
         }
 
         rc = getOperationAuthTokenIfNeeded(op);
-        if (!rc.isOk()) return worker_cb(operationFailed(rc));
+        if (!rc.isOk()) {
+            traceEnforcementFailure("finish", op->owner, op->purpose, op->characteristics, rc);
+            return worker_cb(operationFailed(rc));
+        }
 
         // Check that all key authorization policy requirements are met.
         AuthorizationSet key_auths(op->characteristics.hardwareEnforced);
@@ -710,7 +885,10 @@ void KeymasterWorker::finish(sp<IBinder> token, AuthorizationSet params, hidl_ve
         rc = keyStore_->getEnforcementPolicy().AuthorizeOperation(op->purpose, op->keyid, key_auths,
                                                                   params, op->authToken, op->handle,
                                                                   false /* is_begin_operation */);
-        if (!rc.isOk()) return worker_cb(operationFailed(rc));
+        if (!rc.isOk()) {
+            traceEnforcementFailure("finish", op->owner, op->purpose, op->characteristics, rc);
+            return worker_cb(operationFailed(rc));
+        }
 
         if (entropy.size()) {
             rc = KS_HANDLE_HIDL_ERROR(op->device, op->device->addRngEntropy(entropy));
@@ -730,9 +908,12 @@ void KeymasterWorker::finish(sp<IBinder> token, AuthorizationSet params, hidl_ve
             }
         };
 
-        rc = KS_HANDLE_HIDL_ERROR(op->device, op->device->finish(op->handle, params.hidl_data(),
-                                                                 input, signature, op->authToken,
-                                                                 op->verificationToken, hidlCb));
+        auto finishStart = std::chrono::steady_clock::now();
+        rc = operationHidlError(op, op->device->finish(op->handle, params.hidl_data(), input,
+                                                       signature, op->authToken,
+                                                       op->verificationToken, hidlCb));
+        op->keymasterTime += std::chrono::steady_clock::now() - finishStart;
+        if (rc.isOk() && result.resultCode.isOk()) op->inputBytes += input.size();
 
         if (rc.isOk()) {
             // inform the finalizer that the finish call went through
@@ -775,6 +956,10 @@ void KeymasterWorker::addRngEntropy(hidl_vec<uint8_t> data, addRngEntropy_cb _hi
     addRequest(&Keymaster::addRngEntropy, std::move(_hidl_cb), std::move(data));
 }
 
+void KeymasterWorker::earlyBootEnded(earlyBootEnded_cb _hidl_cb) {
+    addRequest(&Keymaster::earlyBootEnded, std::move(_hidl_cb));
+}
+
 namespace {
 bool containsTag(const hidl_vec<KeyParameter>& params, Tag tag) {
     return params.end() !=
@@ -791,6 +976,7 @@ void KeymasterWorker::generateKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPa
                                   hidl_vec<uint8_t> entropy, int flags, generateKey_cb worker_cb) {
     Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), CAPTURE_MOVE(keyParams),
                         CAPTURE_MOVE(entropy), CAPTURE_MOVE(worker_cb), flags]() mutable {
+        auto callTimer = timeCall("generateKey");
         KeyStoreServiceReturnCode rc =
             KS_HANDLE_HIDL_ERROR(keymasterDevice_, keymasterDevice_->addRngEntropy(entropy));
         if (!rc.isOk()) {
@@ -828,6 +1014,7 @@ void KeymasterWorker::generateKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPa
                 keyBlob.setSuperEncrypted(true);
             }
             keyBlob.setEncrypted(flags & KEYSTORE_FLAG_ENCRYPTED);
+            keyBlob.setAutoDelete(flags & KEYSTORE_FLAG_AUTO_DELETE);
 
             AuthorizationSet sw_enforced = keyParams;
             sw_enforced.Subtract(outCharacteristics.hardwareEnforced);
@@ -842,6 +1029,7 @@ void KeymasterWorker::generateKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPa
             Blob keyCharBlob;
             keyCharBlob.putKeyCharacteristics(outCharacteristics.hardwareEnforced, sw_enforced);
             error = keyStore_->put(lockedEntry, std::move(keyBlob), std::move(keyCharBlob));
+            if (error.isOk()) keyStore_->setTestKey(lockedEntry, flags & KEYSTORE_FLAG_TEST_KEY);
         };
 
         rc = KS_HANDLE_HIDL_ERROR(keymasterDevice_,
@@ -893,6 +1081,7 @@ void KeymasterWorker::getKeyCharacteristics(LockedKeyBlobEntry lockedEntry,
     Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), CAPTURE_MOVE(clientId),
                         CAPTURE_MOVE(appData), CAPTURE_MOVE(keyBlob), CAPTURE_MOVE(charBlob),
                         CAPTURE_MOVE(worker_cb)]() {
+        auto callTimer = timeCall("getKeyCharacteristics");
         auto result = createKeyCharacteristicsCache(lockedEntry, clientId, appData,
                                                     std::move(keyBlob), std::move(charBlob));
         return worker_cb(std::get<0>(result), std::move(std::get<1>(result)));
@@ -904,6 +1093,7 @@ void KeymasterWorker::importKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPara
                                 importKey_cb worker_cb) {
     Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), CAPTURE_MOVE(keyParams), keyFormat,
                         CAPTURE_MOVE(keyData), flags, CAPTURE_MOVE(worker_cb)]() mutable {
+        auto callTimer = timeCall("importKey");
         SecurityLevel securityLevel = keymasterDevice_->halVersion().securityLevel;
 
         // Fallback cannot be considered for Strongbox. Further versions restrictions are enforced
@@ -936,6 +1126,7 @@ void KeymasterWorker::importKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPara
                 keyBlob.setSuperEncrypted(true);
             }
             keyBlob.setEncrypted(flags & KEYSTORE_FLAG_ENCRYPTED);
+            keyBlob.setAutoDelete(flags & KEYSTORE_FLAG_AUTO_DELETE);
 
             AuthorizationSet sw_enforced = keyParams;
             sw_enforced.Subtract(outCharacteristics.hardwareEnforced);
@@ -950,6 +1141,7 @@ void KeymasterWorker::importKey(LockedKeyBlobEntry lockedEntry, hidl_vec<KeyPara
             Blob keyCharBlob;
             keyCharBlob.putKeyCharacteristics(outCharacteristics.hardwareEnforced, sw_enforced);
             error = keyStore_->put(lockedEntry, std::move(keyBlob), std::move(keyCharBlob));
+            if (error.isOk()) keyStore_->setTestKey(lockedEntry, flags & KEYSTORE_FLAG_TEST_KEY);
         };
 
         KeyStoreServiceReturnCode rc = KS_HANDLE_HIDL_ERROR(
@@ -1002,6 +1194,7 @@ void KeymasterWorker::importWrappedKey(LockedKeyBlobEntry wrappingLockedEntry,
                         CAPTURE_MOVE(unwrappingParams), CAPTURE_MOVE(wrappingBlob),
                         CAPTURE_MOVE(wrappingCharBlob), passwordSid, biometricSid,
                         CAPTURE_MOVE(worker_cb)]() mutable {
+        auto callTimer = timeCall("importWrappedKey");
         auto hidlWrappingKey = blob2hidlVec(wrappingBlob);
 
         SecurityLevel securityLevel = keymasterDevice_->halVersion().securityLevel;
@@ -1033,6 +1226,7 @@ void KeymasterWorker::importWrappedKey(LockedKeyBlobEntry wrappingLockedEntry,
             Blob keyCharBlob;
             keyCharBlob.putKeyCharacteristics(outCharacteristics.hardwareEnforced, sw_enforced);
             error = keyStore_->put(wrapppedLockedEntry, std::move(keyBlob), std::move(keyCharBlob));
+            if (error.isOk()) keyStore_->setTestKey(wrapppedLockedEntry, false);
         };
 
         KeyStoreServiceReturnCode rc = KS_HANDLE_HIDL_ERROR(
@@ -1071,6 +1265,7 @@ void KeymasterWorker::exportKey(LockedKeyBlobEntry lockedEntry, KeyFormat export
     Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), exportFormat, CAPTURE_MOVE(clientId),
                         CAPTURE_MOVE(appData), CAPTURE_MOVE(keyBlob), CAPTURE_MOVE(charBlob),
                         CAPTURE_MOVE(worker_cb)]() mutable {
+        auto callTimer = timeCall("exportKey");
         auto key = blob2hidlVec(keyBlob);
 
         ExportResult result;
@@ -1141,4 +1336,106 @@ void KeymasterWorker::binderDied(android::wp<IBinder> who) {
     });
 }
 
+void KeymasterWorker::reapIdleOperations(std::chrono::steady_clock::duration maxIdle) {
+    Worker::addRequest([this, maxIdle]() {
+        for (const auto& token : operationMap_.getIdleOperations(maxIdle)) {
+            LOG(WARNING) << "Aborting operation " << token.get() << " that was idle for too long";
+            abort(token, ResponseCode::OPERATION_EXPIRED);
+            keyStore_->expireOperationDevice(token, ResponseCode::OPERATION_EXPIRED);
+        }
+    });
+}
+
+void KeymasterWorker::replaceDevice(sp<Keymaster> dead, sp<Keymaster> replacement) {
+    Worker::addRequest([this, CAPTURE_MOVE(dead), CAPTURE_MOVE(replacement)]() {
+        if (keymasterDevice_ != dead) return;
+        for (const auto& token : operationMap_.getOperations()) {
+            auto op = operationMap_.removeOperation(
+                token, false /* wasOpSuccessful */,
+                static_cast<int32_t>(ResponseCode::BACKEND_RESTARTED));
+            if (op) keyStore_->getAuthTokenTable().MarkCompleted(op->handle);
+            keyStore_->expireOperationDevice(token, ResponseCode::BACKEND_RESTARTED);
+        }
+        keymasterDevice_ = std::move(replacement);
+        // make sure that hal version is cached.
+        LOG(INFO) << "Reconnected to restarted Keymaster with seclevel "
+                  << toString(keymasterDevice_->halVersion().securityLevel);
+    });
+}
+
+void KeymasterWorker::listOperations(listOperations_cb worker_cb) {
+    // The operation map is only ever touched on the worker thread.
+    Worker::addRequest([this, CAPTURE_MOVE(worker_cb)]() {
+        worker_cb(operationMap_.getOperationDescriptors());
+    });
+}
+
+void KeymasterWorker::checkKeyBlob(Blob keyBlob, checkKeyBlob_cb worker_cb) {
+    Worker::addRequest([this, CAPTURE_MOVE(keyBlob), CAPTURE_MOVE(worker_cb)]() {
+        auto& dev = keymasterDevice_;
+        ErrorCode error = ErrorCode::OK;
+        auto hidlCb = [&](ErrorCode ret, const KeyCharacteristics&) { error = ret; };
+        KeyStoreServiceReturnCode rc = KS_HANDLE_HIDL_ERROR(
+            dev, dev->getKeyCharacteristics(blob2hidlVec(keyBlob), {}, {}, hidlCb));
+        if (!rc.isOk()) return worker_cb(rc);
+
+        if (error == ErrorCode::KEY_REQUIRES_UPGRADE) error = ErrorCode::OK;
+        worker_cb(error);
+    });
+}
+
+void KeymasterWorker::getHalKeyCharacteristics(Blob keyBlob,
+                                               getHalKeyCharacteristics_cb worker_cb) {
+    Worker::addRequest([this, CAPTURE_MOVE(keyBlob), CAPTURE_MOVE(worker_cb)]() {
+        auto& dev = keymasterDevice_;
+        ErrorCode error = ErrorCode::OK;
+        KeyCharacteristics characteristics;
+        auto hidlCb = [&](ErrorCode ret, const KeyCharacteristics& keyCharacteristics) {
+            dev->logIfKeymasterVendorError(ret);
+            error = ret;
+            if (error == ErrorCode::OK) characteristics = keyCharacteristics;
+        };
+        KeyStoreServiceReturnCode rc = KS_HANDLE_HIDL_ERROR(
+            dev, dev->getKeyCharacteristics(blob2hidlVec(keyBlob), {}, {}, hidlCb));
+        if (!rc.isOk()) return worker_cb(rc, {});
+        worker_cb(error, std::move(characteristics));
+    });
+}
+
+void KeymasterWorker::checkKeyAvailability(LockedKeyBlobEntry lockedEntry, Blob keyBlob,
+                                           Blob charBlob, KeyPurpose purpose,
+                                           checkKeyAvailability_cb worker_cb) {
+    Worker::addRequest([this, CAPTURE_MOVE(lockedEntry), CAPTURE_MOVE(keyBlob),
+                        CAPTURE_MOVE(charBlob), purpose, CAPTURE_MOVE(worker_cb)]() mutable {
+        KeyStoreServiceReturnCode rc;
+        KeyCharacteristics characteristics;
+        std::tie(rc, characteristics, keyBlob, charBlob) =
+            createKeyCharacteristicsCache(lockedEntry, {}, {}, std::move(keyBlob),
+                                          std::move(charBlob));
+        if (!rc.isOk()) return worker_cb(rc);
+
+        KeyStoreServiceReturnCode authRc;
+        HardwareAuthToken authToken;
+        std::tie(authRc, authToken) = getAuthToken(characteristics, 0 /* no challenge */, purpose,
+                                                   /*failOnTokenMissing*/ false);
+        if (!authRc.isOk() && authRc != ResponseCode::OP_AUTH_NEEDED) return worker_cb(authRc);
+
+        auto keyid = KeymasterEnforcement::CreateKeyId(blob2hidlVec(keyBlob));
+        if (!keyid) {
+            ALOGE("Failed to create a key ID for authorization checking.");
+            return worker_cb(ErrorCode::UNKNOWN_ERROR);
+        }
+
+        AuthorizationSet key_auths = characteristics.hardwareEnforced;
+        key_auths.append(characteristics.softwareEnforced.begin(),
+                         characteristics.softwareEnforced.end());
+
+        rc = keyStore_->getEnforcementPolicy().AuthorizeOperation(
+            purpose, *keyid, key_auths, {} /* operation_params */, authToken, 0 /* op_handle */,
+            true /* is_begin_operation */, false /* record_use */);
+        if (!rc.isOk()) return worker_cb(rc);
+        worker_cb(authRc);
+    });
+}
+
 }  // namespace keystore
@@ -18,6 +18,8 @@
 #ifndef KEYSTORE_KEYMASTER_WORKER_H_
 #define KEYSTORE_KEYMASTER_WORKER_H_
 
+#include <atomic>
+#include <chrono>
 #include <condition_variable>
 #include <functional>
 #include <keymasterV4_1/Keymaster.h>
@@ -37,6 +39,7 @@
 
 #include "blob.h"
 #include "operation.h"
+#include "slow_call_tracker.h"
 
 namespace keystore {
 
@@ -134,6 +137,9 @@ class KeymasterWorker : protected Worker {
     sp<Keymaster> keymasterDevice_;
     OperationMap operationMap_;
     KeyStore* keyStore_;
+    // Number of begin() calls that gave up on TOO_MANY_OPERATIONS. Only written on the worker
+    // thread.
+    std::atomic<size_t> beginRetryLimitHits_ = 0;
 
     template <typename KMFn, typename ErrorType, typename... Args, size_t... I>
     void unwrap_tuple(KMFn kmfn, std::function<void(ErrorType)> cb,
@@ -169,7 +175,8 @@ class KeymasterWorker : protected Worker {
         });
     }
 
-    void deleteOldKeyOnUpgrade(const LockedKeyBlobEntry& blobfile, Blob keyBlob);
+    void deleteOldKeyOnUpgrade(const LockedKeyBlobEntry& blobfile, Blob keyBlob,
+                               const std::optional<KeyBlobEntry>& pending);
     std::tuple<KeyStoreServiceReturnCode, Blob>
     upgradeKeyBlob(const LockedKeyBlobEntry& lockedEntry, const AuthorizationSet& params);
     std::tuple<KeyStoreServiceReturnCode, KeyCharacteristics, Blob, Blob>
@@ -190,9 +197,24 @@ class KeymasterWorker : protected Worker {
     getAuthToken(const KeyCharacteristics& characteristics, uint64_t handle, KeyPurpose purpose,
                  bool failOnTokenMissing = true);
 
+    /**
+     * Records an operation that enforcement refused in the enforcement trace, together with the
+     * key's authentication requirements and the state they were checked against.
+     */
+    void traceEnforcementFailure(const char* stage, uid_t owner, KeyPurpose purpose,
+                                 const KeyCharacteristics& characteristics,
+                                 const KeyStoreServiceReturnCode& rc);
+
+    /**
+     * Starts timing a request for api against the slow call thresholds. The request is recorded
+     * when the returned timer goes out of scope.
+     */
+    SlowCallTracker::Timer timeCall(const char* api);
+
     KeyStoreServiceReturnCode abort(const sp<IBinder>& token, ResponseCode reason_for_abort);
 
     bool pruneOperation();
+    bool pruneOperation(const sp<IBinder>& token);
 
     KeyStoreServiceReturnCode getOperationAuthTokenIfNeeded(std::shared_ptr<Operation> op);
 
@@ -205,9 +227,11 @@ class KeymasterWorker : protected Worker {
     void logIfKeymasterVendorError(ErrorCode ec) const;
 
     using worker_begin_cb = std::function<void(::android::security::keymaster::OperationResult)>;
-    void begin(LockedKeyBlobEntry, sp<IBinder> appToken, Blob keyBlob, Blob charBlob,
-               bool pruneable, KeyPurpose purpose, AuthorizationSet opParams,
-               hidl_vec<uint8_t> entropy, worker_begin_cb worker_cb);
+    void begin(LockedKeyBlobEntry, uid_t callingUid, sp<IBinder> appToken, Blob keyBlob,
+               Blob charBlob, bool pruneable, KeyPurpose purpose, AuthorizationSet opParams,
+               hidl_vec<uint8_t> entropy,
+               std::optional<std::chrono::steady_clock::time_point> deadline,
+               worker_begin_cb worker_cb);
 
     using update_cb = std::function<void(::android::security::keymaster::OperationResult)>;
     void update(sp<IBinder> token, AuthorizationSet params, hidl_vec<uint8_t> data,
@@ -239,6 +263,10 @@ class KeymasterWorker : protected Worker {
     using addRngEntropy_cb = MakeKeymasterWorkerCB_t<Return<ErrorCode>>;
     void addRngEntropy(hidl_vec<uint8_t> data, addRngEntropy_cb _hidl_cb);
 
+    using earlyBootEnded_cb =
+        MakeKeymasterWorkerCB_t<Return<::android::hardware::keymaster::V4_1::ErrorCode>>;
+    void earlyBootEnded(earlyBootEnded_cb _hidl_cb);
+
     using generateKey_cb = std::function<void(
         KeyStoreServiceReturnCode, ::android::hardware::keymaster::V4_0::KeyCharacteristics)>;
     void generateKey(LockedKeyBlobEntry, hidl_vec<KeyParameter> keyParams,
@@ -285,6 +313,49 @@ class KeymasterWorker : protected Worker {
 
     void binderDied(android::wp<IBinder> who);
 
+    /**
+     * Aborts the operations that have not been used for longer than maxIdle. Their clients get
+     * OPERATION_EXPIRED when they try to use them again.
+     */
+    void reapIdleOperations(std::chrono::steady_clock::duration maxIdle);
+
+    // The number of begin() calls that ran out of retries on TOO_MANY_OPERATIONS.
+    size_t getBeginRetryLimitHits() const { return beginRetryLimitHits_; }
+
+    /**
+     * Makes the worker use replacement if it currently uses dead. The operations begun on dead
+     * are dropped without calling into either device; their clients get BACKEND_RESTARTED when
+     * they try to use them again.
+     */
+    void replaceDevice(sp<Keymaster> dead, sp<Keymaster> replacement);
+
+    using listOperations_cb = std::function<void(std::vector<OperationDescriptor>)>;
+    void listOperations(listOperations_cb worker_cb);
+
+    /**
+     * Asks the HAL whether it still accepts keyBlob. Reports OK for blobs that merely require an
+     * upgrade. The blob is not upgraded and no cache file is written.
+     */
+    using checkKeyBlob_cb = std::function<void(KeyStoreServiceReturnCode)>;
+    void checkKeyBlob(Blob keyBlob, checkKeyBlob_cb worker_cb);
+
+    /**
+     * Asks the HAL for the characteristics of keyBlob, bypassing the characteristics cache. Keys
+     * that require an upgrade report KEY_REQUIRES_UPGRADE; they are not upgraded.
+     */
+    using getHalKeyCharacteristics_cb =
+        std::function<void(KeyStoreServiceReturnCode, KeyCharacteristics)>;
+    void getHalKeyCharacteristics(Blob keyBlob, getHalKeyCharacteristics_cb worker_cb);
+
+    /**
+     * Runs the authorization checks of begin for purpose without beginning an operation. Reports
+     * NO_ERROR if begin would currently be authorized, OP_AUTH_NEEDED if the key requires per
+     * operation authentication, and otherwise the error begin would fail with.
+     */
+    using checkKeyAvailability_cb = std::function<void(KeyStoreServiceReturnCode)>;
+    void checkKeyAvailability(LockedKeyBlobEntry lockedEntry, Blob keyBlob, Blob charBlob,
+                              KeyPurpose purpose, checkKeyAvailability_cb worker_cb);
+
     const Keymaster::VersionResult& halVersion() { return keymasterDevice_->halVersion(); }
 };
 
@@ -63,6 +63,8 @@ void PrintUsageAndExit() {
            "          add-entropy --input=<entropy> [--seclevel=software|strongbox|tee(default)]\n"
            "          generate --name=<key_name> [--seclevel=software|strongbox|tee(default)]\n"
            "          get-chars --name=<key_name>\n"
+           "          diff-chars --name=<key_name>\n"
+           "          check-consistency [--repair]\n"
            "          export --name=<key_name>\n"
            "          delete --name=<key_name>\n"
            "          delete-all\n"
@@ -332,6 +334,56 @@ int GetCharacteristics(const std::string& name) {
     return result.getErrorCode();
 }
 
+int DiffCharacteristics(const std::string& name) {
+    sp<android::IServiceManager> sm = android::defaultServiceManager();
+    sp<android::IBinder> binder = sm->getService(String16("android.security.keystore"));
+    sp<IKeystoreService> service = android::interface_cast<IKeystoreService>(binder);
+    if (service == nullptr) {
+        printf("error: could not connect to keystore service.\n");
+        return 1;
+    }
+
+    std::vector<std::string> differences;
+    int32_t aidl_return;
+    android::binder::Status status = service->diffKeyCharacteristics(
+        String16(name.data(), name.size()), -1 /* uid */, &differences, &aidl_return);
+    if (!status.isOk()) {
+        printf("DiffCharacteristics failed with binder status '%s'.\n",
+               status.toString8().c_str());
+        return 1;
+    }
+    printf("DiffCharacteristics: %d\n", aidl_return);
+    if (aidl_return != static_cast<int32_t>(ResponseCode::NO_ERROR)) return aidl_return;
+    if (differences.empty()) printf("No differences.\n");
+    for (const auto& difference : differences) {
+        printf("  %s\n", difference.c_str());
+    }
+    return 0;
+}
+
+int CheckConsistency(bool repair) {
+    sp<android::IServiceManager> sm = android::defaultServiceManager();
+    sp<android::IBinder> binder = sm->getService(String16("android.security.keystore"));
+    sp<IKeystoreService> service = android::interface_cast<IKeystoreService>(binder);
+    if (service == nullptr) {
+        printf("error: could not connect to keystore service.\n");
+        return 1;
+    }
+
+    std::vector<std::string> report;
+    int32_t aidl_return;
+    android::binder::Status status = service->checkConsistency(repair, &report, &aidl_return);
+    if (!status.isOk()) {
+        printf("CheckConsistency failed with binder status '%s'.\n", status.toString8().c_str());
+        return 1;
+    }
+    printf("CheckConsistency: %d\n", aidl_return);
+    for (const auto& line : report) {
+        printf("  %s\n", line.c_str());
+    }
+    return aidl_return;
+}
+
 int ExportKey(const std::string& name) {
     std::unique_ptr<KeystoreClient> keystore = CreateKeystoreInstance();
     std::string data;
@@ -632,6 +684,10 @@ int main(int argc, char** argv) {
                            command_line->HasSwitch("auth_bound"));
     } else if (args[0] == "get-chars") {
         return GetCharacteristics(command_line->GetSwitchValueASCII("name"));
+    } else if (args[0] == "diff-chars") {
+        return DiffCharacteristics(command_line->GetSwitchValueASCII("name"));
+    } else if (args[0] == "check-consistency") {
+        return CheckConsistency(command_line->HasSwitch("repair"));
     } else if (args[0] == "export") {
         return ExportKey(command_line->GetSwitchValueASCII("name"));
     } else if (args[0] == "delete") {
@@ -19,6 +19,7 @@
 
 #include <time.h>
 
+#include "key_datetime.h"
 #include "keymaster_enforcement.h"
 
 namespace keystore {
@@ -39,38 +40,17 @@ class KeystoreKeymasterEnforcement : public KeymasterEnforcement {
     }
 
     bool activation_date_valid(uint64_t activation_date) const override {
-        time_t now = time(nullptr);
-        if (now == static_cast<time_t>(-1)) {
-            // Failed to obtain current time -- fail safe: activation_date hasn't yet occurred.
-            return false;
-        } else if (now < 0) {
-            // Current time is prior to start of the epoch -- activation_date hasn't yet occurred.
-            return false;
-        }
-
-        // time(NULL) returns seconds since epoch and "loses" milliseconds information. We thus add
-        // 999 ms to now_date to avoid a situation where an activation_date of up to 999ms in the
-        // past may still be considered to still be in the future. This can be removed once
-        // time(NULL) is replaced by a millisecond-precise source of time.
-        uint64_t now_date = static_cast<uint64_t>(now) * 1000 + 999;
-        return now_date >= activation_date;
+        auto now = currentDateTime();
+        // Current time is prior to start of the epoch -- activation_date hasn't yet occurred.
+        if (!now) return false;
+        return *now >= dateTimeFromParameter(activation_date);
     }
 
     bool expiration_date_passed(uint64_t expiration_date) const override {
-        time_t now = time(nullptr);
-        if (now == static_cast<time_t>(-1)) {
-            // Failed to obtain current time -- fail safe: expiration_date has passed.
-            return true;
-        } else if (now < 0) {
-            // Current time is prior to start of the epoch: expiration_date hasn't yet occurred.
-            return false;
-        }
-
-        // time(NULL) returns seconds since epoch and "loses" milliseconds information. As a result,
-        // expiration_date of up to 999 ms in the past may still be considered in the future. This
-        // is OK.
-        uint64_t now_date = static_cast<uint64_t>(now) * 1000;
-        return now_date > expiration_date;
+        auto now = currentDateTime();
+        // Current time is prior to start of the epoch: expiration_date hasn't yet occurred.
+        if (!now) return false;
+        return *now > dateTimeFromParameter(expiration_date);
     }
 
     bool auth_token_timed_out(const HardwareAuthToken&, uint32_t) const {
@@ -97,6 +77,13 @@ class KeystoreKeymasterEnforcement : public KeymasterEnforcement {
         mIsDeviceLockedForUser[userId] = isLocked;
     }
 
+    // Forgets the lock state of a removed user, so that a new user with the same id starts out
+    // locked.
+    void forget_device_locked(int32_t userId) {
+        std::lock_guard<std::mutex> lock(is_device_locked_for_user_map_lock_);
+        mIsDeviceLockedForUser.erase(userId);
+    }
+
   private:
     mutable std::mutex is_device_locked_for_user_map_lock_;
     std::map<int32_t, bool> mIsDeviceLockedForUser;
@@ -17,6 +17,8 @@
 #define LOG_TAG "keystore"
 
 #include <android-base/logging.h>
+#include <android-base/properties.h>
+#include <android-base/stringprintf.h>
 #include <android/hidl/manager/1.2/IServiceManager.h>
 #include <android/security/keystore/IKeystoreService.h>
 #include <binder/IPCThreadState.h>
@@ -25,6 +27,11 @@
 #include <keymasterV4_1/Keymaster4.h>
 #include <utils/StrongPointer.h>
 
+#include <functional>
+#include <map>
+#include <mutex>
+#include <thread>
+
 #include <keystore/keystore_hidl_support.h>
 #include <keystore/keystore_return_types.h>
 
@@ -53,6 +60,99 @@ using ::keystore::keymaster::support::Keymaster4;
 
 using keystore::KeymasterDevices;
 
+/*
+ * A Keymaster HAL that dies takes all of its operations with it, and the proxy keystore holds stays
+ * dead even after the HAL has been restarted. The recipient fetches the restarted HAL and has the
+ * key store swap it in for the dead one, so that auth tokens, unlock state and the operations of
+ * other Keymasters survive.
+ */
+class KeymasterDeathRecipient : public android::hardware::hidl_death_recipient {
+  public:
+    using Reconnect = std::function<sp<Keymaster>()>;
+
+    /* Watches hal, the HAL behind device. reconnect fetches the restarted HAL and watches it. */
+    void watch(const sp<android::hidl::base::V1_0::IBase>& hal, sp<Keymaster> device,
+               Reconnect reconnect) {
+        std::lock_guard<std::mutex> lock(mutex_);
+        uint64_t cookie = nextCookie_++;
+        watched_[cookie] = {std::move(device), std::move(reconnect)};
+        hal->linkToDeath(this, cookie);
+    }
+
+    void setKeyStore(sp<keystore::KeyStore> keyStore) {
+        std::lock_guard<std::mutex> lock(mutex_);
+        keyStore_ = std::move(keyStore);
+    }
+
+    void serviceDied(uint64_t cookie,
+                     const android::wp<android::hidl::base::V1_0::IBase>& /* who */) override {
+        Watched watched;
+        sp<keystore::KeyStore> keyStore;
+        {
+            std::lock_guard<std::mutex> lock(mutex_);
+            auto it = watched_.find(cookie);
+            if (it == watched_.end()) return;
+            watched = std::move(it->second);
+            watched_.erase(it);
+            keyStore = keyStore_;
+        }
+        if (!keyStore) {
+            // Nothing has been built on top of the device yet. The restarted keystore enumerates
+            // the Keymasters anew.
+            LOG(ERROR) << "Keymaster HAL died during startup, exiting";
+            _exit(1);
+        }
+        LOG(ERROR) << "Keymaster HAL died, reconnecting";
+        // getService waits for the HAL to come back, which must not hold up this hwbinder thread.
+        std::thread([watched = std::move(watched), keyStore = std::move(keyStore)] {
+            keyStore->replaceKeymaster(watched.device, watched.reconnect());
+        }).detach();
+    }
+
+  private:
+    struct Watched {
+        sp<Keymaster> device;
+        Reconnect reconnect;
+    };
+
+    std::mutex mutex_;
+    std::map<uint64_t, Watched> watched_;
+    uint64_t nextCookie_ = 0;
+    sp<keystore::KeyStore> keyStore_;
+};
+
+static const sp<KeymasterDeathRecipient>& keymasterDeathRecipient() {
+    static const sp<KeymasterDeathRecipient> recipient = new KeymasterDeathRecipient();
+    return recipient;
+}
+
+template <typename Wrapper>
+void watchKeymaster(const sp<typename Wrapper::WrappedIKeymasterDevice>& device,
+                    const sp<Keymaster>& kmDevice, const hidl_string& name) {
+    // Passthrough HALs live in this process and cannot die separately.
+    if (!device->isRemote()) return;
+    keymasterDeathRecipient()->watch(device, kmDevice, [name]() -> sp<Keymaster> {
+        auto restarted = Wrapper::WrappedIKeymasterDevice::getService(name);
+        CHECK(restarted) << "Failed to get restarted service for \""
+                         << Wrapper::WrappedIKeymasterDevice::descriptor
+                         << "\" with interface name \"" << name << "\"";
+        sp<Keymaster> kmDevice(new Wrapper(restarted, name));
+        watchKeymaster<Wrapper>(restarted, kmDevice, name);
+        return kmDevice;
+    });
+}
+
+/* Devices may expose several Keymaster instances of the same security level, e.g., virtual
+ * instances on emulators. ro.keystore.keymaster.<software|tee|strongbox>_instance names the
+ * instance to use for a security level. Without it the first instance enumerated wins. */
+static std::string configuredInstance(SecurityLevel securityLevel) {
+    const char* level = "software";
+    if (securityLevel == SecurityLevel::TRUSTED_ENVIRONMENT) level = "tee";
+    if (securityLevel == SecurityLevel::STRONGBOX) level = "strongbox";
+    return android::base::GetProperty(
+        android::base::StringPrintf("ro.keystore.keymaster.%s_instance", level), "");
+}
+
 template <typename Wrapper>
 KeymasterDevices enumerateKeymasterDevices(IServiceManager* serviceManager) {
     KeymasterDevices result;
@@ -75,7 +175,11 @@ KeymasterDevices enumerateKeymasterDevices(IServiceManager* serviceManager) {
                     << "Security level of \"" << Wrapper::WrappedIKeymasterDevice::descriptor
                     << "\" with interface name \"" << name << "\" out of range";
                 auto& deviceSlot = result[securityLevel];
-                if (deviceSlot) {
+                if (deviceSlot && name == configuredInstance(securityLevel)) {
+                    LOG(INFO) << "Using configured interface name \"" << name
+                              << "\" for security level " << toString(securityLevel);
+                    deviceSlot = kmDevice;
+                } else if (deviceSlot) {
                     if (!fail_silent) {
                         LOG(WARNING) << "Implementation of \""
                                      << Wrapper::WrappedIKeymasterDevice::descriptor
@@ -86,6 +190,7 @@ KeymasterDevices enumerateKeymasterDevices(IServiceManager* serviceManager) {
                 } else {
                     deviceSlot = kmDevice;
                 }
+                if (deviceSlot == kmDevice) watchKeymaster<Wrapper>(device, kmDevice, name);
             };
             bool has_default = false;
             for (auto& n : names) {
@@ -97,6 +202,16 @@ KeymasterDevices enumerateKeymasterDevices(IServiceManager* serviceManager) {
             if (!has_default) {
                 try_get_device("default", true /* fail_silent */);
             }
+            // The same goes for instances named in the configuration.
+            for (auto securityLevel : {SecurityLevel::SOFTWARE, SecurityLevel::TRUSTED_ENVIRONMENT,
+                                       SecurityLevel::STRONGBOX}) {
+                auto name = configuredInstance(securityLevel);
+                if (name.empty() || name == "default" ||
+                    std::find(names.begin(), names.end(), name.c_str()) != names.end()) {
+                    continue;
+                }
+                try_get_device(name, true /* fail_silent */);
+            }
         });
     return result;
 }
@@ -121,9 +236,25 @@ KeymasterDevices initializeKeymasters() {
         CHECK(fbdev.get()) << "Unable to create Software Keymaster Device";
         result[SecurityLevel::SOFTWARE] = new Keymaster3(fbdev, "Software");
     }
+    // Emulators and development boards may ship without any Keymaster HAL. They can opt into
+    // using a second instance of the built-in software Keymaster as their default device.
+    if (!result[SecurityLevel::TRUSTED_ENVIRONMENT] &&
+        android::base::GetBoolProperty("ro.keystore.software_only", false)) {
+        LOG(WARNING) << "No Keymaster HAL found. Using the built-in software Keymaster as default"
+                        " because ro.keystore.software_only is set.";
+        auto fbdev = android::keystore::makeSoftwareKeymasterDevice();
+        CHECK(fbdev.get()) << "Unable to create Software Keymaster Device";
+        result[SecurityLevel::TRUSTED_ENVIRONMENT] = new Keymaster3(fbdev, "Software");
+    }
     return result;
 }
 
+/* Startup happens in dependency order: Keymaster devices, then the key store on top of them, then
+ * the binder service. Init scripts that depend on keystore wait for the binder service itself. */
+static void logBootStage(const char* stage) {
+    LOG(INFO) << "boot stage " << stage;
+}
+
 int main(int argc, char* argv[]) {
     using android::hardware::hidl_string;
     CHECK(argc >= 2) << "A directory must be specified!";
@@ -134,6 +265,7 @@ int main(int argc, char* argv[]) {
     CHECK(kmDevices[SecurityLevel::SOFTWARE]) << "Missing software Keymaster device";
     CHECK(kmDevices[SecurityLevel::TRUSTED_ENVIRONMENT])
         << "Error no viable keymaster device found";
+    logBootStage("keymaster");
 
     CHECK(configure_selinux() != -1) << "Failed to configure SELinux.";
 
@@ -148,12 +280,17 @@ int main(int argc, char* argv[]) {
     android::sp<keystore::KeyStore> keyStore(
         new keystore::KeyStore(kmDevices, minimalAllowedSecurityLevelForNewKeys));
     keyStore->initialize();
+    keymasterDeathRecipient()->setKeyStore(keyStore);
+    logBootStage("storage");
+
     android::sp<android::IServiceManager> sm = android::defaultServiceManager();
     android::sp<keystore::KeyStoreService> service = new keystore::KeyStoreService(keyStore);
     service->setRequestingSid(true);
     android::status_t ret = sm->addService(android::String16("android.security.keystore"), service);
     CHECK(ret == android::OK) << "Couldn't register binder service!";
 
+    logBootStage("ready");
+
     /*
      * This thread is just going to process Binder transactions.
      */
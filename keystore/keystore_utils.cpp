@@ -176,4 +176,12 @@ uint32_t securityLevelToFlags(SecurityLevel secLevel) {
     }
 }
 
+bool areCallerFlagsValid(int32_t flags) {
+    if (flags & ~KEYSTORE_CALLER_FLAGS) {
+        ALOGW("Unsupported keystore flags 0x%x", flags & ~KEYSTORE_CALLER_FLAGS);
+        return false;
+    }
+    return true;
+}
+
 }  // namespace keystore
@@ -65,6 +65,9 @@ hidl_vec<uint8_t> blob2hidlVec(const Blob& blob);
 SecurityLevel flagsToSecurityLevel(int32_t flags);
 uint32_t securityLevelToFlags(SecurityLevel secLevel);
 
+/* Returns false if flags contains bits outside of KEYSTORE_CALLER_FLAGS. */
+bool areCallerFlagsValid(int32_t flags);
+
 }  // namespace keystore
 
 #endif  // KEYSTORE_KEYSTORE_UTILS_H_
@@ -30,10 +30,12 @@ OperationMap::OperationMap(IBinder::DeathRecipient* deathRecipient)
 sp<IBinder> OperationMap::addOperation(uint64_t handle, uint64_t keyid, KeyPurpose purpose,
                                        const sp<Keymaster>& dev, const sp<IBinder>& appToken,
                                        KeyCharacteristics&& characteristics,
-                                       const hidl_vec<KeyParameter>& params, bool pruneable) {
+                                       const hidl_vec<KeyParameter>& params, bool pruneable,
+                                       uid_t owner) {
     sp<IBinder> token = new ::android::BBinder();
     mMap.emplace(token, std::make_shared<Operation>(handle, keyid, purpose, dev,
-                                                    std::move(characteristics), appToken, params));
+                                                    std::move(characteristics), appToken, params,
+                                                    owner));
     if (pruneable) mLru.push_back(token);
     if (mAppTokenMap.find(appToken) == mAppTokenMap.end()) appToken->linkToDeath(mDeathRecipient);
     mAppTokenMap[appToken].push_back(token);
@@ -47,6 +49,7 @@ std::shared_ptr<Operation> OperationMap::getOperation(const sp<IBinder>& token)
     auto op = entry->second;
 
     updateLru(token);
+    op->lastUsed = std::chrono::steady_clock::now();
     return op;
 }
 
@@ -88,10 +91,35 @@ void OperationMap::removeOperationTracking(const sp<IBinder>& token, const sp<IB
     }
 }
 
-sp<IBinder> OperationMap::getOldestPruneableOperation() {
-    if (mLru.size() == 0) return {};
+sp<IBinder> OperationMap::getPruneCandidate() const {
+    std::map<uid_t, size_t> operationsPerUid;
+    for (const auto& [token, op] : mMap) ++operationsPerUid[op->owner];
+
+    // mLru is ordered from least to most recently used, so the first pruneable operation of the
+    // greediest uid is its longest idle one.
+    sp<IBinder> candidate;
+    size_t candidateOwnerCount = 0;
+    for (const auto& token : mLru) {
+        size_t ownerCount = operationsPerUid[mMap.at(token)->owner];
+        if (ownerCount > candidateOwnerCount) {
+            candidate = token;
+            candidateOwnerCount = ownerCount;
+        }
+    }
+    return candidate;
+}
+
+sp<IBinder> OperationMap::getOldestPruneableOperationForUid(uid_t owner) {
+    auto entry = std::find_if(mLru.begin(), mLru.end(), [&](const sp<IBinder>& token) {
+        return mMap.at(token)->owner == owner;
+    });
+    if (entry == mLru.end()) return {};
+    return *entry;
+}
 
-    return {mLru.front()};
+size_t OperationMap::getOperationCountForUid(uid_t owner) const {
+    return std::count_if(mMap.begin(), mMap.end(),
+                         [&](const auto& entry) { return entry.second->owner == owner; });
 }
 
 std::vector<sp<IBinder>> OperationMap::getOperationsForToken(const sp<IBinder>& appToken) {
@@ -100,4 +128,32 @@ std::vector<sp<IBinder>> OperationMap::getOperationsForToken(const sp<IBinder>&
     return appEntry->second;
 }
 
+std::vector<OperationDescriptor> OperationMap::getOperationDescriptors() const {
+    auto now = std::chrono::steady_clock::now();
+    std::vector<OperationDescriptor> result;
+    result.reserve(mMap.size());
+    for (const auto& [token, op] : mMap) {
+        result.push_back({op->owner, op->keyid, op->purpose,
+                          std::chrono::duration_cast<std::chrono::seconds>(now - op->startTime)});
+    }
+    return result;
+}
+
+std::vector<sp<IBinder>> OperationMap::getOperations() const {
+    std::vector<sp<IBinder>> result;
+    result.reserve(mMap.size());
+    for (const auto& [token, op] : mMap) result.push_back(token);
+    return result;
+}
+
+std::vector<sp<IBinder>>
+OperationMap::getIdleOperations(std::chrono::steady_clock::duration maxIdle) const {
+    auto now = std::chrono::steady_clock::now();
+    std::vector<sp<IBinder>> result;
+    for (const auto& [token, op] : mMap) {
+        if (now - op->lastUsed > maxIdle) result.push_back(token);
+    }
+    return result;
+}
+
 }  // namespace keystore
@@ -17,6 +17,7 @@
 #ifndef KEYSTORE_OPERATION_H_
 #define KEYSTORE_OPERATION_H_
 
+#include <chrono>
 #include <list>
 #include <map>
 #include <memory>
@@ -41,6 +42,17 @@ using ::android::IBinder;
 using ::android::sp;
 using keymaster::support::Keymaster;
 
+/**
+ * Redacted description of an active operation as reported by listOperations. It deliberately
+ * carries the key id hash rather than the alias of the key.
+ */
+struct OperationDescriptor {
+    uid_t owner;
+    uint64_t keyid;
+    KeyPurpose purpose;
+    std::chrono::seconds age;
+};
+
 /**
  * OperationMap handles the translation of uint64_t's and keymaster2_device_t's to opaque binder
  * tokens that can be used to reference that operation at a later time by applications. It also does
@@ -54,13 +66,23 @@ class OperationMap {
     sp<IBinder> addOperation(uint64_t handle, uint64_t keyid, KeyPurpose purpose,
                              const sp<Keymaster>& dev, const sp<IBinder>& appToken,
                              KeyCharacteristics&& characteristics,
-                             const hidl_vec<KeyParameter>& params, bool pruneable);
+                             const hidl_vec<KeyParameter>& params, bool pruneable,
+                             uid_t owner);
     std::shared_ptr<Operation> getOperation(const sp<IBinder>& token);
     std::shared_ptr<Operation> removeOperation(const sp<IBinder>& token, bool wasSuccessful,
                                                int32_t responseCode);
     size_t getOperationCount() const { return mMap.size(); }
-    sp<IBinder> getOldestPruneableOperation();
+    size_t getNonPruneableOperationCount() const { return mMap.size() - mLru.size(); }
+    // Returns the least recently used pruneable operation of the uid that holds the most
+    // operations. Ties go to the uid whose operation has been idle the longest.
+    sp<IBinder> getPruneCandidate() const;
+    sp<IBinder> getOldestPruneableOperationForUid(uid_t owner);
+    size_t getOperationCountForUid(uid_t owner) const;
     std::vector<sp<IBinder>> getOperationsForToken(const sp<IBinder>& appToken);
+    std::vector<OperationDescriptor> getOperationDescriptors() const;
+    std::vector<sp<IBinder>> getOperations() const;
+    // Returns the operations that have not been used for longer than maxIdle.
+    std::vector<sp<IBinder>> getIdleOperations(std::chrono::steady_clock::duration maxIdle) const;
 
   private:
     void updateLru(const sp<IBinder>& token);
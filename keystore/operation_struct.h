@@ -26,6 +26,7 @@
 #include <keystore/keystore_hidl_support.h>
 #include <keystore/keystore_return_types.h>
 
+#include <chrono>
 #include <future>
 
 namespace keystore {
@@ -38,10 +39,11 @@ struct Operation {
     Operation() = default;
     Operation(uint64_t handle_, uint64_t keyid_, KeyPurpose purpose_, const sp<Keymaster>& device_,
               KeyCharacteristics&& characteristics_, sp<IBinder> appToken_,
-              const hidl_vec<KeyParameter> params_)
+              const hidl_vec<KeyParameter> params_, uid_t owner_)
         : handle(handle_), keyid(keyid_), purpose(purpose_), device(device_),
           characteristics(characteristics_), appToken(appToken_), authToken(), verificationToken(),
-          params(params_) {}
+          params(params_), owner(owner_), startTime(std::chrono::steady_clock::now()),
+          lastUsed(startTime) {}
     Operation(Operation&&) = default;
     Operation(const Operation&) = delete;
 
@@ -58,6 +60,13 @@ struct Operation {
     HardwareAuthToken authToken;
     VerificationToken verificationToken;
     const hidl_vec<KeyParameter> params;
+    const uid_t owner;
+    const std::chrono::steady_clock::time_point startTime;
+    // Updated whenever the operation is looked up for use.
+    std::chrono::steady_clock::time_point lastUsed;
+    // Input handed to the Keymaster by update() and finish(), and the time spent in those calls.
+    uint64_t inputBytes = 0;
+    std::chrono::steady_clock::duration keymasterTime{};
 };
 
 }  // namespace keystore
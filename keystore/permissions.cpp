@@ -23,32 +23,21 @@
 #include <private/android_filesystem_config.h>
 
 #include <selinux/android.h>
+#include <selinux/selinux.h>
 
 #include "keystore_utils.h"
 
 /* perm_labels associcated with keystore_key SELinux class verbs. */
 const char* perm_labels[] = {
-    "get_state",
-    "get",
-    "insert",
-    "delete",
-    "exist",
-    "list",
-    "reset",
-    "password",
-    "lock",
-    "unlock",
-    "is_empty",
-    "sign",
-    "verify",
-    "grant",
-    "duplicate",
-    "clear_uid",
-    "add_auth",
-    "user_changed",
-    "gen_unique_id",
+#define PERM_LABEL(name, label) label,
+    KEYSTORE_PERMISSIONS(PERM_LABEL)
+#undef PERM_LABEL
 };
 
+static_assert(sizeof(perm_labels) / sizeof(perm_labels[0]) == P_COUNT,
+              "Every permission needs exactly one label");
+static_assert(P_COUNT <= 32, "perm_t is a 32 bit mask");
+
 struct user_euid {
     uid_t uid;
     uid_t euid;
@@ -117,6 +106,30 @@ static int audit_callback(void* data, security_class_t /* cls */, char* buf, siz
 
 static char* tctx;
 
+static const char* const kKeystoreClass = "keystore_key";
+
+/**
+ * Checks the permission labels against the loaded policy. selinux_check_access() treats a label
+ * the policy does not define as an unknown permission, which it allows unless the policy sets
+ * deny_unknown. A missing or misspelled label would thus silently grant access, so keystore
+ * refuses to start instead.
+ */
+static bool check_perm_labels() {
+    security_class_t cls = string_to_security_class(kKeystoreClass);
+    if (cls == 0) {
+        ALOGE("SELinux: Policy does not define class %s.\n", kKeystoreClass);
+        return false;
+    }
+    bool ok = true;
+    for (const char* label : perm_labels) {
+        if (string_to_av_perm(cls, label) == 0) {
+            ALOGE("SELinux: Policy does not define %s permission %s.\n", kKeystoreClass, label);
+            ok = false;
+        }
+    }
+    return ok;
+}
+
 int configure_selinux() {
     union selinux_callback cb;
     cb.func_audit = audit_callback;
@@ -127,6 +140,10 @@ int configure_selinux() {
         ALOGE("SELinux: Could not acquire target context. Aborting keystore.\n");
         return -1;
     }
+    if (!check_perm_labels()) {
+        ALOGE("SELinux: Permission labels do not match the policy. Aborting keystore.\n");
+        return -1;
+    }
 
     return 0;
 }
@@ -134,7 +151,6 @@ int configure_selinux() {
 static bool keystore_selinux_check_access(uid_t uid, perm_t perm, pid_t spid, const char* ssid) {
     audit_data ad;
     char* sctx = nullptr;
-    const char* selinux_class = "keystore_key";
     const char* str_perm = get_perm_label(perm);
 
     if (!str_perm) {
@@ -152,7 +168,7 @@ static bool keystore_selinux_check_access(uid_t uid, perm_t perm, pid_t spid, co
     ad.uid = uid;
     ad.sid = use_sid;
 
-    bool allowed = selinux_check_access(use_sid, tctx, selinux_class, str_perm,
+    bool allowed = selinux_check_access(use_sid, tctx, kKeystoreClass, str_perm,
                                         reinterpret_cast<void*>(&ad)) == 0;
     freecon(sctx);
     return allowed;
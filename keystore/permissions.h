@@ -19,27 +19,44 @@
 
 #include <unistd.h>
 
+/*
+ * The keystore_key SELinux class verbs. Every entry defines a perm_t value and its SELinux label,
+ * so the two cannot drift apart. The labels must match the policy exactly; configure_selinux()
+ * fails if the loaded policy does not define one of them.
+ */
+#define KEYSTORE_PERMISSIONS(X)                                                                    \
+    X(P_GET_STATE, "get_state")                                                                    \
+    X(P_GET, "get")                                                                                \
+    X(P_INSERT, "insert")                                                                          \
+    X(P_DELETE, "delete")                                                                          \
+    X(P_EXIST, "exist")                                                                            \
+    X(P_LIST, "list")                                                                              \
+    X(P_RESET, "reset")                                                                            \
+    X(P_PASSWORD, "password")                                                                      \
+    X(P_LOCK, "lock")                                                                              \
+    X(P_UNLOCK, "unlock")                                                                          \
+    X(P_IS_EMPTY, "is_empty")                                                                      \
+    X(P_SIGN, "sign")                                                                              \
+    X(P_VERIFY, "verify")                                                                          \
+    X(P_GRANT, "grant")                                                                            \
+    X(P_DUPLICATE, "duplicate")                                                                    \
+    X(P_CLEAR_UID, "clear_uid")                                                                    \
+    X(P_ADD_AUTH, "add_auth")                                                                      \
+    X(P_USER_CHANGED, "user_changed")                                                              \
+    X(P_GEN_UNIQUE_ID, "gen_unique_id")
+
+enum perm_index_t {
+#define PERM_INDEX(name, label) name##_INDEX,
+    KEYSTORE_PERMISSIONS(PERM_INDEX)
+#undef PERM_INDEX
+    P_COUNT,
+};
+
 /* Here are the permissions, actions, users, and the main function. */
 enum perm_t {
-    P_GET_STATE = 1 << 0,
-    P_GET = 1 << 1,
-    P_INSERT = 1 << 2,
-    P_DELETE = 1 << 3,
-    P_EXIST = 1 << 4,
-    P_LIST = 1 << 5,
-    P_RESET = 1 << 6,
-    P_PASSWORD = 1 << 7,
-    P_LOCK = 1 << 8,
-    P_UNLOCK = 1 << 9,
-    P_IS_EMPTY = 1 << 10,
-    P_SIGN = 1 << 11,
-    P_VERIFY = 1 << 12,
-    P_GRANT = 1 << 13,
-    P_DUPLICATE = 1 << 14,
-    P_CLEAR_UID = 1 << 15,
-    P_ADD_AUTH = 1 << 16,
-    P_USER_CHANGED = 1 << 17,
-    P_GEN_UNIQUE_ID = 1 << 18,
+#define PERM_BIT(name, label) name = 1 << name##_INDEX,
+    KEYSTORE_PERMISSIONS(PERM_BIT)
+#undef PERM_BIT
 };
 
 const char* get_perm_label(perm_t perm);
@@ -112,6 +129,9 @@ int configure_selinux();
  *  - ungrant
  * Especially, the latter two mean that neither can a grantee transitively grant a granted key
  * to a third, nor can they relinquish access to the key or revoke access to the key by a third.
+ *
+ * Certificate only grants, created by grantCertificate, are only followed by get. They expose a
+ * generic entry, e.g., a certificate chain, without giving access to anything else.
  */
 
 #endif  // KEYSTORE_PERMISSIONS_H_
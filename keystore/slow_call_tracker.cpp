@@ -0,0 +1,110 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#define LOG_TAG "keystore"
+
+#include "slow_call_tracker.h"
+
+#include <inttypes.h>
+#include <stdio.h>
+
+#include <android-base/properties.h>
+#include <log/log.h>
+
+namespace keystore {
+
+using std::chrono::duration_cast;
+using std::chrono::milliseconds;
+
+namespace {
+
+const char* levelName(SecurityLevel securityLevel) {
+    switch (securityLevel) {
+    case SecurityLevel::SOFTWARE:
+        return "software";
+    case SecurityLevel::TRUSTED_ENVIRONMENT:
+        return "tee";
+    case SecurityLevel::STRONGBOX:
+        return "strongbox";
+    }
+    return "unknown";
+}
+
+bool createsKey(const std::string& api) {
+    return api == "generateKey" || api == "importKey" || api == "importWrappedKey";
+}
+
+milliseconds defaultThreshold(const std::string& api, SecurityLevel securityLevel) {
+    // Key creation may involve generating RSA primes, which StrongBox implementations in
+    // particular are slow at.
+    if (securityLevel == SecurityLevel::STRONGBOX) {
+        return createsKey(api) ? milliseconds(10000) : milliseconds(2000);
+    }
+    return createsKey(api) ? milliseconds(2000) : milliseconds(500);
+}
+
+}  // namespace
+
+milliseconds SlowCallTracker::threshold(const std::string& api, SecurityLevel securityLevel) {
+    std::string property = "keystore.slow_call_ms." + api;
+    int64_t thresholdMs = android::base::GetIntProperty<int64_t>(
+        property + "." + levelName(securityLevel), -1, 0 /* min */);
+    if (thresholdMs < 0) {
+        thresholdMs = android::base::GetIntProperty<int64_t>(property, -1, 0 /* min */);
+    }
+    if (thresholdMs < 0) return defaultThreshold(api, securityLevel);
+    return milliseconds(thresholdMs);
+}
+
+void SlowCallTracker::record(const std::string& api, SecurityLevel securityLevel,
+                             Clock::duration duration) {
+    auto durationMs = duration_cast<milliseconds>(duration);
+    auto limit = threshold(api, securityLevel);
+    bool slow = durationMs > limit;
+    if (slow) {
+        ALOGW("%s on %s took %" PRId64 " ms, threshold is %" PRId64 " ms", api.c_str(),
+              levelName(securityLevel), int64_t(durationMs.count()), int64_t(limit.count()));
+    }
+
+    size_t bucket = 0;
+    while (bucket < kBucketBoundsMs.size() && durationMs.count() >= kBucketBoundsMs[bucket]) {
+        ++bucket;
+    }
+
+    std::lock_guard<std::mutex> lock(mMutex);
+    auto& stats = mStats[{api, securityLevel}];
+    ++stats.buckets[bucket];
+    if (slow) ++stats.slowCalls;
+    if (duration > stats.max) stats.max = duration;
+}
+
+void SlowCallTracker::dump(int fd) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    dprintf(fd, "Keymaster call latency (api, security level, calls per bucket, slow calls, "
+                "max ms, threshold ms):\n");
+    dprintf(fd, "  buckets:");
+    for (auto bound : kBucketBoundsMs) dprintf(fd, " <%" PRIu32, bound);
+    dprintf(fd, " >=%" PRIu32 " ms\n", kBucketBoundsMs.back());
+    for (const auto& [key, stats] : mStats) {
+        dprintf(fd, "  %s %s", key.first.c_str(), levelName(key.second));
+        for (auto count : stats.buckets) dprintf(fd, " %" PRIu64, count);
+        dprintf(fd, " %" PRIu64 " %" PRId64 " %" PRId64 "\n", stats.slowCalls,
+                int64_t(duration_cast<milliseconds>(stats.max).count()),
+                int64_t(threshold(key.first, key.second).count()));
+    }
+}
+
+}  // namespace keystore
@@ -0,0 +1,99 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef KEYSTORE_SLOW_CALL_TRACKER_H_
+#define KEYSTORE_SLOW_CALL_TRACKER_H_
+
+#include <stdint.h>
+
+#include <array>
+#include <chrono>
+#include <map>
+#include <mutex>
+#include <string>
+#include <utility>
+
+#include <keystore/keymaster_types.h>
+
+namespace keystore {
+
+/**
+ * SlowCallTracker keeps a latency histogram of the Keymaster worker requests per API and security
+ * level, and logs a warning for every request that takes longer than its threshold.
+ *
+ * Keymaster implementations differ widely in speed, e.g. StrongBox RSA key generation can take
+ * several seconds, so the thresholds can be tuned per API and security level with the system
+ * properties
+ *
+ *     keystore.slow_call_ms.<api>.<level>
+ *     keystore.slow_call_ms.<api>
+ *
+ * where level is one of "software", "tee" or "strongbox". The first one that is set wins, otherwise
+ * a built-in default applies. The properties are read whenever a threshold is needed, so they can
+ * be changed without restarting keystore.
+ */
+class SlowCallTracker {
+  public:
+    using Clock = std::chrono::steady_clock;
+
+    /**
+     * Timer measures one request from its construction to its destruction and records it with
+     * the tracker it came from.
+     */
+    class Timer {
+      public:
+        Timer(SlowCallTracker* tracker, const char* api, SecurityLevel securityLevel)
+            : mTracker(tracker), mApi(api), mSecurityLevel(securityLevel), mStart(Clock::now()) {}
+        ~Timer() { mTracker->record(mApi, mSecurityLevel, Clock::now() - mStart); }
+
+        Timer(const Timer&) = delete;
+        Timer& operator=(const Timer&) = delete;
+
+      private:
+        SlowCallTracker* mTracker;
+        const char* mApi;
+        SecurityLevel mSecurityLevel;
+        Clock::time_point mStart;
+    };
+
+    Timer start(const char* api, SecurityLevel securityLevel) {
+        return Timer(this, api, securityLevel);
+    }
+
+    void record(const std::string& api, SecurityLevel securityLevel, Clock::duration duration);
+    void dump(int fd);
+
+    static std::chrono::milliseconds threshold(const std::string& api,
+                                               SecurityLevel securityLevel);
+
+  private:
+    // Upper bounds of the histogram buckets. The last bucket takes everything above.
+    static constexpr std::array<uint32_t, 6> kBucketBoundsMs = {10, 50, 100, 500, 1000, 5000};
+
+    struct Stats {
+        std::array<uint64_t, kBucketBoundsMs.size() + 1> buckets{};
+        uint64_t slowCalls = 0;
+        Clock::duration max{};
+    };
+
+    // This mutex protects all data below it.
+    std::mutex mMutex;
+    std::map<std::pair<std::string, SecurityLevel>, Stats> mStats;
+};
+
+}  // namespace keystore
+
+#endif  // KEYSTORE_SLOW_CALL_TRACKER_H_
@@ -516,5 +516,23 @@ TEST(AuthTokenTableTest, SupersededEntries) {
     EXPECT_EQ(8U, found.timestamp);
 }
 
+TEST(AuthTokenTableTest, FindNewestTokenForKey) {
+    AuthTokenTable table(5, monotonic_clock);
+    EXPECT_FALSE(table.FindNewestTokenForKey(make_set(1)));
+
+    // The key's sid may match either the user id or the authenticator id of a token.
+    table.AddAuthenticationToken(make_token(1, 2, 0, 1));
+    table.AddAuthenticationToken(make_token(3, 1, 0, 2));
+    table.AddAuthenticationToken(make_token(4, 5, 0, 3));
+
+    auto newest = table.FindNewestTokenForKey(make_set(1));
+    ASSERT_TRUE(newest);
+    EXPECT_EQ(2U, std::get<0>(*newest).timestamp);
+    // monotonic_clock has ticked since the token was added.
+    EXPECT_LT(0, std::get<1>(*newest));
+
+    EXPECT_FALSE(table.FindNewestTokenForKey(make_set(6)));
+}
+
 }  // namespace test
 }  // namespace keystore
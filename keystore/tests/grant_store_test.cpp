@@ -0,0 +1,105 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include <string>
+
+#include <android-base/file.h>
+
+#include "../grant_store.h"
+
+namespace keystore {
+
+namespace test {
+
+namespace {
+
+constexpr uid_t kGranterUid = 10001;
+constexpr uid_t kGranteeUid = 10002;
+
+}  // namespace
+
+TEST(GrantStoreTest, grantsSurviveReload) {
+    TemporaryDir tmpDir;
+    std::string fileName = std::string(tmpDir.path) + "/grants";
+    // Blanks and line breaks must not break the file format.
+    KeyBlobEntry entry("granted key\n", "user_0", kGranterUid);
+    KeyBlobEntry otherEntry("other", "user_0", kGranterUid);
+
+    std::string grantAlias, otherGrantAlias;
+    {
+        GrantStore grants(fileName);
+        grantAlias = grants.put(kGranteeUid, LockedKeyBlobEntry::get(entry));
+        otherGrantAlias = grants.put(kGranteeUid, LockedKeyBlobEntry::get(otherEntry));
+        ASSERT_TRUE(grants.removeByFileAlias(kGranteeUid, LockedKeyBlobEntry::get(otherEntry)));
+    }
+
+    GrantStore grants(fileName);
+    grants.load();
+    {
+        auto grant = grants.get(kGranteeUid, grantAlias);
+        ASSERT_TRUE(bool(grant));
+        EXPECT_EQ(entry, grant->entry_);
+    }
+    EXPECT_FALSE(bool(grants.get(kGranteeUid, otherGrantAlias)));
+    EXPECT_FALSE(bool(grants.get(kGranterUid, grantAlias)));
+}
+
+TEST(GrantStoreTest, removeAllGrantsOfUser) {
+    constexpr uid_t kOtherUserUid = 1010001;
+    KeyBlobEntry entry("key", "user_0", kGranterUid);
+    KeyBlobEntry otherUserEntry("key", "user_10", kOtherUserUid);
+
+    GrantStore grants;
+    auto grantAlias = grants.put(kGranteeUid, LockedKeyBlobEntry::get(entry));
+    auto toOtherUserAlias = grants.put(kOtherUserUid, LockedKeyBlobEntry::get(entry));
+    auto fromOtherUserAlias = grants.put(kGranteeUid, LockedKeyBlobEntry::get(otherUserEntry));
+
+    grants.removeAllGrantsOfUser(10);
+    EXPECT_TRUE(bool(grants.get(kGranteeUid, grantAlias)));
+    EXPECT_FALSE(bool(grants.get(kOtherUserUid, toOtherUserAlias)));
+    EXPECT_FALSE(bool(grants.get(kGranteeUid, fromOtherUserAlias)));
+}
+
+TEST(GrantStoreTest, certOnlyGrants) {
+    TemporaryDir tmpDir;
+    std::string fileName = std::string(tmpDir.path) + "/grants";
+    KeyBlobEntry entry("cert", "user_0", kGranterUid);
+
+    std::string grantAlias;
+    {
+        GrantStore grants(fileName);
+        grantAlias = grants.put(kGranteeUid, LockedKeyBlobEntry::get(entry), true /* certOnly */);
+    }
+
+    GrantStore grants(fileName);
+    grants.load();
+    {
+        auto grant = grants.get(kGranteeUid, grantAlias);
+        ASSERT_TRUE(bool(grant));
+        EXPECT_TRUE(grant->cert_only_);
+    }
+    // Granting again keeps the alias but widens the grant.
+    EXPECT_EQ(grantAlias, grants.put(kGranteeUid, LockedKeyBlobEntry::get(entry)));
+    auto grant = grants.get(kGranteeUid, grantAlias);
+    ASSERT_TRUE(bool(grant));
+    EXPECT_FALSE(grant->cert_only_);
+}
+
+}  // namespace test
+
+}  // namespace keystore
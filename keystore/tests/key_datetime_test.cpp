@@ -0,0 +1,68 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include <limits>
+
+#include "../key_datetime.h"
+
+namespace keystore {
+
+namespace test {
+
+constexpr uint64_t kSeptember2020Millis = 1598961600123;  // 2020-09-01T12:00:00.123Z
+
+TEST(KeyDateTimeTest, RoundTrip) {
+    auto dateTime = dateTimeFromParameter(kSeptember2020Millis);
+    EXPECT_EQ(int64_t(kSeptember2020Millis), dateTime.time_since_epoch().count());
+    EXPECT_EQ(kSeptember2020Millis, dateTimeToParameter(dateTime));
+}
+
+TEST(KeyDateTimeTest, Validation) {
+    EXPECT_TRUE(isValidDateTimeParameter(0));
+    EXPECT_TRUE(isValidDateTimeParameter(std::numeric_limits<int64_t>::max()));
+    EXPECT_FALSE(isValidDateTimeParameter(uint64_t(std::numeric_limits<int64_t>::max()) + 1));
+    EXPECT_FALSE(isValidDateTimeParameter(std::numeric_limits<uint64_t>::max()));
+}
+
+TEST(KeyDateTimeTest, OutOfRangeValuesAreClamped) {
+    EXPECT_EQ(KeyDateTime::max(), dateTimeFromParameter(std::numeric_limits<uint64_t>::max()));
+    EXPECT_EQ(KeyDateTime::max(),
+              dateTimeFromParameter(uint64_t(std::numeric_limits<int64_t>::max()) + 1));
+    EXPECT_GT(dateTimeFromParameter(std::numeric_limits<uint64_t>::max()),
+              dateTimeFromParameter(kSeptember2020Millis));
+}
+
+TEST(KeyDateTimeTest, Format) {
+    EXPECT_EQ("1970-01-01T00:00:00.000Z", formatDateTime(dateTimeFromParameter(0)));
+    EXPECT_EQ("2020-09-01T12:00:00.123Z",
+              formatDateTime(dateTimeFromParameter(kSeptember2020Millis)));
+    // "Never" is beyond what a four digit year can express.
+    EXPECT_EQ("9223372036854775807",
+              formatDateTime(dateTimeFromParameter(std::numeric_limits<int64_t>::max())));
+}
+
+TEST(KeyDateTimeTest, CurrentDateTimeIsInMilliseconds) {
+    auto now = currentDateTime();
+    ASSERT_TRUE(now.has_value());
+    // Seconds since the epoch would put the current time in January 1970.
+    EXPECT_GT(dateTimeToParameter(*now), kSeptember2020Millis);
+}
+
+}  // namespace test
+
+}  // namespace keystore
@@ -0,0 +1,94 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include <limits>
+
+#include "../key_descriptor.h"
+
+namespace keystore {
+
+namespace test {
+
+TEST(KeyDescriptorTest, Format) {
+    EXPECT_EQ("app:10123:alias", toString(KeyDescriptor{10123, "alias"}));
+    EXPECT_EQ("app:0:a:b", toString(KeyDescriptor{0, "a:b"}));
+}
+
+TEST(KeyDescriptorTest, RoundTrip) {
+    const KeyDescriptor descriptors[] = {
+        {0, "a"},
+        {1000, "wifi_cert"},
+        {10123, "alias:with:colons"},
+        {10123, "10042_KEYSTOREGRANT_7"},
+        {4294967295u, "max uid"},
+        {1010, std::string("with\0nul", 8)},
+    };
+    for (const auto& descriptor : descriptors) {
+        auto parsed = parseKeyDescriptor(toString(descriptor));
+        ASSERT_TRUE(parsed) << toString(descriptor);
+        EXPECT_EQ(descriptor, *parsed);
+    }
+}
+
+TEST(KeyDescriptorTest, RejectsMalformed) {
+    const char* malformed[] = {
+        "",
+        "app",
+        "app:",
+        "app:10123",
+        "app:10123:",
+        "app::alias",
+        "app:-1:alias",
+        "app:+1:alias",
+        "app:0x10:alias",
+        "app:4294967296:alias",
+        "app:99999999999999999999999:alias",
+        "selinux:102:alias",
+        "APP:10123:alias",
+        " app:10123:alias",
+    };
+    for (const char* str : malformed) {
+        EXPECT_FALSE(parseKeyDescriptor(str)) << str;
+    }
+}
+
+TEST(KeyDescriptorTest, Validate) {
+    EXPECT_FALSE(validateKeyDescriptor("alias", -1));
+    EXPECT_FALSE(validateKeyDescriptor("alias", 0));
+    EXPECT_FALSE(validateKeyDescriptor("alias", 10123));
+    // Existing entries with long aliases must remain accessible.
+    EXPECT_FALSE(validateKeyDescriptor(std::string(237, 'a'), 10123));
+
+    EXPECT_TRUE(validateKeyDescriptor("alias", -2));
+    EXPECT_TRUE(validateKeyDescriptor("alias", std::numeric_limits<int32_t>::min()));
+    EXPECT_TRUE(validateKeyDescriptor("", 10123));
+}
+
+TEST(KeyDescriptorTest, ValidateNewAlias) {
+    EXPECT_FALSE(validateNewKeyAlias("alias"));
+    EXPECT_FALSE(validateNewKeyAlias(std::string(236, 'a')));
+    // Characters outside of '0'..'~' take two bytes in file names.
+    EXPECT_FALSE(validateNewKeyAlias(std::string(118, ' ')));
+
+    EXPECT_TRUE(validateNewKeyAlias(std::string(237, 'a')));
+    EXPECT_TRUE(validateNewKeyAlias(std::string(119, ' ')));
+}
+
+}  // namespace test
+
+}  // namespace keystore
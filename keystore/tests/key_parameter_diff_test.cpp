@@ -0,0 +1,78 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include <string>
+#include <vector>
+
+#include "../key_parameter_diff.h"
+
+namespace keystore {
+
+namespace test {
+
+using keymaster::KeyOrigin;
+using keymaster::TAG_CREATION_DATETIME;
+using keymaster::TAG_OS_PATCHLEVEL;
+
+TEST(KeyParameterDiffTest, Format) {
+    EXPECT_EQ("ALGORITHM=EC", formatKeyParameter(Authorization(TAG_ALGORITHM, Algorithm::EC)));
+    EXPECT_EQ("KEY_SIZE=256", formatKeyParameter(Authorization(TAG_KEY_SIZE, 256)));
+    EXPECT_EQ("NO_AUTH_REQUIRED=true", formatKeyParameter(Authorization(TAG_NO_AUTH_REQUIRED)));
+    EXPECT_EQ("APPLICATION_ID=00ab",
+              formatKeyParameter(Authorization(TAG_APPLICATION_ID, hidl_vec<uint8_t>{0x00, 0xab})));
+}
+
+TEST(KeyParameterDiffTest, Identical) {
+    AuthorizationSet hw = AuthorizationSetBuilder().EcdsaSigningKey(256).Digest(Digest::SHA_2_256);
+    AuthorizationSet sw = AuthorizationSetBuilder().Authorization(TAG_CREATION_DATETIME, 42);
+    EXPECT_TRUE(diffKeyCharacteristics(hw, sw, hw, sw).empty());
+}
+
+TEST(KeyParameterDiffTest, KeystoreOnlyParametersAreNotReported) {
+    AuthorizationSet hw = AuthorizationSetBuilder().EcdsaSigningKey(256);
+    AuthorizationSet storedSw = AuthorizationSetBuilder().Authorization(TAG_NO_AUTH_REQUIRED);
+    EXPECT_TRUE(diffKeyCharacteristics(hw, storedSw, hw, {}).empty());
+}
+
+TEST(KeyParameterDiffTest, Differences) {
+    AuthorizationSet storedHw = AuthorizationSetBuilder()
+                                    .Authorization(TAG_ALGORITHM, Algorithm::EC)
+                                    .Authorization(TAG_KEY_SIZE, 256)
+                                    .Digest(Digest::SHA_2_256);
+    AuthorizationSet storedSw =
+        AuthorizationSetBuilder().Authorization(TAG_ORIGIN, KeyOrigin::GENERATED);
+    AuthorizationSet reportedHw = AuthorizationSetBuilder()
+                                      .Authorization(TAG_ALGORITHM, Algorithm::EC)
+                                      .Authorization(TAG_ORIGIN, KeyOrigin::GENERATED)
+                                      .Authorization(TAG_OS_PATCHLEVEL, 202009);
+    AuthorizationSet reportedSw = AuthorizationSetBuilder()
+                                      .Digest(Digest::SHA_2_256)
+                                      .Authorization(TAG_CREATION_DATETIME, 42);
+
+    std::vector<std::string> expected = {
+        "ORIGIN=GENERATED: enforced by hardware, stored as software",
+        "OS_PATCHLEVEL=202009: reported by hardware, not stored",
+        "KEY_SIZE=256: stored as hardware, not reported",
+        "DIGEST=SHA_2_256: stored as hardware, enforced by software",
+        "CREATION_DATETIME=1970-01-01T00:00:00.042Z: reported by software, not stored",
+    };
+    EXPECT_EQ(expected, diffKeyCharacteristics(storedHw, storedSw, reportedHw, reportedSw));
+}
+
+}  // namespace test
+}  // namespace keystore
@@ -0,0 +1,403 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/*
+ * Exercises the running keystore service against whatever Keymaster devices the device exposes.
+ * Every test runs once per security level. Security levels without a device are skipped, so the
+ * binary can run unmodified on devices with and without StrongBox.
+ */
+
+#include <gtest/gtest.h>
+
+#include <grp.h>
+#include <unistd.h>
+
+#include <memory>
+#include <string>
+#include <vector>
+
+#include <android/security/keystore/IKeystoreService.h>
+#include <binder/IServiceManager.h>
+#include <binder/ProcessState.h>
+#include <keystore/keymaster_types.h>
+#include <keystore/keystore.h>
+#include <keystore/keystore_client_impl.h>
+#include <private/android_filesystem_config.h>
+
+using android::sp;
+using android::String16;
+using android::security::keystore::IKeystoreService;
+
+namespace keystore {
+
+namespace test {
+
+namespace {
+
+constexpr const char* kKeyName = "keystore_integration_test_key";
+constexpr const char* kOtherKeyName = "keystore_integration_test_other_key";
+const std::string kMessage = "keystore integration test message";
+
+// FIPS-197 appendix C.1.
+const std::vector<uint8_t> kAesKey = {0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+                                      0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f};
+const std::vector<uint8_t> kAesPlaintext = {0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+                                            0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff};
+const std::vector<uint8_t> kAesCiphertext = {0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30,
+                                             0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5, 0x5a};
+
+std::string toString(const std::vector<uint8_t>& data) {
+    return std::string(data.begin(), data.end());
+}
+
+AuthorizationSet ecdsaSigningParameters() {
+    return AuthorizationSetBuilder()
+        .EcdsaSigningKey(256)
+        .Digest(Digest::SHA_2_256)
+        .Authorization(TAG_NO_AUTH_REQUIRED);
+}
+
+AuthorizationSet aesEcbParameters() {
+    return AuthorizationSetBuilder()
+        .AesEncryptionKey(128)
+        .Authorization(TAG_BLOCK_MODE, BlockMode::ECB)
+        .Padding(PaddingMode::NONE)
+        .Authorization(TAG_NO_AUTH_REQUIRED);
+}
+
+std::string securityLevelName(int32_t flags) {
+    return (flags & KEYSTORE_FLAG_STRONGBOX) ? "StrongBox" : "TrustedEnvironment";
+}
+
+class KeystoreIntegrationTest : public ::testing::TestWithParam<int32_t> {
+  protected:
+    void SetUp() override {
+        android::ProcessState::self()->startThreadPool();
+        client_ = std::make_unique<KeystoreClientImpl>();
+        client_->deleteKey(kKeyName);
+        client_->deleteKey(kOtherKeyName);
+
+        // Use a key generation as the capability probe, so that devices without the security
+        // level under test are skipped rather than failed.
+        AuthorizationSet hwEnforced, swEnforced;
+        auto rc = client_->generateKey(kKeyName, ecdsaSigningParameters(), GetParam(),
+                                       &hwEnforced, &swEnforced);
+        if (rc == ErrorCode::HARDWARE_TYPE_UNAVAILABLE) {
+            GTEST_SKIP() << "No " << securityLevelName(GetParam()) << " Keymaster";
+        }
+        ASSERT_TRUE(rc.isOk()) << "generateKey failed: " << rc.getErrorCode();
+        ASSERT_TRUE(hwEnforced.Contains(TAG_ALGORITHM, Algorithm::EC));
+    }
+
+    void TearDown() override {
+        if (!client_) return;
+        client_->deleteKey(kKeyName);
+        client_->deleteKey(kOtherKeyName);
+    }
+
+    std::string sign(const std::string& keyName) {
+        AuthorizationSet outParams;
+        std::string signature;
+        EXPECT_TRUE(client_->oneShotOperation(KeyPurpose::SIGN, keyName,
+                                              AuthorizationSetBuilder().Digest(Digest::SHA_2_256),
+                                              kMessage, {}, &outParams, &signature));
+        return signature;
+    }
+
+    bool verify(const std::string& keyName, const std::string& signature) {
+        AuthorizationSet outParams;
+        std::string output;
+        return client_->oneShotOperation(KeyPurpose::VERIFY, keyName,
+                                         AuthorizationSetBuilder().Digest(Digest::SHA_2_256),
+                                         kMessage, signature, &outParams, &output);
+    }
+
+    sp<IKeystoreService> service() {
+        return android::interface_cast<IKeystoreService>(
+            android::defaultServiceManager()->getService(String16("android.security.keystore")));
+    }
+
+    std::unique_ptr<KeystoreClient> client_;
+};
+
+}  // namespace
+
+TEST_P(KeystoreIntegrationTest, generateUseDelete) {
+    ASSERT_TRUE(client_->doesKeyExist(kKeyName));
+
+    std::string signature = sign(kKeyName);
+    ASSERT_FALSE(signature.empty());
+    EXPECT_TRUE(verify(kKeyName, signature));
+    EXPECT_FALSE(verify(kKeyName, signature + "x"));
+
+    std::string publicKey;
+    EXPECT_TRUE(client_->exportKey(KeyFormat::X509, kKeyName, &publicKey).isOk());
+    EXPECT_FALSE(publicKey.empty());
+
+    ASSERT_TRUE(client_->deleteKey(kKeyName).isOk());
+    EXPECT_FALSE(client_->doesKeyExist(kKeyName));
+    EXPECT_EQ(ResponseCode::KEY_NOT_FOUND, client_->deleteKey(kKeyName));
+}
+
+TEST_P(KeystoreIntegrationTest, importAndUse) {
+    if (GetParam() != 0) {
+        GTEST_SKIP() << "KeystoreClient only imports into the default security level";
+    }
+    AuthorizationSet hwEnforced, swEnforced;
+    auto rc = client_->importKey(kOtherKeyName, aesEcbParameters(), KeyFormat::RAW,
+                                 toString(kAesKey), &hwEnforced, &swEnforced);
+    ASSERT_TRUE(rc.isOk()) << "importKey failed: " << rc.getErrorCode();
+
+    AuthorizationSet outParams;
+    std::string ciphertext;
+    ASSERT_TRUE(client_->oneShotOperation(KeyPurpose::ENCRYPT, kOtherKeyName,
+                                          AuthorizationSetBuilder()
+                                              .Authorization(TAG_BLOCK_MODE, BlockMode::ECB)
+                                              .Padding(PaddingMode::NONE),
+                                          toString(kAesPlaintext), {}, &outParams, &ciphertext));
+    EXPECT_EQ(kAesCiphertext, std::vector<uint8_t>(ciphertext.begin(), ciphertext.end()));
+}
+
+TEST_P(KeystoreIntegrationTest, grantToSelf) {
+    sp<IKeystoreService> service = this->service();
+    ASSERT_TRUE(service);
+
+    String16 grantAlias;
+    ASSERT_TRUE(service->grant(String16(kKeyName), getuid(), &grantAlias).isOk());
+    ASSERT_NE(0u, grantAlias.size()) << "grant was refused";
+    std::string grantAlias8 = android::String8(grantAlias).string();
+
+    // The grant alias resolves to the same key.
+    EXPECT_TRUE(verify(grantAlias8, sign(kKeyName)));
+
+    int32_t rc;
+    ASSERT_TRUE(service->ungrant(String16(kKeyName), getuid(), &rc).isOk());
+    EXPECT_EQ(int32_t(ResponseCode::NO_ERROR), rc);
+    EXPECT_FALSE(client_->doesKeyExist(grantAlias8));
+
+    // A grant that no longer exists is not found, like a key that never existed.
+    ASSERT_TRUE(service->ungrant(String16(kKeyName), getuid(), &rc).isOk());
+    EXPECT_EQ(int32_t(ResponseCode::KEY_NOT_FOUND), rc);
+    ASSERT_TRUE(service->ungrant(String16(kOtherKeyName), getuid(), &rc).isOk());
+    EXPECT_EQ(int32_t(ResponseCode::KEY_NOT_FOUND), rc);
+}
+
+TEST_P(KeystoreIntegrationTest, listFiltered) {
+    sp<IKeystoreService> service = this->service();
+    ASSERT_TRUE(service);
+
+    auto list = [&](int32_t algorithm, bool authBoundOnly, int32_t securityLevel) {
+        std::vector<String16> aliases;
+        int32_t rc;
+        EXPECT_TRUE(service
+                        ->listFiltered(String16(kKeyName), -1, algorithm, authBoundOnly,
+                                       securityLevel, &aliases, &rc)
+                        .isOk());
+        EXPECT_EQ(int32_t(ResponseCode::NO_ERROR), rc);
+        return aliases.size();
+    };
+    int32_t securityLevel = int32_t((GetParam() & KEYSTORE_FLAG_STRONGBOX)
+                                        ? SecurityLevel::STRONGBOX
+                                        : SecurityLevel::TRUSTED_ENVIRONMENT);
+
+    EXPECT_EQ(1u, list(-1, false, -1));
+    EXPECT_EQ(1u, list(int32_t(Algorithm::EC), false, securityLevel));
+    EXPECT_EQ(0u, list(int32_t(Algorithm::RSA), false, -1));
+    EXPECT_EQ(0u, list(-1, true, -1));
+}
+
+TEST_P(KeystoreIntegrationTest, listPaged) {
+    sp<IKeystoreService> service = this->service();
+    ASSERT_TRUE(service);
+    const std::string prefix = "paged_";
+    for (const char* suffix : {"c", "a", "b"}) {
+        AuthorizationSet hwEnforced, swEnforced;
+        ASSERT_TRUE(client_
+                        ->generateKey(prefix + suffix, ecdsaSigningParameters(), GetParam(),
+                                      &hwEnforced, &swEnforced)
+                        .isOk());
+    }
+
+    auto list = [&](const char* startPastAlias, int32_t maxCount) {
+        std::vector<String16> aliases;
+        int32_t rc;
+        EXPECT_TRUE(service
+                        ->listPaged(String16(prefix.c_str()), -1, String16(startPastAlias),
+                                    maxCount, &aliases, &rc)
+                        .isOk());
+        EXPECT_EQ(int32_t(ResponseCode::NO_ERROR), rc);
+        return aliases;
+    };
+
+    EXPECT_EQ((std::vector<String16>{String16("a"), String16("b")}), list("", 2));
+    EXPECT_EQ((std::vector<String16>{String16("c")}), list("b", 2));
+    EXPECT_TRUE(list("c", 2).empty());
+    EXPECT_EQ(3u, list("", 0).size());
+
+    for (const char* suffix : {"a", "b", "c"}) client_->deleteKey(prefix + suffix);
+}
+
+TEST_P(KeystoreIntegrationTest, expiredAutoDeleteKeyIsDeleted) {
+    client_->deleteKey(kKeyName);
+    AuthorizationSet params = ecdsaSigningParameters();
+    params.push_back(TAG_USAGE_EXPIRE_DATETIME, 1);  // Long gone.
+    AuthorizationSet hwEnforced, swEnforced;
+    ASSERT_TRUE(client_
+                    ->generateKey(kKeyName, params, GetParam() | KEYSTORE_FLAG_AUTO_DELETE,
+                                  &hwEnforced, &swEnforced)
+                    .isOk());
+    ASSERT_TRUE(client_->doesKeyExist(kKeyName));
+
+    // The first lookup notices the expiry and deletes the key.
+    std::string publicKey;
+    EXPECT_EQ(ResponseCode::KEY_NOT_FOUND,
+              client_->exportKey(KeyFormat::X509, kKeyName, &publicKey));
+    EXPECT_FALSE(client_->doesKeyExist(kKeyName));
+}
+
+TEST_P(KeystoreIntegrationTest, oldOperationsArePruned) {
+    constexpr size_t kOperations = 32;
+    std::vector<uint64_t> handles;
+    for (size_t i = 0; i < kOperations; ++i) {
+        AuthorizationSet outParams;
+        uint64_t handle;
+        auto rc = client_->beginOperation(KeyPurpose::SIGN, kKeyName,
+                                          AuthorizationSetBuilder().Digest(Digest::SHA_2_256),
+                                          &outParams, &handle);
+        ASSERT_TRUE(rc.isOk()) << "begin " << i << " failed: " << rc.getErrorCode();
+        handles.push_back(handle);
+    }
+
+    // Neither keystore nor the Keymaster device keeps that many operations for one app, so the
+    // first one must have made room for the later ones.
+    AuthorizationSet outParams;
+    std::string output;
+    size_t consumed;
+    EXPECT_EQ(ErrorCode::INVALID_OPERATION_HANDLE,
+              client_->updateOperation(handles.front(), {}, kMessage, &consumed, &outParams,
+                                       &output));
+
+    std::string signature;
+    EXPECT_TRUE(client_->finishOperation(handles.back(), {}, kMessage, {}, &outParams, &signature)
+                    .isOk());
+    EXPECT_TRUE(verify(kKeyName, signature));
+
+    for (size_t i = 1; i + 1 < handles.size(); ++i) client_->abortOperation(handles[i]);
+}
+
+namespace {
+
+// An app uid of user 0 that no installed app is expected to use.
+constexpr uid_t kForeignUid = AID_APP_END;
+
+// Makes uid the real and effective uid of this process, if it runs as root. Must happen before
+// the process first talks to binder, because the binder driver may remember the opener's uid.
+bool dropToUid(uid_t uid) {
+    if (getuid() != AID_ROOT) return getuid() == uid;
+    return setgroups(0, nullptr) == 0 && setgid(uid) == 0 && setuid(uid) == 0;
+}
+
+// Returns how many of the authorization entry points accept a call from this process.
+int countAcceptedAuthorizationCalls() {
+    sp<IKeystoreService> service = android::interface_cast<IKeystoreService>(
+        android::defaultServiceManager()->getService(String16("android.security.keystore")));
+    if (!service) return -1;
+
+    int accepted = 0;
+    int32_t rc;
+    auto count = [&](const android::binder::Status& status) {
+        if (!status.isOk() || rc != int32_t(ResponseCode::PERMISSION_DENIED)) ++accepted;
+    };
+    // The permission is checked before the token is looked at.
+    count(service->addAuthToken({}, &rc));
+    count(service->onKeyguardVisibilityChanged(false, 0, &rc));
+    count(service->onKeyguardVisibilityChanged(true, 0, &rc));
+    return accepted;
+}
+
+// Checks that probing kForeignUid's namespace is denied the same way for an alias that exists
+// there as for one that does not.
+bool foreignNamespaceIsOpaque() {
+    sp<IKeystoreService> service = android::interface_cast<IKeystoreService>(
+        android::defaultServiceManager()->getService(String16("android.security.keystore")));
+    if (!service) return false;
+
+    int32_t existingRc, missingRc;
+    if (!service->exist(String16(kKeyName), kForeignUid, &existingRc).isOk() ||
+        !service->exist(String16(kOtherKeyName), kForeignUid, &missingRc).isOk()) {
+        return false;
+    }
+    return existingRc == int32_t(ResponseCode::PERMISSION_DENIED) && missingRc == existingRc;
+}
+
+}  // namespace
+
+// Auth tokens and lock screen events decide which keys can be used, so only platform components
+// may report them. The test usually runs as root, so the calls are made from a child that drops
+// to an app uid first.
+TEST(KeystoreAuthorizationTest, unprivilegedCallersAreRejected) {
+    if (getuid() % AID_USER_OFFSET == AID_SYSTEM) {
+        GTEST_SKIP() << "The system uid cannot drop to an app uid";
+    }
+    // The child must not inherit the binder state of this process.
+    ::testing::FLAGS_gtest_death_test_style = "threadsafe";
+    EXPECT_EXIT(
+        {
+            if (!dropToUid(AID_APP_START)) _exit(2);
+            _exit(countAcceptedAuthorizationCalls() == 0 ? 0 : 1);
+        },
+        ::testing::ExitedWithCode(0), "");
+}
+
+// Whether a key exists in another namespace must not make a difference to a caller that may not
+// access it. The key is created in kForeignUid's namespace and probed from this uid. Each step runs
+// in its own child, so that every child talks to binder only with the uid it acts as.
+TEST(KeystoreDisclosureTest, foreignNamespaceDoesNotRevealKeys) {
+    if (getuid() != AID_ROOT) {
+        GTEST_SKIP() << "Needs root to act as another uid";
+    }
+    ::testing::FLAGS_gtest_death_test_style = "threadsafe";
+    EXPECT_EXIT(
+        {
+            if (!dropToUid(kForeignUid)) _exit(2);
+            KeystoreClientImpl client;
+            client.deleteKey(kKeyName);
+            AuthorizationSet hwEnforced, swEnforced;
+            _exit(client.generateKey(kKeyName, ecdsaSigningParameters(), 0 /* flags */,
+                                     &hwEnforced, &swEnforced)
+                          .isOk()
+                      ? 0
+                      : 1);
+        },
+        ::testing::ExitedWithCode(0), "");
+    EXPECT_EXIT(_exit(foreignNamespaceIsOpaque() ? 0 : 1), ::testing::ExitedWithCode(0), "");
+    EXPECT_EXIT(
+        {
+            if (!dropToUid(kForeignUid)) _exit(2);
+            KeystoreClientImpl client;
+            _exit(client.deleteKey(kKeyName).isOk() ? 0 : 1);
+        },
+        ::testing::ExitedWithCode(0), "");
+}
+
+INSTANTIATE_TEST_SUITE_P(PerSecurityLevel, KeystoreIntegrationTest,
+                         ::testing::Values(0, KEYSTORE_FLAG_STRONGBOX),
+                         [](const ::testing::TestParamInfo<int32_t>& info) {
+                             return securityLevelName(info.param);
+                         });
+
+}  // namespace test
+}  // namespace keystore
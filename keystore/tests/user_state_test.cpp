@@ -0,0 +1,315 @@
+/*
+ * Copyright (C) 2020 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include <dirent.h>
+#include <unistd.h>
+
+#include <map>
+#include <string>
+#include <vector>
+
+#include <android-base/file.h>
+#include <private/android_filesystem_config.h>
+#include <utils/String8.h>
+
+#include "../blob.h"
+#include "../user_state.h"
+
+namespace keystore {
+
+namespace test {
+
+namespace {
+
+constexpr uid_t kAppId = 10001;
+const android::String8 kPassword("correct horse");
+const android::String8 kOtherPassword("battery staple");
+const std::string kSecret = "not for other users";
+
+uid_t appUid(uid_t userId) {
+    return userId * AID_USER_OFFSET + kAppId;
+}
+
+size_t countFiles(const std::string& dirName) {
+    DIR* dir = opendir(dirName.c_str());
+    if (!dir) return 0;
+    size_t count = 0;
+    struct dirent* file;
+    while ((file = readdir(dir)) != nullptr) {
+        if (file->d_type == DT_REG) ++count;
+    }
+    closedir(dir);
+    return count;
+}
+
+// Keeps the recorded epochs in memory, so that tests never touch the device's properties.
+class FakeEpochStore : public MasterKeyEpochStore {
+  public:
+    uint64_t get(uid_t userId) const override {
+        auto it = epochs_.find(userId);
+        return it == epochs_.end() ? 0 : it->second;
+    }
+    bool record(uid_t userId, uint64_t epoch) override {
+        if (failRecords) return false;
+        epochs_[userId] = epoch;
+        return true;
+    }
+
+    bool failRecords = false;
+
+  private:
+    std::map<uid_t, uint64_t> epochs_;
+};
+
+/*
+ * Runs the user lifecycle the way KeyStore drives it: users are added with a password, unlocked,
+ * populated with encrypted keys, locked and finally removed. Everything happens in a scratch
+ * directory, so no Keymaster or binder service is needed.
+ */
+class UserStateTest : public ::testing::Test {
+  protected:
+    void SetUp() override {
+        char* cwd = getcwd(nullptr, 0);
+        ASSERT_NE(nullptr, cwd);
+        oldCwd_ = cwd;
+        free(cwd);
+        ASSERT_EQ(0, chdir(tmpDir_.path));
+    }
+
+    void TearDown() override { ASSERT_EQ(0, chdir(oldCwd_.c_str())); }
+
+    void addUser(uid_t userId) {
+        auto userState = db_.getUserState(userId);
+        ASSERT_EQ(STATE_UNINITIALIZED, userState->getState());
+        ASSERT_EQ(ResponseCode::NO_ERROR, userState->initialize(kPassword));
+        ASSERT_EQ(STATE_NO_ERROR, userState->getState());
+    }
+
+    void lockUser(uid_t userId) {
+        auto userState = db_.getUserState(userId);
+        userState->zeroizeMasterKeysInMemory();
+        userState->setState(STATE_LOCKED);
+    }
+
+    ResponseCode putSecret(uid_t userId) {
+        auto userState = db_.getUserState(userId);
+        KeyBlobEntry entry("secret", userState->getUserDirName(), appUid(userId));
+        Blob blob(reinterpret_cast<const uint8_t*>(kSecret.data()), kSecret.size(), nullptr, 0,
+                  TYPE_GENERIC);
+        blob.setEncrypted(true);
+        return LockedKeyBlobEntry::get(entry).writeBlobs(
+            blob, {}, userState->getEncryptionKey(), userState->getState());
+    }
+
+    std::tuple<ResponseCode, std::string> getSecret(uid_t ownerId, uid_t readerId) {
+        std::string userDirName = db_.getUserState(ownerId)->getUserDirName();
+        auto reader = db_.getUserState(readerId);
+        KeyBlobEntry entry("secret", userDirName, appUid(ownerId));
+        auto [rc, keyBlob, charBlob] = LockedKeyBlobEntry::get(entry).readBlobs(
+            reader->getEncryptionKey(), reader->getState());
+        if (rc != ResponseCode::NO_ERROR) return {rc, {}};
+        return {rc, std::string(reinterpret_cast<const char*>(keyBlob.getValue()),
+                                keyBlob.getLength())};
+    }
+
+    TemporaryDir tmpDir_;
+    std::string oldCwd_;
+    FakeEpochStore epochStore_;
+    UserStateDB db_{&epochStore_};
+};
+
+}  // namespace
+
+TEST_F(UserStateTest, lockAndUnlock) {
+    addUser(0);
+    ASSERT_EQ(ResponseCode::NO_ERROR, putSecret(0));
+
+    lockUser(0);
+    EXPECT_EQ(ResponseCode::LOCKED, std::get<0>(getSecret(0, 0)));
+    EXPECT_EQ(ResponseCode::LOCKED, putSecret(0));
+
+    EXPECT_EQ(ResponseCode::WRONG_PASSWORD_0, db_.getUserState(0)->readMasterKey(kOtherPassword));
+    EXPECT_EQ(STATE_LOCKED, db_.getUserState(0)->getState());
+
+    ASSERT_EQ(ResponseCode::NO_ERROR, db_.getUserState(0)->readMasterKey(kPassword));
+    EXPECT_EQ(STATE_NO_ERROR, db_.getUserState(0)->getState());
+    EXPECT_EQ(std::make_tuple(ResponseCode::NO_ERROR, kSecret), getSecret(0, 0));
+}
+
+TEST_F(UserStateTest, usersAreIsolated) {
+    addUser(0);
+    addUser(10);
+    ASSERT_EQ(ResponseCode::NO_ERROR, putSecret(0));
+    ASSERT_EQ(ResponseCode::NO_ERROR, putSecret(10));
+
+    EXPECT_NE(db_.getUserState(0)->getEncryptionKey(), db_.getUserState(10)->getEncryptionKey());
+    EXPECT_NE(ResponseCode::NO_ERROR, std::get<0>(getSecret(0, 10)));
+
+    // Locking one user leaves the other one usable.
+    lockUser(0);
+    EXPECT_EQ(ResponseCode::LOCKED, std::get<0>(getSecret(0, 0)));
+    EXPECT_EQ(std::make_tuple(ResponseCode::NO_ERROR, kSecret), getSecret(10, 10));
+}
+
+TEST_F(UserStateTest, passwordChange) {
+    addUser(0);
+    ASSERT_EQ(ResponseCode::NO_ERROR, putSecret(0));
+    ASSERT_EQ(ResponseCode::NO_ERROR, db_.getUserState(0)->writeMasterKey(kOtherPassword));
+
+    lockUser(0);
+    EXPECT_EQ(ResponseCode::WRONG_PASSWORD_0, db_.getUserState(0)->readMasterKey(kPassword));
+    ASSERT_EQ(ResponseCode::NO_ERROR, db_.getUserState(0)->readMasterKey(kOtherPassword));
+    EXPECT_EQ(std::make_tuple(ResponseCode::NO_ERROR, kSecret), getSecret(0, 0));
+}
+
+TEST_F(UserStateTest, rolledBackMasterKeyIsRefused) {
+    addUser(11);
+    std::string masterKeyFile = db_.getUserState(11)->getMasterKeyFileName();
+    std::string oldMasterKey;
+    ASSERT_TRUE(android::base::ReadFileToString(masterKeyFile, &oldMasterKey));
+
+    ASSERT_EQ(ResponseCode::NO_ERROR, db_.getUserState(11)->writeMasterKey(kOtherPassword));
+    ASSERT_TRUE(android::base::WriteStringToFile(oldMasterKey, masterKeyFile));
+
+    lockUser(11);
+    EXPECT_EQ(ResponseCode::SYSTEM_ERROR, db_.getUserState(11)->readMasterKey(kPassword));
+    EXPECT_EQ(STATE_LOCKED, db_.getUserState(11)->getState());
+}
+
+TEST_F(UserStateTest, failedEpochRecordIsNotFatal) {
+    epochStore_.failRecords = true;
+    addUser(0);
+    ASSERT_EQ(ResponseCode::NO_ERROR, db_.getUserState(0)->writeMasterKey(kOtherPassword));
+
+    lockUser(0);
+    EXPECT_EQ(ResponseCode::NO_ERROR, db_.getUserState(0)->readMasterKey(kOtherPassword));
+}
+
+TEST_F(UserStateTest, removeUser) {
+    addUser(0);
+    addUser(10);
+    ASSERT_EQ(ResponseCode::NO_ERROR, putSecret(10));
+    std::string userDirName = db_.getUserState(10)->getUserDirName();
+    ASSERT_LT(0u, countFiles(userDirName));
+
+    {
+        auto userState = db_.getUserState(10);
+        ASSERT_TRUE(userState->reset());
+        ASSERT_TRUE(userState->deleteMasterKey());
+        EXPECT_EQ(STATE_UNINITIALIZED, userState->getState());
+    }
+    EXPECT_EQ(0u, countFiles(userDirName));
+
+    // The remaining user is untouched and the removed one can be added again.
+    EXPECT_EQ(STATE_NO_ERROR, db_.getUserState(0)->getState());
+    addUser(10);
+    EXPECT_NE(ResponseCode::NO_ERROR, std::get<0>(getSecret(10, 10)));
+}
+
+TEST_F(UserStateTest, superEncryptedKeyOfUnavailableUser) {
+    addUser(0);
+    auto userState = db_.getUserState(0);
+    KeyBlobEntry entry("super", userState->getUserDirName(), appUid(0));
+    Blob blob(reinterpret_cast<const uint8_t*>(kSecret.data()), kSecret.size(), nullptr, 0,
+              TYPE_KEYMASTER_10);
+    blob.setSuperEncrypted(true);
+    ASSERT_EQ(ResponseCode::NO_ERROR,
+              LockedKeyBlobEntry::get(entry).writeBlobs(blob, {}, userState->getEncryptionKey(),
+                                                        userState->getState()));
+    auto encryptionKey = userState->getEncryptionKey();
+    userState = {};
+
+    auto read = [&](const std::vector<uint8_t>& key, State state) {
+        return LockedKeyBlobEntry::get(entry).readBlobs(key, state);
+    };
+
+    // Unlocked, the key is readable.
+    auto [unlockedRc, unlockedBlob, unlockedChars] = read(encryptionKey, STATE_NO_ERROR);
+    EXPECT_EQ(ResponseCode::NO_ERROR, unlockedRc);
+
+    // Locked, and without a lock screen key at all, e.g. before the first unlock after the lock
+    // screen was removed, the payload is unavailable but the header tells why.
+    for (auto [state, expectedRc] :
+         {std::pair(STATE_LOCKED, ResponseCode::LOCKED),
+          std::pair(STATE_UNINITIALIZED, ResponseCode::UNINITIALIZED)}) {
+        auto [rc, keyBlob, charBlob] = read({}, state);
+        EXPECT_EQ(expectedRc, rc);
+        ASSERT_TRUE(keyBlob);
+        EXPECT_TRUE(keyBlob.isSuperEncrypted());
+    }
+}
+
+TEST_F(UserStateTest, characteristicsOfLockedUser) {
+    addUser(0);
+    auto userState = db_.getUserState(0);
+    KeyBlobEntry entry("key", userState->getUserDirName(), appUid(0));
+    Blob keyBlob(reinterpret_cast<const uint8_t*>(kSecret.data()), kSecret.size(), nullptr, 0,
+                 TYPE_KEYMASTER_10);
+    keyBlob.setEncrypted(true);
+    Blob charBlob;
+    ASSERT_TRUE(charBlob.putKeyCharacteristics(
+        AuthorizationSetBuilder().Authorization(TAG_ALGORITHM, Algorithm::EC), {}));
+    ASSERT_EQ(ResponseCode::NO_ERROR, LockedKeyBlobEntry::get(entry).writeBlobs(
+                                          keyBlob, charBlob, userState->getEncryptionKey(),
+                                          userState->getState()));
+    userState = {};
+    lockUser(0);
+
+    auto [rc, readCharBlob] = LockedKeyBlobEntry::get(entry).readCharacteristicsBlob(
+        {}, db_.getUserState(0)->getState());
+    ASSERT_EQ(ResponseCode::NO_ERROR, rc);
+    auto [success, hwEnforced, swEnforced] = readCharBlob.getKeyCharacteristics();
+    ASSERT_TRUE(success);
+    EXPECT_TRUE(hwEnforced.Contains(TAG_ALGORITHM, Algorithm::EC));
+}
+
+TEST_F(UserStateTest, orphanedCharacteristics) {
+    addUser(0);
+    auto userState = db_.getUserState(0);
+    std::string userDirName = userState->getUserDirName();
+    Blob keyBlob(reinterpret_cast<const uint8_t*>(kSecret.data()), kSecret.size(), nullptr, 0,
+                 TYPE_KEYMASTER_10);
+    Blob charBlob(reinterpret_cast<const uint8_t*>(kSecret.data()), kSecret.size(), nullptr, 0,
+                  TYPE_KEY_CHARACTERISTICS_CACHE);
+    for (const char* alias : {"complete", "orphan"}) {
+        KeyBlobEntry entry(alias, userDirName, appUid(0));
+        ASSERT_EQ(ResponseCode::NO_ERROR,
+                  LockedKeyBlobEntry::get(entry).writeBlobs(keyBlob, charBlob, {}, STATE_NO_ERROR));
+    }
+    userState = {};
+    // keystore died between deleting the key blob and the characteristics blob.
+    KeyBlobEntry orphan("orphan", userDirName, appUid(0));
+    ASSERT_EQ(0, unlink(orphan.getKeyBlobPath().c_str()));
+
+    {
+        auto [rc, orphans] = LockedKeyBlobEntry::listOrphanedCharacteristics(userDirName);
+        ASSERT_EQ(ResponseCode::NO_ERROR, rc);
+        ASSERT_EQ(1u, orphans.size());
+        EXPECT_EQ(orphan, *orphans.front());
+        EXPECT_EQ(ResponseCode::NO_ERROR, orphans.front().deleteBlobs());
+    }
+    EXPECT_FALSE(orphan.hasCharacteristicsBlob());
+
+    auto [rc, orphans] = LockedKeyBlobEntry::listOrphanedCharacteristics(userDirName);
+    ASSERT_EQ(ResponseCode::NO_ERROR, rc);
+    EXPECT_TRUE(orphans.empty());
+    EXPECT_TRUE(KeyBlobEntry("complete", userDirName, appUid(0)).hasCharacteristicsBlob());
+}
+
+}  // namespace test
+}  // namespace keystore
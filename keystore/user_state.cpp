@@ -28,6 +28,7 @@
 #include <openssl/evp.h>
 #include <openssl/rand.h>
 
+#include <android-base/properties.h>
 #include <log/log.h>
 
 #include "blob.h"
@@ -35,9 +36,9 @@
 
 namespace keystore {
 
-UserState::UserState(uid_t userId)
+UserState::UserState(uid_t userId, MasterKeyEpochStore* epochStore)
     : mMasterKeyEntry(".masterkey", "user_" + std::to_string(userId), userId, /* masterkey */ true),
-      mUserId(userId), mState(STATE_UNINITIALIZED) {}
+      mUserId(userId), mState(STATE_UNINITIALIZED), mMasterKeyEpoch(0), mEpochStore(epochStore) {}
 
 bool UserState::operator<(const UserState& rhs) const {
     return getUserId() < rhs.getUserId();
@@ -74,6 +75,7 @@ void UserState::setState(State state) {
 void UserState::zeroizeMasterKeysInMemory() {
     memset(mMasterKey.data(), 0, mMasterKey.size());
     memset(mSalt, 0, sizeof(mSalt));
+    mMasterKeyEpoch = 0;
 }
 
 bool UserState::deleteMasterKey() {
@@ -102,8 +104,18 @@ ResponseCode UserState::copyMasterKey(LockedUserState<UserState>* src) {
         return ResponseCode::SYSTEM_ERROR;
     }
     mMasterKey = (*src)->mMasterKey;
+    mMasterKeyEpoch = (*src)->mMasterKeyEpoch;
     setupMasterKeys();
-    return copyMasterKeyFile(src);
+    auto rc = copyMasterKeyFile(src);
+    if (rc != ResponseCode::NO_ERROR) return rc;
+    if (getRecordedEpoch() > mMasterKeyEpoch) {
+        // A record left behind by an earlier user with this id is not lowered, so the copied
+        // master key is refused until the next password change rewrites it.
+        LOG(WARNING) << "User " << mUserId << " has recorded master key epoch "
+                     << getRecordedEpoch() << ", above the copied " << mMasterKeyEpoch;
+    }
+    recordEpoch(mMasterKeyEpoch);
+    return ResponseCode::NO_ERROR;
 }
 
 ResponseCode UserState::copyMasterKeyFile(LockedUserState<UserState>* src) {
@@ -143,9 +155,23 @@ ResponseCode UserState::writeMasterKey(const android::String8& pw) {
     if (mMasterKey.size() == kAes128KeySizeBytes) {
         blobType = TYPE_MASTER_KEY;
     }
-    Blob masterKeyBlob(mMasterKey.data(), mMasterKey.size(), mSalt, sizeof(mSalt), blobType);
+
+    // The epoch is appended to the key material so that it is covered by the same encryption
+    // and cannot be paired with a master key file protected by a different password.
+    uint64_t epoch = std::max(getRecordedEpoch(), mMasterKeyEpoch) + 1;
+    std::vector<uint8_t> value(mMasterKey);
+    value.resize(mMasterKey.size() + sizeof(epoch));
+    memcpy(value.data() + mMasterKey.size(), &epoch, sizeof(epoch));
+
+    Blob masterKeyBlob(value.data(), value.size(), mSalt, sizeof(mSalt), blobType);
+    memset(value.data(), 0, value.size());
     auto lockedEntry = LockedKeyBlobEntry::get(mMasterKeyEntry);
-    return lockedEntry.writeBlobs(masterKeyBlob, {}, passwordKey, STATE_NO_ERROR);
+    auto rc = lockedEntry.writeBlobs(masterKeyBlob, {}, passwordKey, STATE_NO_ERROR);
+    if (rc != ResponseCode::NO_ERROR) return rc;
+
+    mMasterKeyEpoch = epoch;
+    recordEpoch(epoch);
+    return ResponseCode::NO_ERROR;
 }
 
 ResponseCode UserState::readMasterKey(const android::String8& pw) {
@@ -189,7 +215,22 @@ ResponseCode UserState::readMasterKey(const android::String8& pw) {
 
     size_t masterKeyBlobLength = static_cast<size_t>(masterKeyBlob.getLength());
 
+    // Master key files written before epochs were introduced hold just the key material.
+    uint64_t epoch = 0;
+    if (response == ResponseCode::NO_ERROR &&
+        masterKeyBlobLength == masterKeySize + sizeof(epoch)) {
+        memcpy(&epoch, masterKeyBlob.getValue() + masterKeySize, sizeof(epoch));
+        masterKeyBlobLength = masterKeySize;
+    }
+
     if (response == ResponseCode::NO_ERROR && masterKeyBlobLength == masterKeySize) {
+        if (epoch < getRecordedEpoch()) {
+            LOG(ERROR) << "Refusing master key of user " << mUserId << " with epoch " << epoch
+                       << ", the recorded epoch is " << getRecordedEpoch();
+            return ResponseCode::SYSTEM_ERROR;
+        }
+        mMasterKeyEpoch = epoch;
+
         // If salt was missing, generate one and write a new master key file with the salt.
         if (salt == nullptr) {
             if (!generateSalt()) {
@@ -199,7 +240,7 @@ ResponseCode UserState::readMasterKey(const android::String8& pw) {
         }
         if (response == ResponseCode::NO_ERROR) {
             mMasterKey = std::vector<uint8_t>(masterKeyBlob.getValue(),
-                                              masterKeyBlob.getValue() + masterKeyBlob.getLength());
+                                              masterKeyBlob.getValue() + masterKeyBlobLength);
 
             setupMasterKeys();
         }
@@ -276,11 +317,57 @@ void UserState::setupMasterKeys() {
     setState(STATE_NO_ERROR);
 }
 
+namespace {
+
+/*
+ * The recorded epoch lives in a persistent property rather than in the keystore directory, so
+ * that restoring an old copy of the keystore directory does not roll it back as well.
+ */
+class PropertyEpochStore : public MasterKeyEpochStore {
+  public:
+    uint64_t get(uid_t userId) const override {
+        return android::base::GetUintProperty<uint64_t>(propertyName(userId), 0);
+    }
+    bool record(uid_t userId, uint64_t epoch) override {
+        return android::base::SetProperty(propertyName(userId), std::to_string(epoch));
+    }
+
+  private:
+    static std::string propertyName(uid_t userId) {
+        return "persist.keystore.masterkey_epoch.user_" + std::to_string(userId);
+    }
+};
+
+}  // namespace
+
+MasterKeyEpochStore* MasterKeyEpochStore::systemProperties() {
+    static PropertyEpochStore store;
+    return &store;
+}
+
+uint64_t UserState::getRecordedEpoch() const {
+    return mEpochStore->get(mUserId);
+}
+
+/*
+ * The master key file is already written when this is called, so a failure must not fail the
+ * password change. It only weakens the rollback protection until the next successful record.
+ */
+void UserState::recordEpoch(uint64_t epoch) const {
+    // The recorded epoch only ever grows, or older master key files would become acceptable.
+    if (epoch <= getRecordedEpoch()) return;
+    if (!mEpochStore->record(mUserId, epoch)) {
+        LOG(ERROR) << "Failed to record master key epoch " << epoch << " for user " << mUserId;
+    }
+}
+
 LockedUserState<UserState> UserStateDB::getUserState(uid_t userId) {
     std::unique_lock<std::mutex> lock(locked_state_mutex_);
     decltype(mMasterKeys.begin()) it;
     bool inserted;
-    std::tie(it, inserted) = mMasterKeys.emplace(userId, userId);
+    std::tie(it, inserted) = mMasterKeys.emplace(std::piecewise_construct,
+                                                 std::forward_as_tuple(userId),
+                                                 std::forward_as_tuple(userId, mEpochStore));
     if (inserted) {
         if (!it->second.initialize()) {
             /* There's not much we can do if initialization fails. Trying to
@@ -39,11 +39,25 @@ namespace keystore {
 
 class UserState;
 
+/*
+ * Records the highest master key epoch written for each user. See
+ * UserState::getMasterKeyEpoch().
+ */
+class MasterKeyEpochStore {
+  public:
+    virtual ~MasterKeyEpochStore() {}
+    virtual uint64_t get(uid_t userId) const = 0;
+    virtual bool record(uid_t userId, uint64_t epoch) = 0;
+
+    // The store used by keystore. It keeps the epochs in persistent system properties.
+    static MasterKeyEpochStore* systemProperties();
+};
+
 template <typename UserState> using LockedUserState = ProxyLock<UnlockProxyLockHelper<UserState>>;
 
 class UserState {
   public:
-    explicit UserState(uid_t userId);
+    UserState(uid_t userId, MasterKeyEpochStore* epochStore);
 
     bool initialize();
 
@@ -65,6 +79,14 @@ class UserState {
     ResponseCode writeMasterKey(const android::String8& pw);
     ResponseCode readMasterKey(const android::String8& pw);
 
+    /*
+     * Every write of the master key file bumps an epoch that is encrypted along with the key.
+     * The highest epoch ever written is recorded outside of the keystore directory, and master
+     * key files with an older epoch are refused. This keeps a restored copy of the user directory
+     * from bringing back a master key protected by a previous, possibly compromised, password.
+     */
+    uint64_t getMasterKeyEpoch() const { return mMasterKeyEpoch; }
+
     const std::vector<uint8_t>& getEncryptionKey() const { return mMasterKey; }
 
     bool reset();
@@ -87,6 +109,9 @@ class UserState {
     bool generateMasterKey();
     void setupMasterKeys();
 
+    uint64_t getRecordedEpoch() const;
+    void recordEpoch(uint64_t epoch) const;
+
     KeyBlobEntry mMasterKeyEntry;
 
     uid_t mUserId;
@@ -94,12 +119,18 @@ class UserState {
 
     std::vector<uint8_t> mMasterKey;
     uint8_t mSalt[SALT_SIZE];
+    uint64_t mMasterKeyEpoch;
+    MasterKeyEpochStore* mEpochStore;
 };
 
 bool operator<(uid_t userId, const UserState& rhs);
 
 class UserStateDB {
   public:
+    explicit UserStateDB(
+        MasterKeyEpochStore* epochStore = MasterKeyEpochStore::systemProperties())
+        : mEpochStore(epochStore) {}
+
     LockedUserState<UserState> getUserState(uid_t userId);
     LockedUserState<UserState> getUserStateByUid(uid_t uid);
     LockedUserState<const UserState> getUserState(uid_t userId) const;
@@ -124,6 +155,7 @@ class UserStateDB {
     }
 
     std::map<uid_t, UserState> mMasterKeys;
+    MasterKeyEpochStore* mEpochStore;
 };
 
 }  //  namespace keystore